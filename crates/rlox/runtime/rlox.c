@@ -0,0 +1,332 @@
+/* The runtime of scripts compiled to C by `rlox build --emit c`, pasted in front of the
+   translated code. Each instruction of the chunk becomes a call into it, operating on the same
+   value stack as the virtual machine. Strings are never freed, a script runs once and exits. */
+
+/* For `clock_gettime`, which C99 alone doesn't have. */
+#define _POSIX_C_SOURCE 199309L
+
+#include <math.h>
+#include <stdbool.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <time.h>
+
+typedef struct LoxNative LoxNative;
+
+typedef enum { LOX_NIL, LOX_BOOL, LOX_NUMBER, LOX_STRING, LOX_NATIVE } LoxType;
+
+typedef struct {
+    LoxType type;
+    union {
+        bool boolean;
+        double number;
+        const char *string;
+        const LoxNative *native;
+    } as;
+} LoxValue;
+
+/* A function the runtime defines for scripts to call, the global of its name holds it. */
+struct LoxNative {
+    const char *name;
+    int arity;
+    LoxValue (*function)(LoxValue *args);
+};
+
+/* A global variable, statically allocated for every name the script uses. */
+typedef struct {
+    const char *name;
+    bool defined;
+    LoxValue value;
+} LoxGlobal;
+
+/* `LOX_STACK_MAX`, the deepest the stack gets, is defined in front of the runtime. */
+static LoxValue lox_stack[LOX_STACK_MAX];
+static int lox_top = 0;
+
+static inline LoxValue lox_nil(void) {
+    LoxValue value;
+    value.type = LOX_NIL;
+    value.as.number = 0;
+    return value;
+}
+
+static inline LoxValue lox_bool(bool boolean) {
+    LoxValue value;
+    value.type = LOX_BOOL;
+    value.as.boolean = boolean;
+    return value;
+}
+
+static inline LoxValue lox_number(double number) {
+    LoxValue value;
+    value.type = LOX_NUMBER;
+    value.as.number = number;
+    return value;
+}
+
+static inline LoxValue lox_string(const char *string) {
+    LoxValue value;
+    value.type = LOX_STRING;
+    value.as.string = string;
+    return value;
+}
+
+/* When the program started, `clock()` counts from there. */
+static struct timespec lox_start;
+
+static inline void lox_start_clock(void) {
+    clock_gettime(CLOCK_MONOTONIC, &lox_start);
+}
+
+/* `clock()`, the seconds of wall time since the program started, like the interpreter's
+   seconds since its virtual machine was made. */
+static inline LoxValue lox_clock(LoxValue *args) {
+    (void)args;
+    struct timespec now;
+    clock_gettime(CLOCK_MONOTONIC, &now);
+    return lox_number((double)(now.tv_sec - lox_start.tv_sec) +
+                      (double)(now.tv_nsec - lox_start.tv_nsec) / 1e9);
+}
+
+/* Reports a runtime error the way the interpreter's one line form does, and exits with its
+   status. */
+static inline void lox_error(int line, const char *code, const char *message) {
+    fflush(stdout);
+    fprintf(stderr, "[line %d] Error[%s]: %s\nRuntime error\n", line, code, message);
+    exit(70);
+}
+
+static inline void lox_push(LoxValue value) {
+    lox_stack[lox_top++] = value;
+}
+
+static inline LoxValue lox_pop(void) {
+    return lox_stack[--lox_top];
+}
+
+static inline LoxValue lox_peek(void) {
+    return lox_stack[lox_top - 1];
+}
+
+static inline bool lox_is_falsey(LoxValue value) {
+    return value.type == LOX_NIL || (value.type == LOX_BOOL && !value.as.boolean);
+}
+
+static inline bool lox_values_equal(LoxValue a, LoxValue b) {
+    if (a.type != b.type) {
+        return false;
+    }
+    switch (a.type) {
+    case LOX_NIL:
+        return true;
+    case LOX_BOOL:
+        return a.as.boolean == b.as.boolean;
+    case LOX_NUMBER:
+        return a.as.number == b.as.number;
+    case LOX_STRING:
+        return strcmp(a.as.string, b.as.string) == 0;
+    case LOX_NATIVE:
+        return a.as.native == b.as.native;
+    }
+    return false;
+}
+
+/* Writes `number` as Rust's `Display` for `f64` does: the shortest digits that read back as
+   the same number, never in exponent notation. */
+static inline void lox_format_number(double number, char *buffer) {
+    if (isnan(number)) {
+        strcpy(buffer, "NaN");
+        return;
+    }
+    if (isinf(number)) {
+        strcpy(buffer, number > 0 ? "inf" : "-inf");
+        return;
+    }
+
+    char scientific[32];
+    for (int precision = 0; precision < 17; precision++) {
+        snprintf(scientific, sizeof scientific, "%.*e", precision, fabs(number));
+        if (strtod(scientific, NULL) == fabs(number)) {
+            break;
+        }
+    }
+    /* `scientific` is `d.ddde+XX`, or `de+XX` for a single digit. */
+    char digits[20];
+    int count = 0;
+    char *cursor = scientific;
+    for (; *cursor != 'e'; cursor++) {
+        if (*cursor != '.') {
+            digits[count++] = *cursor;
+        }
+    }
+    int exponent = atoi(cursor + 1);
+    while (count > 1 && digits[count - 1] == '0') {
+        count--;
+    }
+
+    char *out = buffer;
+    if (signbit(number)) {
+        *out++ = '-';
+    }
+    int point = exponent + 1;
+    if (point <= 0) {
+        *out++ = '0';
+        *out++ = '.';
+        for (int i = 0; i < -point; i++) {
+            *out++ = '0';
+        }
+        for (int i = 0; i < count; i++) {
+            *out++ = digits[i];
+        }
+    } else {
+        for (int i = 0; i < count || i < point; i++) {
+            if (i == point) {
+                *out++ = '.';
+            }
+            *out++ = i < count ? digits[i] : '0';
+        }
+    }
+    *out = '\0';
+}
+
+static inline const char *lox_to_string(LoxValue value) {
+    switch (value.type) {
+    case LOX_NIL:
+        return "nil";
+    case LOX_BOOL:
+        return value.as.boolean ? "true" : "false";
+    case LOX_STRING:
+        return value.as.string;
+    case LOX_NUMBER: {
+        char *buffer = malloc(400);
+        lox_format_number(value.as.number, buffer);
+        return buffer;
+    }
+    case LOX_NATIVE: {
+        char *buffer = malloc(strlen(value.as.native->name) + 16);
+        sprintf(buffer, "<native fn %s>", value.as.native->name);
+        return buffer;
+    }
+    }
+    return "";
+}
+
+static inline void lox_op_equal(void) {
+    LoxValue b = lox_pop();
+    LoxValue a = lox_pop();
+    lox_push(lox_bool(lox_values_equal(a, b)));
+}
+
+/* Pops the operands of an arithmetic or comparison instruction. */
+static inline void lox_pop_numbers(int line, double *a, double *b) {
+    LoxValue right = lox_pop();
+    LoxValue left = lox_pop();
+    if (left.type != LOX_NUMBER || right.type != LOX_NUMBER) {
+        lox_error(line, RLOX_OPERANDS_NOT_NUMBERS, "Operands must be numbers.");
+    }
+    *a = left.as.number;
+    *b = right.as.number;
+}
+
+#define LOX_BINARY(name, wrap, op)                                                             \
+    static inline void name(int line) {                                                        \
+        double a, b;                                                                           \
+        lox_pop_numbers(line, &a, &b);                                                         \
+        lox_push(wrap(a op b));                                                                \
+    }
+
+LOX_BINARY(lox_op_greater, lox_bool, >)
+LOX_BINARY(lox_op_greater_equal, lox_bool, >=)
+LOX_BINARY(lox_op_less, lox_bool, <)
+LOX_BINARY(lox_op_less_equal, lox_bool, <=)
+LOX_BINARY(lox_op_subtract, lox_number, -)
+LOX_BINARY(lox_op_multiply, lox_number, *)
+LOX_BINARY(lox_op_divide, lox_number, /)
+
+static inline void lox_op_add(int line) {
+    LoxValue b = lox_pop();
+    LoxValue a = lox_pop();
+    if (a.type == LOX_NUMBER && b.type == LOX_NUMBER) {
+        lox_push(lox_number(a.as.number + b.as.number));
+    } else if (a.type == LOX_STRING && b.type == LOX_STRING) {
+        size_t length = strlen(a.as.string);
+        char *string = malloc(length + strlen(b.as.string) + 1);
+        strcpy(string, a.as.string);
+        strcpy(string + length, b.as.string);
+        lox_push(lox_string(string));
+    } else {
+        lox_error(line, RLOX_OPERANDS_NOT_ADDABLE, "Operands must be two numbers or two strings.");
+    }
+}
+
+static inline void lox_op_negate(int line) {
+    LoxValue value = lox_pop();
+    if (value.type != LOX_NUMBER) {
+        lox_error(line, RLOX_OPERAND_NOT_NUMBER, "Operand must be a number.");
+    }
+    lox_push(lox_number(-value.as.number));
+}
+
+static inline void lox_op_not(void) {
+    lox_push(lox_bool(lox_is_falsey(lox_pop())));
+}
+
+static inline void lox_op_stringify(void) {
+    lox_push(lox_string(lox_to_string(lox_pop())));
+}
+
+static inline void lox_op_print(void) {
+    puts(lox_to_string(lox_pop()));
+}
+
+static inline void lox_undefined(LoxGlobal *global, int line) {
+    char *message = malloc(strlen(global->name) + 32);
+    sprintf(message, "Undefined variable '%s'.", global->name);
+    lox_error(line, RLOX_UNDEFINED_VARIABLE, message);
+}
+
+static inline void lox_op_define_global(LoxGlobal *global) {
+    global->value = lox_pop();
+    global->defined = true;
+}
+
+static inline void lox_op_get_global(LoxGlobal *global, int line) {
+    if (!global->defined) {
+        lox_undefined(global, line);
+    }
+    lox_push(global->value);
+}
+
+static inline void lox_op_set_global(LoxGlobal *global, int line) {
+    if (!global->defined) {
+        lox_undefined(global, line);
+    }
+    global->value = lox_peek();
+}
+
+/* Calls the native below the `argc` arguments on top of the stack, the value it returns takes
+   their place. Natives are the only values that can be called. */
+static inline void lox_op_call(int argc, int line) {
+    LoxValue callee = lox_stack[lox_top - argc - 1];
+    if (callee.type != LOX_NATIVE) {
+        lox_error(line, RLOX_NOT_CALLABLE, "Can only call functions.");
+    }
+    const LoxNative *native = callee.as.native;
+    if (argc != native->arity) {
+        char *message = malloc(64);
+        sprintf(message, "Expected %d arguments but got %d.", native->arity, argc);
+        lox_error(line, RLOX_ARITY_MISMATCH, message);
+    }
+    LoxValue result = native->function(&lox_stack[lox_top - argc]);
+    lox_top -= argc + 1;
+    lox_push(result);
+}
+
+static inline void lox_op_get_local(int slot) {
+    lox_push(lox_stack[slot]);
+}
+
+static inline void lox_op_set_local(int slot) {
+    lox_stack[slot] = lox_peek();
+}
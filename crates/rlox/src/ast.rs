@@ -0,0 +1,762 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::parser::{Builder, Parser};
+use crate::scanner::Scanner;
+use std::fmt;
+
+/// A whole script, `end` is where the input ended.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    pub declarations: Vec<Declaration>,
+    pub end: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Declaration {
+    /// `var name = initializer;`, `span` covers the whole declaration.
+    Var {
+        name: String,
+        name_span: Span,
+        initializer: Option<Expr>,
+        span: Span,
+    },
+    Statement(Stmt),
+    /// A declaration that went wrong, with the ones inside it that could be parsed.
+    Error {
+        kind: ErrorKind,
+        span: Span,
+        children: Vec<Declaration>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Stmt {
+    pub kind: StmtKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StmtKind {
+    Print(Expr),
+    Expression(Expr),
+    Block(Vec<Declaration>),
+    If {
+        condition: Expr,
+        then_branch: Box<Declaration>,
+        /// The `else` keyword and the branch after it.
+        else_branch: Option<(Span, Box<Declaration>)>,
+    },
+    While {
+        condition: Expr,
+        body: Box<Declaration>,
+    },
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExprKind {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    /// `"a ${b} c"`, the text parts are already unescaped.
+    Interpolation(Vec<InterpolationPart>),
+    Grouping(Box<Expr>),
+    Unary {
+        op: UnaryOp,
+        operator: Span,
+        operand: Box<Expr>,
+    },
+    Binary {
+        op: BinaryOp,
+        operator: Span,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Logical {
+        op: LogicalOp,
+        operator: Span,
+        left: Box<Expr>,
+        right: Box<Expr>,
+    },
+    Variable(String),
+    Call {
+        callee: Box<Expr>,
+        arguments: Vec<Expr>,
+        /// From the `(` to the `)`.
+        parens: Span,
+    },
+    Assign {
+        name: String,
+        value: Box<Expr>,
+    },
+    /// Stands in for an expression that failed to parse, with the parts that could be.
+    Error {
+        kind: ErrorKind,
+        children: Vec<Expr>,
+    },
+}
+
+/// Why an error node is in the tree, the parser reports the details as a diagnostic.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum ErrorKind {
+    /// Tokens that don't fit where they are, skipped to get back in step with the grammar.
+    Unexpected,
+    /// A malformed token or construct, like a number that is too large or an assignment to
+    /// something that is not a variable.
+    Invalid,
+    /// The named token or node is absent, it belongs at the end of the error node.
+    Missing(&'static str),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum InterpolationPart {
+    Text(String, Span),
+    Expr(Expr),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Equal,
+    NotEqual,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LogicalOp {
+    And,
+    Or,
+}
+
+impl Declaration {
+    pub fn span(&self) -> Span {
+        match self {
+            Declaration::Var { span, .. } => *span,
+            Declaration::Statement(stmt) => stmt.span,
+            Declaration::Error { span, .. } => *span,
+        }
+    }
+}
+
+impl Expr {
+    pub fn new(kind: ExprKind, span: Span) -> Self {
+        Expr { kind, span }
+    }
+}
+
+/// Parses `source` into a syntax tree, also returns every error found on the way.
+pub fn parse(source: &str) -> (Program, Vec<Diagnostic>) {
+    let mut scanner = Scanner::new(source);
+    let mut builder = TreeBuilder::new();
+    let diagnostics = Parser::new(&mut scanner, &mut builder).parse();
+    (builder.finish(), diagnostics)
+}
+
+/// Assembles the tree from the parser's postfix events: operands are complete by the time the
+/// node using them is reported.
+struct TreeBuilder {
+    exprs: Vec<Expr>,
+    declarations: Vec<Declaration>,
+    interpolations: Vec<Vec<InterpolationPart>>,
+    /// The names of the variables being declared.
+    variables: Vec<(String, Span)>,
+    /// Where the declarations of each open block start.
+    blocks: Vec<usize>,
+    /// The `else` keywords of the open `if` statements that have one.
+    elses: Vec<Span>,
+    end: Span,
+}
+
+impl TreeBuilder {
+    pub fn new() -> Self {
+        TreeBuilder {
+            exprs: Vec::new(),
+            declarations: Vec::new(),
+            interpolations: Vec::new(),
+            variables: Vec::new(),
+            blocks: Vec::new(),
+            elses: Vec::new(),
+            end: Span::new(0, 0, 1),
+        }
+    }
+
+    pub fn finish(self) -> Program {
+        Program {
+            declarations: self.declarations,
+            end: self.end,
+        }
+    }
+
+    fn push(&mut self, kind: ExprKind, span: Span) {
+        self.exprs.push(Expr::new(kind, span));
+    }
+
+    fn pop(&mut self) -> Expr {
+        self.exprs.pop().expect("Missing operand")
+    }
+
+    fn pop_declaration(&mut self) -> Declaration {
+        self.declarations.pop().expect("Missing declaration")
+    }
+
+    fn pop_children<T>(nodes: &mut Vec<T>, children: usize) -> Vec<T> {
+        let len = nodes.len().checked_sub(children).expect("Missing child");
+        nodes.split_off(len)
+    }
+
+    fn statement(&mut self, kind: StmtKind, span: Span) {
+        let stmt = Stmt { kind, span };
+        self.declarations.push(Declaration::Statement(stmt));
+    }
+}
+
+impl Builder for TreeBuilder {
+    fn nil(&mut self, span: Span) {
+        self.push(ExprKind::Nil, span);
+    }
+
+    fn boolean(&mut self, value: bool, span: Span) {
+        self.push(ExprKind::Bool(value), span);
+    }
+
+    fn number(&mut self, value: f64, span: Span) {
+        self.push(ExprKind::Number(value), span);
+    }
+
+    fn string(&mut self, value: String, span: Span) {
+        self.push(ExprKind::String(value), span);
+    }
+
+    fn begin_interpolation(&mut self) {
+        self.interpolations.push(Vec::new());
+    }
+
+    fn interpolation_text(&mut self, text: String, span: Span) {
+        let parts = self
+            .interpolations
+            .last_mut()
+            .expect("Missing interpolation");
+        parts.push(InterpolationPart::Text(text, span));
+    }
+
+    fn interpolation_value(&mut self, _span: Span) {
+        let expr = self.pop();
+        let parts = self
+            .interpolations
+            .last_mut()
+            .expect("Missing interpolation");
+        parts.push(InterpolationPart::Expr(expr));
+    }
+
+    fn end_interpolation(&mut self, span: Span) {
+        let parts = self.interpolations.pop().expect("Missing interpolation");
+        self.push(ExprKind::Interpolation(parts), span);
+    }
+
+    fn grouping(&mut self, span: Span) {
+        let expr = self.pop();
+        self.push(ExprKind::Grouping(Box::new(expr)), span);
+    }
+
+    fn unary(&mut self, op: UnaryOp, operator: Span) {
+        let operand = self.pop();
+        let span = operator.to(operand.span);
+        self.push(
+            ExprKind::Unary {
+                op,
+                operator,
+                operand: Box::new(operand),
+            },
+            span,
+        );
+    }
+
+    fn binary(&mut self, op: BinaryOp, operator: Span) {
+        let right = self.pop();
+        let left = self.pop();
+        let span = left.span.to(right.span);
+        self.push(
+            ExprKind::Binary {
+                op,
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+            span,
+        );
+    }
+
+    fn variable(&mut self, name: String, span: Span) {
+        self.push(ExprKind::Variable(name), span);
+    }
+
+    fn assignment(&mut self, name: String, span: Span) {
+        let value = Box::new(self.pop());
+        self.push(ExprKind::Assign { name, value }, span);
+    }
+
+    fn call(&mut self, arg_count: usize, span: Span) {
+        let arguments = TreeBuilder::pop_children(&mut self.exprs, arg_count);
+        let callee = self.pop();
+        let parens = span;
+        let span = callee.span.to(span);
+        self.push(
+            ExprKind::Call {
+                callee: Box::new(callee),
+                arguments,
+                parens,
+            },
+            span,
+        );
+    }
+
+    fn logical_operator(&mut self, _op: LogicalOp, _operator: Span) {}
+
+    fn logical(&mut self, op: LogicalOp, operator: Span) {
+        let right = self.pop();
+        let left = self.pop();
+        let span = left.span.to(right.span);
+        self.push(
+            ExprKind::Logical {
+                op,
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+            span,
+        );
+    }
+
+    fn expression_error(&mut self, kind: ErrorKind, span: Span, children: usize) {
+        let children = TreeBuilder::pop_children(&mut self.exprs, children);
+        self.push(ExprKind::Error { kind, children }, span);
+    }
+
+    fn print_statement(&mut self, span: Span) {
+        let expr = self.pop();
+        self.statement(StmtKind::Print(expr), span);
+    }
+
+    fn expression_statement(&mut self, span: Span) {
+        let expr = self.pop();
+        self.statement(StmtKind::Expression(expr), span);
+    }
+
+    fn declare_variable(&mut self, name: String, span: Span) {
+        self.variables.push((name, span));
+    }
+
+    fn define_variable(&mut self, initialized: bool, span: Span) {
+        let (name, name_span) = self.variables.pop().expect("Missing variable");
+        let initializer = initialized.then(|| self.pop());
+        self.declarations.push(Declaration::Var {
+            name,
+            name_span,
+            initializer,
+            span,
+        });
+    }
+
+    fn begin_block(&mut self) {
+        self.blocks.push(self.declarations.len());
+    }
+
+    fn end_block(&mut self, span: Span) {
+        let start = self.blocks.pop().expect("Missing block");
+        let declarations = self.declarations.split_off(start);
+        self.statement(StmtKind::Block(declarations), span);
+    }
+
+    fn if_condition(&mut self, _span: Span) {}
+
+    fn if_else(&mut self, span: Span) {
+        self.elses.push(span);
+    }
+
+    fn end_if(&mut self, has_else: bool, span: Span) {
+        let else_branch = has_else.then(|| {
+            let keyword = self.elses.pop().expect("Missing else");
+            (keyword, Box::new(self.pop_declaration()))
+        });
+        let then_branch = Box::new(self.pop_declaration());
+        let condition = self.pop();
+        let kind = StmtKind::If {
+            condition,
+            then_branch,
+            else_branch,
+        };
+        self.statement(kind, span);
+    }
+
+    fn begin_loop(&mut self) {}
+
+    fn loop_condition(&mut self, _span: Span) {}
+
+    fn end_loop(&mut self, span: Span) {
+        let body = Box::new(self.pop_declaration());
+        let condition = self.pop();
+        self.statement(StmtKind::While { condition, body }, span);
+    }
+
+    fn declaration_error(&mut self, kind: ErrorKind, span: Span, children: usize) {
+        let children = TreeBuilder::pop_children(&mut self.declarations, children);
+        self.declarations.push(Declaration::Error {
+            kind,
+            span,
+            children,
+        });
+    }
+
+    fn end(&mut self, span: Span) -> Vec<Diagnostic> {
+        self.end = span;
+        Vec::new()
+    }
+}
+
+/// Lisp style, every node in parentheses: `(print (+ 1 (* 2 3)))`.
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, declaration) in self.declarations.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "{}", declaration)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Declaration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Declaration::Var {
+                name, initializer, ..
+            } => match initializer {
+                Some(initializer) => write!(f, "(var {} {})", name, initializer),
+                None => write!(f, "(var {})", name),
+            },
+            Declaration::Statement(stmt) => write!(f, "{}", stmt),
+            Declaration::Error { kind, children, .. } => {
+                write!(f, "(error {}", kind)?;
+                for child in children {
+                    write!(f, " {}", child)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Stmt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            StmtKind::Print(expr) => write!(f, "(print {})", expr),
+            StmtKind::Expression(expr) => write!(f, "(expr {})", expr),
+            StmtKind::Block(declarations) => {
+                write!(f, "(block")?;
+                for declaration in declarations {
+                    write!(f, " {}", declaration)?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::If {
+                condition,
+                then_branch,
+                else_branch,
+            } => {
+                write!(f, "(if {} {}", condition, then_branch)?;
+                if let Some((_, else_branch)) = else_branch {
+                    write!(f, " {}", else_branch)?;
+                }
+                write!(f, ")")
+            }
+            StmtKind::While { condition, body } => write!(f, "(while {} {})", condition, body),
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ExprKind::Nil => write!(f, "nil"),
+            ExprKind::Bool(value) => write!(f, "{}", value),
+            ExprKind::Number(value) => write!(f, "{}", value),
+            ExprKind::String(value) => write!(f, "{:?}", value),
+            ExprKind::Interpolation(parts) => {
+                write!(f, "(str")?;
+                for part in parts {
+                    match part {
+                        InterpolationPart::Text(text, _) => write!(f, " {:?}", text)?,
+                        InterpolationPart::Expr(expr) => write!(f, " {}", expr)?,
+                    }
+                }
+                write!(f, ")")
+            }
+            ExprKind::Grouping(expr) => write!(f, "(group {})", expr),
+            ExprKind::Unary { op, operand, .. } => write!(f, "({} {})", op, operand),
+            ExprKind::Binary {
+                op, left, right, ..
+            } => write!(f, "({} {} {})", op, left, right),
+            ExprKind::Logical {
+                op, left, right, ..
+            } => write!(f, "({} {} {})", op, left, right),
+            ExprKind::Variable(name) => write!(f, "{}", name),
+            ExprKind::Call {
+                callee, arguments, ..
+            } => {
+                write!(f, "(call {}", callee)?;
+                for argument in arguments {
+                    write!(f, " {}", argument)?;
+                }
+                write!(f, ")")
+            }
+            ExprKind::Assign { name, value } => write!(f, "(= {} {})", name, value),
+            ExprKind::Error { kind, children } => {
+                write!(f, "(error {}", kind)?;
+                for child in children {
+                    write!(f, " {}", child)?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Unexpected => write!(f, "unexpected"),
+            ErrorKind::Invalid => write!(f, "invalid"),
+            ErrorKind::Missing(what) => write!(f, "missing {}", what),
+        }
+    }
+}
+
+impl fmt::Display for UnaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UnaryOp::Negate => write!(f, "-"),
+            UnaryOp::Not => write!(f, "!"),
+        }
+    }
+}
+
+impl fmt::Display for LogicalOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogicalOp::And => write!(f, "and"),
+            LogicalOp::Or => write!(f, "or"),
+        }
+    }
+}
+
+impl fmt::Display for BinaryOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BinaryOp::Add => write!(f, "+"),
+            BinaryOp::Subtract => write!(f, "-"),
+            BinaryOp::Multiply => write!(f, "*"),
+            BinaryOp::Divide => write!(f, "/"),
+            BinaryOp::Equal => write!(f, "=="),
+            BinaryOp::NotEqual => write!(f, "!="),
+            BinaryOp::Greater => write!(f, ">"),
+            BinaryOp::GreaterEqual => write!(f, ">="),
+            BinaryOp::Less => write!(f, "<"),
+            BinaryOp::LessEqual => write!(f, "<="),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Declaration, ExprKind, StmtKind};
+    use crate::diagnostic::Span;
+    use crate::error_code::ErrorCode;
+
+    fn tree(source: &str) -> String {
+        let (program, diagnostics) = parse(source);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        program.to_string()
+    }
+
+    #[test]
+    fn parse_expressions() {
+        assert_eq!(tree("1 + 2 * 3;"), "(expr (+ 1 (* 2 3)))");
+        assert_eq!(
+            tree("print -(1 - 2) / 4;"),
+            "(print (/ (- (group (- 1 2))) 4))"
+        );
+        assert_eq!(tree("print \"a\";\n\"b\";"), "(print \"a\")\n(expr \"b\")");
+        assert_eq!(
+            tree("!true == 1 < 2 != nil;"),
+            "(expr (!= (== (! true) (< 1 2)) nil))"
+        );
+        assert_eq!(
+            tree("-f()(1, a = 2);"),
+            "(expr (- (call (call f) 1 (= a 2))))"
+        );
+    }
+
+    #[test]
+    fn parse_interpolation() {
+        assert_eq!(
+            tree(r#"print "x ${1 + "${2}"} y\n";"#),
+            r#"(print (str "x " (+ 1 (str "" 2 "")) " y\n"))"#
+        );
+    }
+
+    #[test]
+    fn parse_spans() {
+        let source = "print 1 +\n  -2;\n(3);";
+        let (program, _) = parse(source);
+
+        let Declaration::Statement(print) = &program.declarations[0] else {
+            panic!("{:?}", program)
+        };
+        assert_eq!(print.span, Span::new(0, 15, 1));
+        let StmtKind::Print(sum) = &print.kind else {
+            panic!("{:?}", print)
+        };
+        assert_eq!(sum.span, Span::new(6, 14, 1));
+        let ExprKind::Binary {
+            operator, right, ..
+        } = &sum.kind
+        else {
+            panic!("{:?}", sum)
+        };
+        assert_eq!(*operator, Span::new(8, 9, 1));
+        assert_eq!(right.span, Span::new(12, 14, 2));
+
+        assert_eq!(program.declarations[1].span(), Span::new(16, 20, 3));
+        assert_eq!(program.end, Span::new(20, 20, 3));
+    }
+
+    /// The tree of a program with errors, and how many diagnostics it has.
+    fn recovered(source: &str) -> (String, usize) {
+        let (program, diagnostics) = parse(source);
+        assert!(diagnostics.iter().any(|d| d.is_error()), "{}", source);
+        (program.to_string(), diagnostics.len())
+    }
+
+    #[test]
+    fn parse_errors_into_error_nodes() {
+        assert_eq!(
+            recovered("print 1 +;\nprint 1e400;"),
+            (
+                "(print (+ 1 (error missing expression)))\n(print (error invalid))".to_string(),
+                2
+            )
+        );
+        assert_eq!(
+            recovered("print #;"),
+            ("(print (error invalid))".to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn recover_missing_tokens() {
+        assert_eq!(
+            recovered("print 1\nprint 2;"),
+            ("(error missing ';' (print 1))\n(print 2)".to_string(), 1)
+        );
+        assert_eq!(
+            recovered("print (1 + 2;\n3;"),
+            (
+                "(print (error missing ')' (+ 1 2)))\n(expr 3)".to_string(),
+                1
+            )
+        );
+        assert_eq!(
+            recovered("print f(1, 2;\n3;"),
+            ("(print (error missing ')' f 1 2))\n(expr 3)".to_string(), 1)
+        );
+        assert_eq!(
+            recovered(r#"print "a ${} b";"#),
+            (
+                r#"(print (str "a " (error missing expression) " b"))"#.to_string(),
+                1
+            )
+        );
+        assert_eq!(
+            recovered(r#"print "a ${1;"#),
+            (r#"(print (error missing '}' (str "a " 1)))"#.to_string(), 1)
+        );
+    }
+
+    #[test]
+    fn limit_arguments() {
+        let call = |count: usize| format!("f({});", vec!["nil"; count].join(", "));
+        assert!(parse(&call(255)).1.is_empty());
+        let (program, diagnostics) = parse(&call(256));
+        let codes: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.code)
+            .collect();
+        assert_eq!(codes, [Some(ErrorCode::TooManyArguments)]);
+        assert!(program.to_string().starts_with("(expr (call f nil"));
+    }
+
+    #[test]
+    fn recover_unexpected_tokens() {
+        assert_eq!(
+            recovered("4 5 6;\nprint 7;"),
+            ("(error unexpected (expr 4))\n(print 7)".to_string(), 1)
+        );
+        assert_eq!(
+            recovered("print (1 2);"),
+            ("(print (error unexpected 1))".to_string(), 1)
+        );
+        assert_eq!(
+            recovered(r#"print "a ${1 2} b";"#),
+            (
+                r#"(print (error unexpected (str "a " 1 " b")))"#.to_string(),
+                1
+            )
+        );
+        assert_eq!(
+            recovered("print 1;\n)\nprint 2;"),
+            (
+                "(print 1)\n(error unexpected (expr (error missing expression)))\n(print 2)"
+                    .to_string(),
+                1
+            )
+        );
+    }
+
+    #[test]
+    fn error_node_spans() {
+        let (program, _) = parse("print 1 2;\nprint (3 4);");
+
+        let Declaration::Error { span, children, .. } = &program.declarations[0] else {
+            panic!("{:?}", program)
+        };
+        // The skipped `2;` is part of the error, not of the statement.
+        assert_eq!(*span, Span::new(0, 10, 1));
+        assert_eq!(children[0].span(), Span::new(0, 7, 1));
+
+        let Declaration::Statement(print) = &program.declarations[1] else {
+            panic!("{:?}", program)
+        };
+        let StmtKind::Print(group) = &print.kind else {
+            panic!("{:?}", print)
+        };
+        assert_eq!(group.span, Span::new(17, 22, 2));
+    }
+}
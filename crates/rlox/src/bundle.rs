@@ -0,0 +1,85 @@
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// Ends a bundled executable, after the length of the payload.
+const MAGIC: &[u8; 8] = b"RLOXBNDL";
+
+/// The payload length and the magic.
+const TRAILER_LEN: usize = 16;
+
+/// Writes a copy of `executable` to `output` with `payload` appended, the copy runs the payload
+/// when it starts. A payload `executable` already carries is replaced.
+pub fn write(executable: &Path, payload: &[u8], output: &Path) -> io::Result<()> {
+    let mut bytes = fs::read(executable)?;
+    let carried = payload_len(&bytes).map_or(0, |length| length + TRAILER_LEN);
+    if carried <= bytes.len() {
+        bytes.truncate(bytes.len() - carried);
+    }
+    bytes.extend(payload);
+    bytes.extend((payload.len() as u64).to_le_bytes());
+    bytes.extend(MAGIC);
+    fs::write(output, bytes)?;
+    fs::set_permissions(output, fs::metadata(executable)?.permissions())
+}
+
+/// The payload appended to `executable`, reading only its end.
+pub fn payload(executable: &Path) -> io::Result<Option<Vec<u8>>> {
+    let mut file = File::open(executable)?;
+    let size = file.metadata()?.len() as usize;
+    if size < TRAILER_LEN {
+        return Ok(None);
+    }
+    let mut trailer = [0; TRAILER_LEN];
+    file.seek(SeekFrom::Start((size - TRAILER_LEN) as u64))?;
+    file.read_exact(&mut trailer)?;
+    let Some(length) = payload_len(&trailer) else {
+        return Ok(None);
+    };
+    if length > size - TRAILER_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Bundle is cut off",
+        ));
+    }
+    let mut payload = vec![0; length];
+    file.seek(SeekFrom::Start((size - TRAILER_LEN - length) as u64))?;
+    file.read_exact(&mut payload)?;
+    Ok(Some(payload))
+}
+
+/// The length of the payload `bytes` end with, if they end with a trailer.
+fn payload_len(bytes: &[u8]) -> Option<usize> {
+    let length = bytes.strip_suffix(MAGIC)?;
+    let length = length.get(length.len().checked_sub(8)?..)?;
+    Some(u64::from_le_bytes(length.try_into().expect("Took 8 bytes")) as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{payload, write};
+    use std::{env, fs, process};
+
+    #[test]
+    fn append_and_read_payloads() {
+        let dir = env::temp_dir().join(format!("rlox-bundle-{}", process::id()));
+        fs::create_dir_all(&dir).expect("Create a directory for the bundles");
+        let (executable, app, again) = (dir.join("rlox"), dir.join("app"), dir.join("again"));
+        fs::write(&executable, b"\x7fELF and the rest").expect("Write the executable");
+        assert_eq!(payload(&executable).expect("Read the executable"), None);
+
+        write(&executable, b"bytecode", &app).expect("Bundle");
+        assert_eq!(
+            payload(&app).expect("Read the bundle"),
+            Some(b"bytecode".to_vec())
+        );
+        write(&app, b"", &again).expect("Bundle a bundle");
+        assert_eq!(payload(&again).expect("Read the bundle"), Some(Vec::new()));
+        let mut bytes = fs::read(&executable).expect("Read the executable");
+        bytes.extend(0u64.to_le_bytes());
+        bytes.extend(super::MAGIC);
+        assert_eq!(fs::read(&again).expect("Read the bundle"), bytes);
+
+        fs::remove_dir_all(&dir).expect("Remove the bundle directory");
+    }
+}
@@ -0,0 +1,315 @@
+use crate::chunk::{Chunk, OpCode};
+use crate::error_code::ErrorCode;
+use crate::value::Value;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt::Write;
+
+/// The C runtime the translated code calls into.
+const RUNTIME: &str = include_str!("../runtime/rlox.c");
+
+/// The native functions of the runtime, by name: their arity and the C function.
+const NATIVES: [(&str, usize, &str); 1] = [("clock", 0, "lox_clock")];
+
+/// Translates `chunk` into a C program of its own, the runtime included, that does what
+/// running the chunk does. Every instruction becomes a call into the runtime and jumps become
+/// `goto`s, build it with any C99 compiler: `cc script.c -o script`. A chunk that fails
+/// `Chunk::verify` is not translated, the error says why.
+pub fn emit(chunk: &Chunk, name: &str) -> Result<String, String> {
+    // The stack is as deep as the code takes it. Within an instruction it grows by two at
+    // most, `AddLocals` pushes both locals before adding them.
+    let heights = chunk.stack_heights()?;
+    let depth = heights.into_iter().flatten().max().unwrap_or(0) + 2;
+
+    let mut c = format!(
+        "/* Compiled from {} by rlox. */\n\n",
+        name.replace("*/", "* /")
+    );
+    for (define, code) in [
+        ("RLOX_OPERANDS_NOT_NUMBERS", ErrorCode::OperandsNotNumbers),
+        ("RLOX_OPERAND_NOT_NUMBER", ErrorCode::OperandNotNumber),
+        ("RLOX_OPERANDS_NOT_ADDABLE", ErrorCode::OperandsNotAddable),
+        ("RLOX_UNDEFINED_VARIABLE", ErrorCode::UndefinedVariable),
+        ("RLOX_NOT_CALLABLE", ErrorCode::NotCallable),
+        ("RLOX_ARITY_MISMATCH", ErrorCode::ArityMismatch),
+    ] {
+        writeln!(c, "#define {} \"{}\"", define, code).unwrap();
+    }
+    writeln!(c, "#define LOX_STACK_MAX {}", depth).unwrap();
+    c += "\n";
+    c += RUNTIME;
+
+    let mut offsets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        offsets.push(offset);
+        offset += 1 + chunk.get_op_code(offset).operand_bytes();
+    }
+    let targets: BTreeSet<usize> = offsets
+        .iter()
+        .filter(|&&offset| chunk.get_op_code(offset).is_jump())
+        .map(|&offset| chunk.jump_target(offset))
+        .collect();
+
+    // Each global name gets a slot of `lox_globals`, in the order the code mentions them. The
+    // ones naming a native start out holding it.
+    let mut globals: HashMap<&str, usize> = HashMap::new();
+    let mut names = Vec::new();
+    for &offset in &offsets {
+        if let OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal =
+            chunk.get_op_code(offset)
+        {
+            let name = name_at(chunk, offset);
+            globals.entry(name).or_insert_with(|| {
+                names.push(name);
+                names.len() - 1
+            });
+        }
+    }
+    if !names.is_empty() {
+        c += "\nstatic LoxGlobal lox_globals[] = {\n";
+        for name in &names {
+            let literal = string_literal(name);
+            match NATIVES.iter().find(|native| native.0 == *name) {
+                Some((_, arity, function)) => writeln!(
+                    c,
+                    "    {{{0}, true, {{LOX_NATIVE, {{.native = &(const LoxNative){{{0}, {1}, {2}}}}}}}}},",
+                    literal, arity, function
+                ),
+                None => writeln!(c, "    {{{}, false, {{LOX_NIL, {{false}}}}}},", literal),
+            }
+            .unwrap();
+        }
+        c += "};\n";
+    }
+
+    c += "\nint main(void) {\n    lox_start_clock();\n";
+    for &offset in &offsets {
+        if targets.contains(&offset) {
+            writeln!(c, "L{}:", offset).unwrap();
+        }
+        let line = chunk.get_line(offset);
+        let byte = |n: usize| chunk.code[offset + n] as usize;
+        let global = || globals[name_at(chunk, offset)];
+        let target = || chunk.jump_target(offset);
+        let statement = match chunk.get_op_code(offset) {
+            OpCode::Constant => format!("lox_push({});", constant(chunk, byte(1))),
+            OpCode::Nil => "lox_push(lox_nil());".to_string(),
+            OpCode::True => "lox_push(lox_bool(true));".to_string(),
+            OpCode::False => "lox_push(lox_bool(false));".to_string(),
+            OpCode::Equal => "lox_op_equal();".to_string(),
+            OpCode::Greater => format!("lox_op_greater({});", line),
+            OpCode::GreaterEqual => format!("lox_op_greater_equal({});", line),
+            OpCode::Less => format!("lox_op_less({});", line),
+            OpCode::LessEqual => format!("lox_op_less_equal({});", line),
+            OpCode::Add => format!("lox_op_add({});", line),
+            OpCode::Subtract => format!("lox_op_subtract({});", line),
+            OpCode::Multiply => format!("lox_op_multiply({});", line),
+            OpCode::Divide => format!("lox_op_divide({});", line),
+            OpCode::Negate => format!("lox_op_negate({});", line),
+            OpCode::Not => "lox_op_not();".to_string(),
+            OpCode::Stringify => "lox_op_stringify();".to_string(),
+            OpCode::Print => "lox_op_print();".to_string(),
+            OpCode::Pop => "lox_pop();".to_string(),
+            OpCode::DefineGlobal => format!("lox_op_define_global(&lox_globals[{}]);", global()),
+            OpCode::GetGlobal => {
+                format!("lox_op_get_global(&lox_globals[{}], {});", global(), line)
+            }
+            OpCode::SetGlobal => {
+                format!("lox_op_set_global(&lox_globals[{}], {});", global(), line)
+            }
+            OpCode::GetLocal => format!("lox_op_get_local({});", byte(1)),
+            OpCode::SetLocal => format!("lox_op_set_local({});", byte(1)),
+            OpCode::Jump | OpCode::Loop => format!("goto L{};", target()),
+            OpCode::Call => format!("lox_op_call({}, {});", byte(1), line),
+            OpCode::JumpIfFalse => format!("if (lox_is_falsey(lox_pop())) goto L{};", target()),
+            OpCode::JumpIfTrue => format!("if (!lox_is_falsey(lox_pop())) goto L{};", target()),
+            OpCode::JumpIfFalseOrPop => {
+                format!(
+                    "if (lox_is_falsey(lox_peek())) goto L{};\n    lox_pop();",
+                    target()
+                )
+            }
+            OpCode::JumpIfTrueOrPop => {
+                format!(
+                    "if (!lox_is_falsey(lox_peek())) goto L{};\n    lox_pop();",
+                    target()
+                )
+            }
+            OpCode::SetLocalPop => format!("lox_op_set_local({});\n    lox_pop();", byte(1)),
+            OpCode::AddConstant => {
+                format!(
+                    "lox_push({});\n    lox_op_add({});",
+                    constant(chunk, byte(1)),
+                    line
+                )
+            }
+            OpCode::AddLocals => format!(
+                "lox_op_get_local({});\n    lox_op_get_local({});\n    lox_op_add({});",
+                byte(1),
+                byte(2),
+                line
+            ),
+            OpCode::JumpIfNotLess => {
+                format!(
+                    "lox_op_less({});\n    if (lox_is_falsey(lox_pop())) goto L{};",
+                    line,
+                    target()
+                )
+            }
+            OpCode::Return | OpCode::Eop => "return 0;".to_string(),
+        };
+        writeln!(c, "    {}", statement).unwrap();
+    }
+    c += "    return 0;\n}\n";
+    Ok(c)
+}
+
+fn name_at(chunk: &Chunk, offset: usize) -> &str {
+    let name = chunk.get_constant(chunk.code[offset + 1] as usize);
+    name.and_then(Value::as_str).unwrap_or_default()
+}
+
+/// The C expression for the constant at `idx`.
+fn constant(chunk: &Chunk, idx: usize) -> String {
+    let value = chunk.get_constant(idx);
+    if let Some(string) = value.and_then(Value::as_str) {
+        return format!("lox_string({})", string_literal(string));
+    }
+    match value {
+        Some(&Value::Number(number)) => {
+            // `{:?}` keeps a `.0` or an exponent, it reads back as the same double in C.
+            let literal = match number {
+                number if number.is_nan() => "NAN".to_string(),
+                f64::INFINITY => "INFINITY".to_string(),
+                f64::NEG_INFINITY => "-INFINITY".to_string(),
+                number => format!("{:?}", number),
+            };
+            format!("lox_number({})", literal)
+        }
+        Some(&Value::Bool(boolean)) => format!("lox_bool({})", boolean),
+        _ => "lox_nil()".to_string(),
+    }
+}
+
+/// A C string literal of `string`, every byte outside printable ASCII escaped in octal.
+fn string_literal(string: &str) -> String {
+    let mut literal = String::from("\"");
+    for byte in string.bytes() {
+        match byte {
+            b'"' | b'\\' => {
+                literal.push('\\');
+                literal.push(byte as char);
+            }
+            // A `?` could start a trigraph.
+            b' '..=b'~' if byte != b'?' => literal.push(byte as char),
+            _ => write!(literal, "\\{:03o}", byte).unwrap(),
+        }
+    }
+    literal.push('"');
+    literal
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emit;
+    use crate::chunk::{Chunk, OpCode};
+    use crate::compiler::{self, OptLevel};
+    use crate::optimizer::tests::Output;
+    use crate::{optimizer, register_vm, Vm};
+    use std::path::Path;
+    use std::process::Command;
+    use std::{env, fs, process};
+
+    /// Programs for the parts of the runtime the other test programs leave out: printing
+    /// numbers like Rust does, strings that need escaping in C, a stack as deep as the code
+    /// takes it and calling `clock`.
+    const PROGRAMS: [&str; 9] = [
+        "print 0.1 + 0.2; print 1 / 3; print 1000000000 * 1000000000 * 1000 * 10; print -0;\n\
+         print 0.000000123; print 1 / 0; print -1 / 0; print 0 / 0; print 123.5 * -1;",
+        "var s = \"quote \\\" backslash \\\\ tab\\t??= é\"; print s; print \"${s}${1.5}${nil}${true}\";",
+        "var a = 1; var b; print b; b = a = 2; print a + b; print a == b; print \"a\" == \"a\"; print nil == false;",
+        "{ var i = 0; while (i < 3) { var j = i; while (j > 0) { print j; j = j - 1; } i = i + 1; } }",
+        "print 1;\nprint 2 + nil;",
+        "var x = 1;\nx = 2;\ny = 3;",
+        "{ var a = 1; print (a + (a + (a + (a + (a + (a + (a + (a + a)))))))); }",
+        "var t = clock(); print t >= 0; print clock; print clock == clock; var c = clock; c();",
+        "print 1;\nclock(nil);",
+    ];
+
+    /// What the interpreter prints for `source` and its exit status.
+    fn interpret(source: &str, level: OptLevel) -> (String, i32) {
+        let output = Output::default();
+        let mut vm = Vm::with_output(output.clone()).opt_level(level);
+        let chunk = vm.compile(source).expect("Compiles");
+        let status = match vm.run(chunk) {
+            Err(_) => 70,
+            Ok(()) => 0,
+        };
+        let printed = String::from_utf8(output.0.take()).expect("Output is UTF-8");
+        (printed, status)
+    }
+
+    /// What the C translation of `source`, built with `cc` in `dir`, prints and its exit status.
+    fn build_and_run(source: &str, level: OptLevel, dir: &Path) -> (String, i32) {
+        let mut chunk = Chunk::new();
+        assert!(
+            compiler::compile(source, &mut chunk, level).is_empty(),
+            "{}",
+            source
+        );
+        let (c, executable) = (dir.join("script.c"), dir.join("script"));
+        let translated = emit(&chunk, "script.lox").expect("Compiled code verifies");
+        fs::write(&c, translated).expect("Write C file");
+
+        let cc = Command::new("cc")
+            .args(["-std=c99", "-Wall", "-Werror", "-o"])
+            .args([&executable, &c])
+            .arg("-lm")
+            .output()
+            .expect("Run cc");
+        assert!(
+            cc.status.success(),
+            "{}\n{}",
+            source,
+            String::from_utf8_lossy(&cc.stderr)
+        );
+        let run = Command::new(&executable)
+            .output()
+            .expect("Run the executable");
+        let printed = String::from_utf8(run.stdout).expect("Output is UTF-8");
+        (printed, run.status.code().unwrap_or(-1))
+    }
+
+    #[test]
+    fn c_matches_interpreter() {
+        let dir = env::temp_dir().join(format!("rlox-c-backend-{}", process::id()));
+        fs::create_dir_all(&dir).expect("Create a directory for the build");
+        let programs = compiler::tests::PROGRAMS
+            .iter()
+            .chain(&optimizer::tests::PROGRAMS)
+            .chain(&register_vm::tests::PROGRAMS)
+            .chain(&PROGRAMS);
+        for source in programs {
+            for level in [OptLevel::None, OptLevel::Fuse] {
+                let expected = interpret(source, level);
+                let built = build_and_run(source, level, &dir);
+                assert_eq!(built, expected, "{} at {:?}", source, level);
+            }
+        }
+        fs::remove_dir_all(&dir).expect("Remove the build directory");
+
+        assert_eq!(
+            interpret(PROGRAMS[0], OptLevel::default()).0,
+            "0.30000000000000004\n0.3333333333333333\n10000000000000000000000\n-0\n\
+             0.000000123\ninf\n-inf\nNaN\n-123.5\n"
+        );
+    }
+    #[test]
+    fn refuse_code_that_does_not_verify() {
+        let mut chunk = Chunk::new();
+        for op in [OpCode::Pop, OpCode::Return] {
+            chunk.push_op_code(op, 1);
+        }
+        assert!(emit(&chunk, "script.lox").is_err());
+    }
+}
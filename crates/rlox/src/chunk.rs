@@ -1,24 +1,163 @@
+use crate::diagnostic::Span;
+use crate::value::Value;
+use std::collections::HashMap;
+
 #[repr(u8)]
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
 pub enum OpCode {
     Constant,
+    Nil,
+    True,
+    False,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
     Add,
     Subtract,
     Multiply,
     Divide,
     Negate,
+    Not,
+    Stringify,
+    Print,
+    Pop,
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    /// Jumps forward, by the 16-bit offset that follows.
+    Jump,
+    /// Pops the condition and jumps forward when it is false.
+    JumpIfFalse,
+    /// Pops the condition and jumps forward when it is true.
+    JumpIfTrue,
+    /// Keeps a false left operand of `and` as the result, pops a true one.
+    JumpIfFalseOrPop,
+    /// Keeps a true left operand of `or` as the result, pops a false one.
+    JumpIfTrueOrPop,
+    /// Jumps backward, by the 16-bit offset that follows.
+    Loop,
+    /// Calls the value below the arguments, the operand counts the arguments.
+    Call,
+    /// `SetLocal` then `Pop`, an assignment statement.
+    SetLocalPop,
+    /// `Constant` then `Add`, like `i + 1`.
+    AddConstant,
+    /// `GetLocal` twice then `Add`, the two slots follow the instruction.
+    AddLocals,
+    /// `Less` then `JumpIfFalse`, jumps forward when the left operand is not less.
+    JumpIfNotLess,
     Return,
-    EOP,
+    Eop,
+}
+
+impl OpCode {
+    /// The number of bytes following the instruction in the code.
+    pub fn operand_bytes(self) -> usize {
+        match self {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::SetLocalPop
+            | OpCode::AddConstant
+            | OpCode::Call => 1,
+            OpCode::AddLocals => 2,
+            op if op.is_jump() => 2,
+            _ => 0,
+        }
+    }
+
+    /// Whether the operand is a 16-bit jump offset.
+    pub fn is_jump(self) -> bool {
+        matches!(
+            self,
+            OpCode::Jump
+                | OpCode::JumpIfFalse
+                | OpCode::JumpIfTrue
+                | OpCode::JumpIfFalseOrPop
+                | OpCode::JumpIfTrueOrPop
+                | OpCode::Loop
+                | OpCode::JumpIfNotLess
+        )
+    }
+
+    /// The opcode of a byte of code, `None` if no opcode has that value.
+    pub fn from_byte(byte: Code) -> Option<OpCode> {
+        if byte <= OpCode::Eop as Code {
+            Some(unsafe { ::std::mem::transmute::<Code, OpCode>(byte) })
+        } else {
+            None
+        }
+    }
+
+    /// How many values the instruction needs on the stack, and how many it leaves there in
+    /// their place. Conditional jumps that pop only when they fall through count as popping.
+    /// `Call` also pops its arguments, as many as its operand says.
+    fn stack_effect(self) -> (usize, usize) {
+        match self {
+            OpCode::Constant
+            | OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::GetGlobal
+            | OpCode::GetLocal
+            | OpCode::AddLocals => (0, 1),
+            OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide => (2, 1),
+            OpCode::Negate
+            | OpCode::Not
+            | OpCode::Stringify
+            | OpCode::SetGlobal
+            | OpCode::SetLocal
+            | OpCode::AddConstant
+            | OpCode::Call => (1, 1),
+            OpCode::Print
+            | OpCode::Pop
+            | OpCode::DefineGlobal
+            | OpCode::SetLocalPop
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfTrue
+            | OpCode::JumpIfFalseOrPop
+            | OpCode::JumpIfTrueOrPop => (1, 0),
+            OpCode::JumpIfNotLess => (2, 0),
+            OpCode::Jump | OpCode::Loop | OpCode::Return | OpCode::Eop => (0, 0),
+        }
+    }
 }
 
 pub type Code = u8;
 
-pub type Value = f64;
+/// Starts serialized bytecode, the last byte is the version of the format.
+const MAGIC: &[u8; 8] = b"RLOXBC\0\x02";
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Default)]
 pub struct Chunk {
     pub code: Vec<Code>,
     constants: Vec<Value>,
-    lines: Vec<usize>,
+    /// The source of each byte of code, runtime errors point at the span of their instruction.
+    spans: Vec<Span>,
+}
+
+/// Chunks are the same when they run the same code on the same lines, wherever in the lines
+/// their instructions came from.
+impl PartialEq for Chunk {
+    fn eq(&self, other: &Self) -> bool {
+        let lines = |chunk: &Chunk| chunk.spans.iter().map(|span| span.line).collect::<Vec<_>>();
+        self.code == other.code && self.constants == other.constants && lines(self) == lines(other)
+    }
 }
 
 impl Chunk {
@@ -26,26 +165,24 @@ impl Chunk {
         Chunk {
             code: Vec::new(),
             constants: Vec::new(),
-            lines: Vec::new(),
+            spans: Vec::new(),
         }
     }
 
-    pub fn push_chunk(&mut self, code: Code, line: usize) {
+    /// `span` is where in the source the byte comes from, a line number for code without one.
+    pub fn push_chunk(&mut self, code: Code, span: impl Into<Span>) {
         self.code.push(code);
-        self.lines.push(line);
+        self.spans.push(span.into());
     }
 
-    pub fn push_op_code(&mut self, code: OpCode, line: usize) {
-        self.push_chunk(code as Code, line)
+    pub fn push_op_code(&mut self, code: OpCode, span: impl Into<Span>) {
+        self.push_chunk(code as Code, span)
     }
 
     pub fn get_op_code(&self, offset: usize) -> OpCode {
-        if offset < self.code.len() {
-            let chunk = self.code[offset];
-            let op_code: OpCode = unsafe { ::std::mem::transmute(chunk) };
-            op_code
-        } else {
-            OpCode::EOP
+        match self.code.get(offset) {
+            Some(&code) => OpCode::from_byte(code).expect("Invalid opcode"),
+            None => OpCode::Eop,
         }
     }
 
@@ -54,10 +191,214 @@ impl Chunk {
         self.constants.len() - 1
     }
 
+    pub fn constant_count(&self) -> usize {
+        self.constants.len()
+    }
+
+    /// Drops the code from `code_len` and the constants from `constant_count` on.
+    pub fn truncate(&mut self, code_len: usize, constant_count: usize) {
+        self.code.truncate(code_len);
+        self.spans.truncate(code_len);
+        self.constants.truncate(constant_count);
+    }
+
     pub fn get_constant(&self, idx: usize) -> Option<&Value> {
         self.constants.get(idx)
     }
 
+    pub fn get_line(&self, offset: usize) -> usize {
+        self.spans[offset].line
+    }
+
+    pub fn get_span(&self, offset: usize) -> Span {
+        self.spans[offset]
+    }
+
+    /// Reads the 16-bit big endian operand at `offset`.
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    /// Overwrites the 16-bit operand at `offset`, for jumps emitted before their target.
+    pub fn patch_u16(&mut self, offset: usize, value: u16) {
+        self.code[offset..offset + 2].copy_from_slice(&value.to_be_bytes());
+    }
+
+    /// The chunk as bytes `from_bytes` reads back: the code, the span of each byte and the
+    /// constants, each list led by its length. Numbers are little endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        let length = |length: usize| (length as u32).to_le_bytes();
+        bytes.extend(length(self.code.len()));
+        bytes.extend(&self.code);
+        for span in &self.spans {
+            for position in [span.start, span.end, span.line] {
+                bytes.extend(length(position));
+            }
+        }
+        bytes.extend(length(self.constants.len()));
+        for constant in &self.constants {
+            if let Some(string) = constant.as_str() {
+                bytes.push(1);
+                bytes.extend(length(string.len()));
+                bytes.extend(string.as_bytes());
+            } else {
+                let number = constant
+                    .as_number()
+                    .expect("Constants are numbers or strings");
+                bytes.push(0);
+                bytes.extend(number.to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Reads a chunk `to_bytes` wrote, the error says why `bytes` are not one that can run.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Chunk, String> {
+        let rest = bytes
+            .strip_prefix(MAGIC)
+            .ok_or("Not rlox bytecode of this version")?;
+        let mut reader = Reader(rest);
+        let mut chunk = Chunk::new();
+        let code_len = reader.length()?;
+        chunk.code = reader.take(code_len)?.to_vec();
+        for _ in 0..code_len {
+            let (start, end, line) = (reader.length()?, reader.length()?, reader.length()?);
+            chunk.spans.push(Span::new(start, end, line));
+        }
+        for _ in 0..reader.length()? {
+            let constant = match reader.take(1)?[0] {
+                0 => Value::Number(f64::from_le_bytes(reader.array()?)),
+                1 => {
+                    let length = reader.length()?;
+                    let string = std::str::from_utf8(reader.take(length)?);
+                    Value::string(string.map_err(|error| error.to_string())?)
+                }
+                tag => return Err(format!("Unknown constant tag {}", tag)),
+            };
+            chunk.constants.push(constant);
+        }
+        chunk.verify()?;
+        Ok(chunk)
+    }
+
+    /// Checks that the code can run: every instruction is whole, constants, names and local
+    /// slots exist, jumps land on instructions, the stack never underflows and has the same
+    /// height whichever way an instruction is reached. The error names the offending offset.
+    pub fn verify(&self) -> Result<(), String> {
+        self.stack_heights().map(|_| ())
+    }
+
+    /// The height of the stack before each instruction, by offset, as `verify` works it out.
+    /// `None` for offsets inside an instruction and for instructions no path reaches.
+    pub fn stack_heights(&self) -> Result<Vec<Option<usize>>, String> {
+        let fail = |offset: usize, message: String| Err(format!("{:04}: {}", offset, message));
+        // The stack height before each instruction, once a path to it has been followed. Only
+        // the offsets where an instruction starts are in the map.
+        let mut heights: HashMap<usize, Option<usize>> = HashMap::new();
+        let mut offset = 0;
+        while offset < self.code.len() {
+            let Some(op) = OpCode::from_byte(self.code[offset]) else {
+                return fail(offset, format!("invalid opcode {}", self.code[offset]));
+            };
+            heights.insert(offset, None);
+            offset += 1 + op.operand_bytes();
+        }
+        if offset > self.code.len() {
+            return fail(
+                self.code.len(),
+                "the last instruction is cut off".to_string(),
+            );
+        }
+
+        let mut pending = vec![(0, 0)];
+        while let Some((offset, height)) = pending.pop() {
+            let known = match heights.get_mut(&offset) {
+                Some(known) => known,
+                None if offset == self.code.len() => {
+                    return fail(offset, "ran off the end".to_string())
+                }
+                None => return fail(offset, "jumped into an instruction".to_string()),
+            };
+            match *known {
+                Some(known) if known == height => continue,
+                Some(known) => {
+                    return fail(offset, format!("stack height {} or {}", known, height))
+                }
+                None => *known = Some(height),
+            }
+
+            let op = self.get_op_code(offset);
+            let operand = |n: usize| self.code[offset + n] as usize;
+            let missing = match op {
+                OpCode::Constant | OpCode::AddConstant if operand(1) >= self.constants.len() => {
+                    Some(format!("no constant {}", operand(1)))
+                }
+                OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal
+                    if self
+                        .get_constant(operand(1))
+                        .and_then(Value::as_str)
+                        .is_none() =>
+                {
+                    Some(format!("constant {} is not a name", operand(1)))
+                }
+                // A local is below the operands of the instruction.
+                OpCode::GetLocal | OpCode::SetLocal | OpCode::SetLocalPop | OpCode::AddLocals => {
+                    let slot = match op {
+                        OpCode::AddLocals => operand(1).max(operand(2)),
+                        _ => operand(1),
+                    };
+                    let locals = height - op.stack_effect().0.min(height);
+                    (slot >= locals).then(|| format!("no local in slot {}", slot))
+                }
+                _ => None,
+            };
+            if let Some(message) = missing {
+                return fail(offset, message);
+            }
+
+            let (pops, pushes) = match op {
+                OpCode::Call => (operand(1) + 1, 1),
+                _ => op.stack_effect(),
+            };
+            if height < pops {
+                return fail(
+                    offset,
+                    format!("{:?} pops {} values off {}", op, pops, height),
+                );
+            }
+            let after = height - pops + pushes;
+            let next = offset + 1 + op.operand_bytes();
+            match op {
+                OpCode::Return | OpCode::Eop => {}
+                OpCode::Jump | OpCode::Loop => pending.push((self.jump_target(offset), after)),
+                // Jumping keeps the value falling through pops.
+                OpCode::JumpIfFalseOrPop | OpCode::JumpIfTrueOrPop => {
+                    pending.extend([(next, after), (self.jump_target(offset), height)])
+                }
+                op if op.is_jump() => {
+                    pending.extend([(next, after), (self.jump_target(offset), after)])
+                }
+                _ => pending.push((next, after)),
+            }
+        }
+
+        let mut by_offset = vec![None; self.code.len()];
+        for (offset, height) in heights {
+            by_offset[offset] = height;
+        }
+        Ok(by_offset)
+    }
+
+    /// Where the jump at `offset` lands, `usize::MAX` for a loop back past the start.
+    pub fn jump_target(&self, offset: usize) -> usize {
+        let jump = self.read_u16(offset + 1) as usize;
+        match self.get_op_code(offset) {
+            OpCode::Loop => (offset + 3).checked_sub(jump).unwrap_or(usize::MAX),
+            _ => offset + 3 + jump,
+        }
+    }
+
     pub fn disassemble(&self, name: &str) {
         println!("== {} ==", name);
 
@@ -76,21 +417,49 @@ impl Chunk {
         }
 
         print!("{:0>4} ", offset);
-        if offset > 0 && self.lines[offset] == self.lines[offset - 1] {
+        if offset > 0 && self.get_line(offset) == self.get_line(offset - 1) {
             print!("   | ");
         } else {
-            print!("{: >4} ", self.lines[offset]);
+            print!("{: >4} ", self.get_line(offset));
         }
 
         match self.get_op_code(offset) {
             OpCode::Constant => self.constant_instruction("OP_CONSTANT", offset),
+            OpCode::Nil => self.simple_instruction("OP_NIL", offset),
+            OpCode::True => self.simple_instruction("OP_TRUE", offset),
+            OpCode::False => self.simple_instruction("OP_FALSE", offset),
+            OpCode::Equal => self.simple_instruction("OP_EQUAL", offset),
+            OpCode::Greater => self.simple_instruction("OP_GREATER", offset),
+            OpCode::GreaterEqual => self.simple_instruction("OP_GREATER_EQUAL", offset),
+            OpCode::Less => self.simple_instruction("OP_LESS", offset),
+            OpCode::LessEqual => self.simple_instruction("OP_LESS_EQUAL", offset),
             OpCode::Add => self.simple_instruction("OP_ADD", offset),
             OpCode::Subtract => self.simple_instruction("OP_SUBTRACT", offset),
             OpCode::Multiply => self.simple_instruction("OP_MULTIPLY", offset),
             OpCode::Divide => self.simple_instruction("OP_Divide", offset),
             OpCode::Negate => self.simple_instruction("OP_NEGATE", offset),
+            OpCode::Not => self.simple_instruction("OP_NOT", offset),
+            OpCode::Stringify => self.simple_instruction("OP_STRINGIFY", offset),
+            OpCode::Print => self.simple_instruction("OP_PRINT", offset),
+            OpCode::Pop => self.simple_instruction("OP_POP", offset),
+            OpCode::DefineGlobal => self.constant_instruction("OP_DEFINE_GLOBAL", offset),
+            OpCode::GetGlobal => self.constant_instruction("OP_GET_GLOBAL", offset),
+            OpCode::SetGlobal => self.constant_instruction("OP_SET_GLOBAL", offset),
+            OpCode::GetLocal => self.byte_instruction("OP_GET_LOCAL", offset),
+            OpCode::SetLocal => self.byte_instruction("OP_SET_LOCAL", offset),
+            OpCode::Jump => self.jump_instruction("OP_JUMP", 1, offset),
+            OpCode::JumpIfFalse => self.jump_instruction("OP_JUMP_IF_FALSE", 1, offset),
+            OpCode::JumpIfTrue => self.jump_instruction("OP_JUMP_IF_TRUE", 1, offset),
+            OpCode::JumpIfFalseOrPop => self.jump_instruction("OP_JUMP_IF_FALSE_OR_POP", 1, offset),
+            OpCode::JumpIfTrueOrPop => self.jump_instruction("OP_JUMP_IF_TRUE_OR_POP", 1, offset),
+            OpCode::Loop => self.jump_instruction("OP_LOOP", -1, offset),
+            OpCode::Call => self.byte_instruction("OP_CALL", offset),
+            OpCode::SetLocalPop => self.byte_instruction("OP_SET_LOCAL_POP", offset),
+            OpCode::AddConstant => self.constant_instruction("OP_ADD_CONSTANT", offset),
+            OpCode::AddLocals => self.two_byte_instruction("OP_ADD_LOCALS", offset),
+            OpCode::JumpIfNotLess => self.jump_instruction("OP_JUMP_IF_NOT_LESS", 1, offset),
             OpCode::Return => self.simple_instruction("OP_RETURN", offset),
-            OpCode::EOP => self.simple_instruction("OP_END_OF_PROGRAM", offset),
+            OpCode::Eop => self.simple_instruction("OP_END_OF_PROGRAM", offset),
         }
     }
 
@@ -99,10 +468,159 @@ impl Chunk {
         offset + 1
     }
 
+    fn byte_instruction(&self, name: &str, offset: usize) -> usize {
+        let slot = self.code[offset + 1];
+        println!("{: <16} {: >4}", name, slot);
+        offset + 2
+    }
+
+    fn two_byte_instruction(&self, name: &str, offset: usize) -> usize {
+        let (first, second) = (self.code[offset + 1], self.code[offset + 2]);
+        println!("{: <16} {: >4} {: >4}", name, first, second);
+        offset + 3
+    }
+
+    fn jump_instruction(&self, name: &str, sign: isize, offset: usize) -> usize {
+        let jump = self.read_u16(offset + 1) as isize;
+        let target = offset as isize + 3 + sign * jump;
+        println!("{: <16} {: >4} -> {}", name, offset, target);
+        offset + 3
+    }
+
     fn constant_instruction(&self, name: &str, offset: usize) -> usize {
         let const_idx = self.code[offset + 1] as usize;
-        let constant = self.constants[const_idx];
+        let constant = &self.constants[const_idx];
         println!("{: <16} {: >4} '{}'", name, offset, constant);
         offset + 2
     }
 }
+
+/// The bytes of a serialized chunk not read yet.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], String> {
+        if count > self.0.len() {
+            return Err("Bytecode is cut off".to_string());
+        }
+        let (taken, rest) = self.0.split_at(count);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], String> {
+        Ok(self.take(N)?.try_into().expect("Took N bytes"))
+    }
+
+    fn length(&mut self) -> Result<usize, String> {
+        Ok(u32::from_le_bytes(self.array()?) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Chunk, Code, OpCode};
+    use crate::compiler::{compile, OptLevel};
+    use crate::value::Value;
+
+    /// A chunk of raw bytes, all on line 1, with a number and a name in the pool.
+    fn chunk(code: &[Code]) -> Chunk {
+        let mut chunk = Chunk::new();
+        chunk.push_constant(Value::Number(1.0));
+        chunk.push_constant(Value::string("a"));
+        for &code in code {
+            chunk.push_chunk(code, 1);
+        }
+        chunk
+    }
+
+    #[test]
+    fn verify_compiled_code() {
+        let source = "var a = 1; { var b = a; while (b < 3 and a) { b = b + 1; print b + b; } }\n\
+                      print a or -a;";
+        for level in [OptLevel::None, OptLevel::Fold, OptLevel::Peephole] {
+            let mut chunk = Chunk::new();
+            assert!(compile(source, &mut chunk, level).is_empty());
+            assert_eq!(chunk.verify(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn reject_broken_code() {
+        const CONSTANT: Code = OpCode::Constant as Code;
+        const RETURN: Code = OpCode::Return as Code;
+        let cases: [(&[Code], &str); 9] = [
+            (&[200], "0000: invalid opcode 200"),
+            (&[CONSTANT], "0001: the last instruction is cut off"),
+            (&[CONSTANT, 0], "0002: ran off the end"),
+            (&[CONSTANT, 2, RETURN], "0000: no constant 2"),
+            (
+                &[OpCode::GetGlobal as Code, 0, RETURN],
+                "0000: constant 0 is not a name",
+            ),
+            (
+                &[OpCode::Pop as Code, RETURN],
+                "0000: Pop pops 1 values off 0",
+            ),
+            (
+                &[CONSTANT, 0, OpCode::SetLocal as Code, 0, RETURN],
+                "0002: no local in slot 0",
+            ),
+            (
+                &[OpCode::Jump as Code, 0, 1, CONSTANT, 0, RETURN],
+                "0004: jumped into an instruction",
+            ),
+            (
+                &[
+                    OpCode::True as Code,
+                    OpCode::JumpIfTrue as Code,
+                    0,
+                    2,
+                    CONSTANT,
+                    0,
+                    RETURN,
+                ],
+                "0006: stack height 0 or 1",
+            ),
+        ];
+        for (code, error) in cases {
+            assert_eq!(chunk(code).verify(), Err(error.to_string()), "{:?}", code);
+        }
+        let fused = [
+            CONSTANT,
+            0,
+            OpCode::AddLocals as Code,
+            0,
+            0,
+            OpCode::SetLocalPop as Code,
+            0,
+            RETURN,
+        ];
+        assert_eq!(chunk(&fused).verify(), Ok(()));
+    }
+
+    #[test]
+    fn serialize_chunks() {
+        let source =
+            "var s = \"é\";\n{ var n = 1.5; while (n < 3) n = n + 1; print s + \"${n}\"; }";
+        let mut chunk = Chunk::new();
+        assert!(compile(source, &mut chunk, OptLevel::default()).is_empty());
+        let bytes = chunk.to_bytes();
+        assert_eq!(Chunk::from_bytes(&bytes), Ok(chunk));
+
+        assert_eq!(
+            Chunk::from_bytes(b"#!/bin/sh"),
+            Err("Not rlox bytecode of this version".to_string())
+        );
+        assert_eq!(
+            Chunk::from_bytes(&bytes[..bytes.len() - 1]),
+            Err("Bytecode is cut off".to_string())
+        );
+        let mut broken = bytes.clone();
+        broken[12] = 200;
+        assert_eq!(
+            Chunk::from_bytes(&broken),
+            Err("0000: invalid opcode 200".to_string())
+        );
+    }
+}
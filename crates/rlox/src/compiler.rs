@@ -0,0 +1,869 @@
+use crate::ast::{
+    BinaryOp, Declaration, ErrorKind, Expr, ExprKind, InterpolationPart, LogicalOp, Program,
+    StmtKind, UnaryOp,
+};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error_code::ErrorCode;
+use crate::optimizer;
+use crate::parser::{Builder, Parser};
+use crate::scanner::Scanner;
+use crate::{Chunk, Code, OpCode, Value};
+
+/// How much work the compiler puts into making the bytecode faster.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Default)]
+pub enum OptLevel {
+    /// Bytecode for exactly what was written, `-O0`.
+    None,
+    /// Operations on literals are evaluated at compile time, `-O1`.
+    Fold,
+    /// The finished bytecode is also run through the peephole optimizer, `-O2`.
+    Peephole,
+    /// The peephole optimizer also fuses frequent instruction sequences into superinstructions,
+    /// `-O3`.
+    #[default]
+    Fuse,
+}
+
+impl OptLevel {
+    /// Reads a command line flag like `-O1`.
+    pub fn parse(flag: &str) -> Option<OptLevel> {
+        match flag {
+            "-O0" => Some(OptLevel::None),
+            "-O1" => Some(OptLevel::Fold),
+            "-O2" => Some(OptLevel::Peephole),
+            "-O3" => Some(OptLevel::Fuse),
+            _ => None,
+        }
+    }
+}
+
+/// Compiles `source` straight to bytecode while parsing it, returns every error.
+pub fn compile(source: &str, chunk: &mut Chunk, level: OptLevel) -> Vec<Diagnostic> {
+    let mut scanner = Scanner::new(source);
+    let mut compiler = Compiler::new(chunk, level);
+    Parser::new(&mut scanner, &mut compiler).parse()
+}
+
+/// Compiles a syntax tree, the bytecode is the same `compile` produces for its source.
+#[allow(dead_code)]
+pub fn generate(program: &Program, chunk: &mut Chunk, level: OptLevel) -> Vec<Diagnostic> {
+    let mut compiler = Compiler::new(chunk, level);
+    for declaration in &program.declarations {
+        compiler.declaration(declaration);
+    }
+    compiler.end(program.end)
+}
+
+/// Emits bytecode for the parser's events, or for a tree walked in the same order.
+pub struct Compiler<'a> {
+    chunk: &'a mut Chunk,
+    level: OptLevel,
+    /// The expressions whose values will be on the stack, in the same order.
+    operands: Vec<Operand>,
+    /// Whether each open interpolation has produced a string to append to yet.
+    interpolations: Vec<bool>,
+    /// The local variables in scope, the index of each is its slot on the stack.
+    locals: Vec<Local>,
+    scope_depth: usize,
+    /// For each variable being declared, the constant with its name if it is a global.
+    globals: Vec<Option<Code>>,
+    /// The operands of the jumps waiting for their target to be emitted.
+    jumps: Vec<(usize, Span)>,
+    /// Where the open loops start.
+    loops: Vec<usize>,
+    diagnostics: Vec<Diagnostic>,
+}
+
+struct Local {
+    name: String,
+    /// `None` until the initializer has run.
+    depth: Option<usize>,
+}
+
+/// The bytecode of an expression, from `start` to the end of the chunk once it is complete.
+struct Operand {
+    start: usize,
+    /// The size of the constant pool before the expression added to it.
+    constants: usize,
+    /// Known for literals, and for operations on literals that were folded.
+    value: Option<Value>,
+}
+
+impl<'a> Compiler<'a> {
+    pub fn new(chunk: &'a mut Chunk, level: OptLevel) -> Self {
+        Compiler {
+            chunk,
+            level,
+            operands: Vec::new(),
+            interpolations: Vec::new(),
+            locals: Vec::new(),
+            scope_depth: 0,
+            globals: Vec::new(),
+            jumps: Vec::new(),
+            loops: Vec::new(),
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn declaration(&mut self, declaration: &Declaration) {
+        match declaration {
+            Declaration::Var {
+                name,
+                name_span,
+                initializer,
+                span,
+            } => {
+                self.declare_variable(name.clone(), *name_span);
+                if let Some(initializer) = initializer {
+                    self.expression(initializer);
+                }
+                self.define_variable(initializer.is_some(), *span);
+            }
+            Declaration::Statement(stmt) => match &stmt.kind {
+                StmtKind::Print(expr) => {
+                    self.expression(expr);
+                    self.print_statement(stmt.span);
+                }
+                StmtKind::Expression(expr) => {
+                    self.expression(expr);
+                    self.expression_statement(stmt.span);
+                }
+                StmtKind::Block(declarations) => {
+                    self.begin_block();
+                    for declaration in declarations {
+                        self.declaration(declaration);
+                    }
+                    self.end_block(stmt.span);
+                }
+                StmtKind::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                } => {
+                    self.expression(condition);
+                    self.if_condition(condition.span);
+                    self.declaration(then_branch);
+                    if let Some((keyword, else_branch)) = else_branch {
+                        self.if_else(*keyword);
+                        self.declaration(else_branch);
+                    }
+                    self.end_if(else_branch.is_some(), stmt.span);
+                }
+                StmtKind::While { condition, body } => {
+                    self.begin_loop();
+                    self.expression(condition);
+                    self.loop_condition(condition.span);
+                    self.declaration(body);
+                    self.end_loop(stmt.span);
+                }
+            },
+            Declaration::Error {
+                kind,
+                span,
+                children,
+            } => {
+                for child in children {
+                    self.declaration(child);
+                }
+                self.declaration_error(*kind, *span, children.len());
+            }
+        }
+    }
+
+    fn expression(&mut self, expr: &Expr) {
+        match &expr.kind {
+            ExprKind::Nil => self.nil(expr.span),
+            ExprKind::Bool(value) => self.boolean(*value, expr.span),
+            ExprKind::Number(value) => self.number(*value, expr.span),
+            ExprKind::String(value) => self.string(value.clone(), expr.span),
+            ExprKind::Interpolation(parts) => {
+                self.begin_interpolation();
+                for part in parts {
+                    match part {
+                        InterpolationPart::Text(text, span) => {
+                            self.interpolation_text(text.clone(), *span)
+                        }
+                        InterpolationPart::Expr(value) => {
+                            self.expression(value);
+                            self.interpolation_value(value.span);
+                        }
+                    }
+                }
+                self.end_interpolation(expr.span);
+            }
+            ExprKind::Grouping(inner) => {
+                self.expression(inner);
+                self.grouping(expr.span);
+            }
+            ExprKind::Unary {
+                op,
+                operator,
+                operand,
+            } => {
+                self.expression(operand);
+                self.unary(*op, *operator);
+            }
+            ExprKind::Binary {
+                op,
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.expression(right);
+                self.binary(*op, *operator);
+            }
+            ExprKind::Logical {
+                op,
+                operator,
+                left,
+                right,
+            } => {
+                self.expression(left);
+                self.logical_operator(*op, *operator);
+                self.expression(right);
+                self.logical(*op, *operator);
+            }
+            ExprKind::Variable(name) => self.variable(name.clone(), expr.span),
+            ExprKind::Call {
+                callee,
+                arguments,
+                parens,
+            } => {
+                self.expression(callee);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.call(arguments.len(), *parens);
+            }
+            ExprKind::Assign { name, value } => {
+                self.expression(value);
+                self.assignment(name.clone(), expr.span);
+            }
+            ExprKind::Error { kind, children } => {
+                for child in children {
+                    self.expression(child);
+                }
+                self.expression_error(*kind, expr.span, children.len());
+            }
+        }
+    }
+
+    fn emit_op(&mut self, op: OpCode, span: Span) {
+        self.chunk.push_op_code(op, span);
+    }
+
+    fn emit_with_operand(&mut self, op: OpCode, operand: Code, span: Span) {
+        self.emit_op(op, span);
+        self.chunk.push_chunk(operand, span);
+    }
+
+    fn emit_constant(&mut self, value: Value, span: Span) {
+        let constant = self.make_constant(value, span);
+        self.emit_with_operand(OpCode::Constant, constant, span);
+    }
+
+    /// Emits a jump to a target patched in later, returns the offset of its operand.
+    fn emit_jump(&mut self, op: OpCode, span: Span) -> usize {
+        self.emit_op(op, span);
+        self.chunk.push_chunk(0xff, span);
+        self.chunk.push_chunk(0xff, span);
+        self.chunk.code.len() - 2
+    }
+
+    /// Makes the jump waiting for a target jump to the end of the code.
+    fn patch_jump(&mut self) {
+        let (offset, span) = self.jumps.pop().expect("Missing jump");
+        let jump = self.chunk.code.len() - offset - 2;
+        match u16::try_from(jump) {
+            Ok(jump) => self.chunk.patch_u16(offset, jump),
+            Err(_) => self.jump_too_large(span),
+        }
+    }
+
+    fn emit_loop(&mut self, start: usize, span: Span) {
+        self.emit_op(OpCode::Loop, span);
+        let jump = self.chunk.code.len() - start + 2;
+        let jump = u16::try_from(jump).unwrap_or_else(|_| {
+            self.jump_too_large(span);
+            u16::MAX
+        });
+        for byte in jump.to_be_bytes() {
+            self.chunk.push_chunk(byte, span);
+        }
+    }
+
+    fn jump_too_large(&mut self, span: Span) {
+        let error = Diagnostic::error(ErrorCode::JumpTooLarge, span, "Too much code to jump over.");
+        self.diagnostics.push(error);
+    }
+
+    /// Starts an operand for a literal `value` and emits the instruction that loads it.
+    fn literal(&mut self, value: Value, span: Span) {
+        self.operands.push(Operand {
+            start: self.chunk.code.len(),
+            constants: self.chunk.constant_count(),
+            value: Some(value.clone()),
+        });
+        match value {
+            Value::Nil => self.emit_op(OpCode::Nil, span),
+            Value::Bool(true) => self.emit_op(OpCode::True, span),
+            Value::Bool(false) => self.emit_op(OpCode::False, span),
+            value => self.emit_constant(value, span),
+        }
+    }
+
+    /// Replaces the last `arity` operands with the operation on them, emitting `op_codes` or,
+    /// when the operands are known and `evaluate` succeeds, loading the result as a literal.
+    /// `evaluate` returns `None` for operations that fail, they are left to fail at runtime.
+    fn operation(
+        &mut self,
+        arity: usize,
+        op_codes: &[OpCode],
+        span: Span,
+        evaluate: impl FnOnce(&[Value]) -> Option<Value>,
+    ) {
+        let first = self
+            .operands
+            .len()
+            .checked_sub(arity)
+            .expect("Missing operand");
+        let operands = self.operands.split_off(first);
+        let start = operands
+            .first()
+            .map_or(self.chunk.code.len(), |operand| operand.start);
+        let constants = operands
+            .first()
+            .map_or(self.chunk.constant_count(), |operand| operand.constants);
+
+        if self.level >= OptLevel::Fold {
+            let values: Option<Vec<Value>> =
+                operands.into_iter().map(|operand| operand.value).collect();
+            if let Some(value) = values.as_deref().and_then(evaluate) {
+                self.chunk.truncate(start, constants);
+                return self.literal(value, span);
+            }
+        }
+
+        for op_code in op_codes {
+            self.emit_op(*op_code, span);
+        }
+        self.operands.push(Operand {
+            start,
+            constants,
+            value: None,
+        });
+    }
+
+    /// Joins the string on top of the stack to the interpolation built so far, if any.
+    fn append_interpolated(&mut self, span: Span) {
+        let has_value = self
+            .interpolations
+            .last_mut()
+            .map(|has_value| std::mem::replace(has_value, true));
+        if has_value == Some(true) {
+            self.operation(2, &[OpCode::Add], span, |values| {
+                add(&values[0], &values[1])
+            });
+        }
+    }
+
+    /// The slot of the innermost local variable called `name`, `None` for globals.
+    fn resolve_local(&mut self, name: &str, span: Span) -> Option<Code> {
+        let slot = self.locals.iter().rposition(|local| local.name == name)?;
+        if self.locals[slot].depth.is_none() {
+            let error = Diagnostic::error(
+                ErrorCode::OwnInitializer,
+                span,
+                "Can't read local variable in its own initializer.",
+            );
+            self.diagnostics.push(error);
+        }
+        Some(slot as Code)
+    }
+
+    fn make_constant(&mut self, value: Value, span: Span) -> Code {
+        let constant_idx = self.chunk.push_constant(value);
+        match Code::try_from(constant_idx) {
+            Ok(constant) => constant,
+            Err(_) => {
+                // Reported once, every later constant overflows as well.
+                if constant_idx == Code::MAX as usize + 1 {
+                    let error = Diagnostic::error(
+                        ErrorCode::TooManyConstants,
+                        span,
+                        "Too many constants in one chunk",
+                    );
+                    self.diagnostics.push(error);
+                }
+                Code::MAX
+            }
+        }
+    }
+}
+
+/// Evaluates a binary operation on known operands, `None` if it fails.
+type BinaryFold = fn(&Value, &Value) -> Option<Value>;
+
+/// `+` on numbers and on strings, the same as the virtual machine's `OpCode::Add`.
+fn add(a: &Value, b: &Value) -> Option<Value> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => Some(Value::Number(a + b)),
+        _ => Some(Value::string(format!("{}{}", a.as_str()?, b.as_str()?))),
+    }
+}
+
+/// Arithmetic on numbers with the virtual machine's IEEE 754 semantics, `1 / 0` is infinity.
+fn arithmetic(a: &Value, b: &Value, apply: fn(f64, f64) -> f64) -> Option<Value> {
+    Some(Value::Number(apply(a.as_number()?, b.as_number()?)))
+}
+
+/// Comparisons are false when either side is NaN, so `a >= b` is not `!(a < b)`.
+fn comparison(a: &Value, b: &Value, apply: fn(&f64, &f64) -> bool) -> Option<Value> {
+    Some(Value::Bool(apply(&a.as_number()?, &b.as_number()?)))
+}
+
+impl Builder for Compiler<'_> {
+    fn nil(&mut self, span: Span) {
+        self.literal(Value::Nil, span);
+    }
+
+    fn boolean(&mut self, value: bool, span: Span) {
+        self.literal(Value::Bool(value), span);
+    }
+
+    fn number(&mut self, value: f64, span: Span) {
+        self.literal(Value::Number(value), span);
+    }
+
+    fn string(&mut self, value: String, span: Span) {
+        self.literal(Value::string(value), span);
+    }
+
+    fn begin_interpolation(&mut self) {
+        self.interpolations.push(false);
+    }
+
+    /// `"a ${b} c"` compiles to `"a " + str(b) + " c"`, empty texts are left out.
+    fn interpolation_text(&mut self, text: String, span: Span) {
+        if text.is_empty() {
+            return;
+        }
+        self.literal(Value::string(text), span);
+        self.append_interpolated(span);
+    }
+
+    fn interpolation_value(&mut self, span: Span) {
+        self.operation(1, &[OpCode::Stringify], span, |values| {
+            Some(Value::string(values[0].to_string()))
+        });
+        self.append_interpolated(span);
+    }
+
+    fn end_interpolation(&mut self, _span: Span) {
+        self.interpolations.pop();
+    }
+
+    fn grouping(&mut self, _span: Span) {}
+
+    fn unary(&mut self, op: UnaryOp, operator: Span) {
+        match op {
+            UnaryOp::Negate => self.operation(1, &[OpCode::Negate], operator, |values| {
+                Some(Value::Number(-values[0].as_number()?))
+            }),
+            UnaryOp::Not => self.operation(1, &[OpCode::Not], operator, |values| {
+                Some(Value::Bool(values[0].is_falsey()))
+            }),
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, operator: Span) {
+        use std::ops::{Div, Mul, Sub};
+
+        let (op_codes, evaluate): (&[OpCode], BinaryFold) = match op {
+            BinaryOp::Add => (&[OpCode::Add], add),
+            BinaryOp::Subtract => (&[OpCode::Subtract], |a, b| arithmetic(a, b, f64::sub)),
+            BinaryOp::Multiply => (&[OpCode::Multiply], |a, b| arithmetic(a, b, f64::mul)),
+            BinaryOp::Divide => (&[OpCode::Divide], |a, b| arithmetic(a, b, f64::div)),
+            BinaryOp::Equal => (&[OpCode::Equal], |a, b| Some(Value::Bool(a == b))),
+            BinaryOp::NotEqual => (&[OpCode::Equal, OpCode::Not], |a, b| {
+                Some(Value::Bool(a != b))
+            }),
+            BinaryOp::Greater => (&[OpCode::Greater], |a, b| comparison(a, b, f64::gt)),
+            BinaryOp::GreaterEqual => (&[OpCode::GreaterEqual], |a, b| comparison(a, b, f64::ge)),
+            BinaryOp::Less => (&[OpCode::Less], |a, b| comparison(a, b, f64::lt)),
+            BinaryOp::LessEqual => (&[OpCode::LessEqual], |a, b| comparison(a, b, f64::le)),
+        };
+        self.operation(2, op_codes, operator, |values| {
+            evaluate(&values[0], &values[1])
+        });
+    }
+
+    /// The parser has reported the error, nothing of this program will run.
+    fn variable(&mut self, name: String, span: Span) {
+        self.operation(0, &[], span, |_| None);
+        match self.resolve_local(&name, span) {
+            Some(slot) => self.emit_with_operand(OpCode::GetLocal, slot, span),
+            None => {
+                let name = self.make_constant(Value::string(name), span);
+                self.emit_with_operand(OpCode::GetGlobal, name, span);
+            }
+        }
+    }
+
+    /// Calls are never folded, natives are only known when the code runs. More than 255
+    /// arguments has been reported by the parser.
+    fn call(&mut self, arg_count: usize, span: Span) {
+        self.operation(arg_count + 1, &[], span, |_| None);
+        self.emit_with_operand(OpCode::Call, arg_count as Code, span);
+    }
+
+    /// The assigned value stays on the stack as the value of the assignment.
+    fn assignment(&mut self, name: String, span: Span) {
+        self.operation(1, &[], span, |_| None);
+        match self.resolve_local(&name, span) {
+            Some(slot) => self.emit_with_operand(OpCode::SetLocal, slot, span),
+            None => {
+                let name = self.make_constant(Value::string(name), span);
+                self.emit_with_operand(OpCode::SetGlobal, name, span);
+            }
+        }
+    }
+
+    /// The left operand decides the result of `false and x` and `true or x` on its own.
+    fn logical_operator(&mut self, op: LogicalOp, operator: Span) {
+        let op_code = match op {
+            LogicalOp::And => OpCode::JumpIfFalseOrPop,
+            LogicalOp::Or => OpCode::JumpIfTrueOrPop,
+        };
+        let jump = self.emit_jump(op_code, operator);
+        self.jumps.push((jump, operator));
+    }
+
+    fn logical(&mut self, _op: LogicalOp, operator: Span) {
+        self.patch_jump();
+        self.operation(2, &[], operator, |_| None);
+    }
+
+    /// The parser has reported the error, nothing of this program will run.
+    fn expression_error(&mut self, _kind: ErrorKind, span: Span, children: usize) {
+        self.operation(children, &[], span, |_| None);
+    }
+
+    fn print_statement(&mut self, span: Span) {
+        self.operands.pop();
+        self.emit_op(OpCode::Print, span);
+    }
+
+    fn expression_statement(&mut self, span: Span) {
+        self.operands.pop();
+        self.emit_op(OpCode::Pop, span);
+    }
+
+    fn declare_variable(&mut self, name: String, span: Span) {
+        if self.scope_depth == 0 {
+            let name = self.make_constant(Value::string(name), span);
+            return self.globals.push(Some(name));
+        }
+        self.globals.push(None);
+
+        let depth = self.scope_depth;
+        let mut in_scope =
+            (self.locals.iter().rev()).take_while(|local| local.depth.is_none_or(|d| d == depth));
+        if in_scope.any(|local| local.name == name) {
+            let error = Diagnostic::error(
+                ErrorCode::DuplicateVariable,
+                span,
+                "Already a variable with this name in this scope.",
+            );
+            self.diagnostics.push(error);
+        }
+        if self.locals.len() == Code::MAX as usize + 1 {
+            let error = Diagnostic::error(
+                ErrorCode::TooManyLocals,
+                span,
+                "Too many local variables in scope.",
+            );
+            self.diagnostics.push(error);
+        }
+        self.locals.push(Local { name, depth: None });
+    }
+
+    /// Globals are stored by name, a local stays on the stack where its initializer left it.
+    fn define_variable(&mut self, initialized: bool, span: Span) {
+        if !initialized {
+            self.nil(span);
+        }
+        self.operands.pop();
+        match self.globals.pop().expect("Missing variable") {
+            Some(name) => self.emit_with_operand(OpCode::DefineGlobal, name, span),
+            None => {
+                let local = self.locals.last_mut().expect("Missing local");
+                local.depth = Some(self.scope_depth);
+            }
+        }
+    }
+
+    fn begin_block(&mut self) {
+        self.scope_depth += 1;
+    }
+
+    fn end_block(&mut self, span: Span) {
+        self.scope_depth -= 1;
+        while self
+            .locals
+            .last()
+            .is_some_and(|local| local.depth.is_none_or(|d| d > self.scope_depth))
+        {
+            self.locals.pop();
+            self.emit_op(OpCode::Pop, span);
+        }
+    }
+
+    fn if_condition(&mut self, span: Span) {
+        self.operands.pop();
+        let jump = self.emit_jump(OpCode::JumpIfFalse, span);
+        self.jumps.push((jump, span));
+    }
+
+    fn if_else(&mut self, span: Span) {
+        let jump = self.emit_jump(OpCode::Jump, span);
+        self.patch_jump();
+        self.jumps.push((jump, span));
+    }
+
+    fn end_if(&mut self, _has_else: bool, _span: Span) {
+        self.patch_jump();
+    }
+
+    fn begin_loop(&mut self) {
+        self.loops.push(self.chunk.code.len());
+    }
+
+    fn loop_condition(&mut self, span: Span) {
+        self.if_condition(span);
+    }
+
+    fn end_loop(&mut self, span: Span) {
+        let start = self.loops.pop().expect("Missing loop");
+        self.emit_loop(start, span);
+        self.patch_jump();
+    }
+
+    fn declaration_error(&mut self, _kind: ErrorKind, _span: Span, _children: usize) {}
+
+    fn end(&mut self, span: Span) -> Vec<Diagnostic> {
+        self.emit_op(OpCode::Return, span);
+        let failed = self.diagnostics.iter().any(Diagnostic::is_error);
+        if self.level >= OptLevel::Peephole && !failed {
+            optimizer::optimize(self.chunk, self.level >= OptLevel::Fuse);
+        }
+        std::mem::take(&mut self.diagnostics)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{compile, generate, OptLevel};
+    use crate::ast;
+    use crate::{Chunk, OpCode, Value};
+
+    /// Programs covering every kind of node, each compiled both ways.
+    pub(crate) const PROGRAMS: [&str; 9] = [
+        "",
+        "1 + 2 * 3 - 4 / 5;",
+        "print -(1 - -2);\nprint (3);",
+        "print \"a\" +\n  \"b\";",
+        r#"print "x ${1 + 2} y ${"${3}"}${4}";"#,
+        "/// doc\nprint\n  1\n  +\n  2\n;\n\n",
+        "print !nil == (1 < 2) != (3 >= -\"a\");",
+        r#"print "${true}" + "${-"b"}";"#,
+        "var f = nil;\nprint f(1, 2 + 3)(f);",
+    ];
+
+    #[test]
+    fn generate_matches_single_pass() {
+        for level in [OptLevel::None, OptLevel::Fold] {
+            for source in PROGRAMS {
+                let mut single_pass = Chunk::new();
+                assert!(
+                    compile(source, &mut single_pass, level).is_empty(),
+                    "{}",
+                    source
+                );
+
+                let (program, diagnostics) = ast::parse(source);
+                assert!(diagnostics.is_empty(), "{}", source);
+                let mut generated = Chunk::new();
+                assert!(
+                    generate(&program, &mut generated, level).is_empty(),
+                    "{}",
+                    source
+                );
+
+                assert_eq!(generated, single_pass, "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn too_many_constants() {
+        let source = (0..300).map(|i| format!("{};", i)).collect::<String>();
+        let mut chunk = Chunk::new();
+        let messages: Vec<String> = compile(&source, &mut chunk, OptLevel::Fold)
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+
+        assert_eq!(
+            messages,
+            ["[line 1] Error[E0014]: Too many constants in one chunk"]
+        );
+    }
+
+    fn chunk(source: &str, level: OptLevel) -> Chunk {
+        let mut chunk = Chunk::new();
+        let diagnostics = compile(source, &mut chunk, level);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        chunk
+    }
+
+    /// `print <value>;` as the folding compiler should emit it.
+    fn print_constant(value: Value) -> Chunk {
+        let mut expected = Chunk::new();
+        expected.push_op_code(OpCode::Constant, 1);
+        expected.push_chunk(0, 1);
+        expected.push_op_code(OpCode::Print, 1);
+        expected.push_op_code(OpCode::Return, 1);
+        expected.push_constant(value);
+        expected
+    }
+
+    #[test]
+    fn fold_arithmetic() {
+        assert_eq!(
+            chunk("print 1 + 2 * 3;", OptLevel::Fold),
+            print_constant(Value::Number(7.0))
+        );
+        assert_eq!(
+            chunk("print -(2 - 5) / 2;", OptLevel::Fold),
+            print_constant(Value::Number(1.5))
+        );
+        assert_eq!(
+            chunk("print 1 + 2 * 3;", OptLevel::Fold),
+            chunk("print 7;", OptLevel::None)
+        );
+    }
+
+    #[test]
+    fn fold_comparisons() {
+        assert_eq!(
+            chunk("print 1 < 2 == !false;", OptLevel::Fold),
+            chunk("print true;", OptLevel::None)
+        );
+        assert_eq!(
+            chunk("print 3 <= 2 != (nil != false);", OptLevel::Fold),
+            chunk("print true;", OptLevel::None)
+        );
+        assert_eq!(
+            chunk("print \"a\" == \"a\";", OptLevel::Fold),
+            chunk("print true;", OptLevel::None)
+        );
+        assert_eq!(
+            chunk("print !\"\";", OptLevel::Fold),
+            chunk("print false;", OptLevel::None)
+        );
+    }
+
+    #[test]
+    fn fold_strings() {
+        assert_eq!(
+            chunk("print \"a\" + \"b\";", OptLevel::Fold),
+            print_constant(Value::string("ab"))
+        );
+        assert_eq!(
+            chunk(
+                r#"print "x ${1 + 2} y ${"${true}"}${nil}";"#,
+                OptLevel::Fold
+            ),
+            print_constant(Value::string("x 3 y truenil"))
+        );
+    }
+
+    #[test]
+    fn fold_with_ieee_semantics() {
+        assert_eq!(
+            chunk("print 1 / 0;", OptLevel::Fold),
+            print_constant(Value::Number(f64::INFINITY))
+        );
+
+        let negative_zero = chunk("print -0;", OptLevel::Fold);
+        let zero = negative_zero.get_constant(0).and_then(Value::as_number);
+        assert!(zero.is_some_and(|zero| zero == 0.0 && zero.is_sign_negative()));
+
+        let nan = chunk("print 0 / 0;", OptLevel::Fold);
+        assert!(nan
+            .get_constant(0)
+            .and_then(Value::as_number)
+            .is_some_and(f64::is_nan));
+
+        // NaN is unordered and unequal to itself, so none of these hold.
+        for comparison in ["==", "<", "<=", ">", ">="] {
+            let source = format!("print 0 / 0 {} 0 / 0;", comparison);
+            assert_eq!(
+                chunk(&source, OptLevel::Fold),
+                chunk("print false;", OptLevel::None),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn leave_runtime_errors_to_runtime() {
+        for source in [
+            "print -\"a\";",
+            "print 1 + \"a\";",
+            "print \"a\" < \"b\";",
+            "print nil * 2;",
+        ] {
+            assert_eq!(
+                chunk(source, OptLevel::Fold),
+                chunk(source, OptLevel::None),
+                "{}",
+                source
+            );
+        }
+
+        // The operations around the failing one are still folded.
+        assert_eq!(
+            chunk("print -\"a\" + (1 + 2);", OptLevel::Fold),
+            chunk("print -\"a\" + 3;", OptLevel::None)
+        );
+    }
+
+    #[test]
+    fn no_folding_at_level_none() {
+        let mut expected = Chunk::new();
+        expected.push_op_code(OpCode::Constant, 1);
+        expected.push_chunk(0, 1);
+        expected.push_op_code(OpCode::Constant, 1);
+        expected.push_chunk(1, 1);
+        expected.push_op_code(OpCode::Add, 1);
+        expected.push_op_code(OpCode::Pop, 1);
+        expected.push_op_code(OpCode::Return, 1);
+        expected.push_constant(Value::Number(1.0));
+        expected.push_constant(Value::Number(2.0));
+
+        assert_eq!(chunk("1 + 2;", OptLevel::None), expected);
+    }
+
+    #[test]
+    fn folding_frees_constants() {
+        let source = format!("print 0{};", " + 1".repeat(300));
+        assert_eq!(
+            chunk(&source, OptLevel::Fold),
+            print_constant(Value::Number(300.0))
+        );
+    }
+}
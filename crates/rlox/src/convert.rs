@@ -0,0 +1,344 @@
+use crate::error_code::ErrorCode;
+use crate::lox_error::LoxError;
+use crate::object::Obj;
+use crate::value::Value;
+use std::collections::HashMap;
+
+/// Rust values that become Lox values, what native functions return and what `Vm::call` passes
+/// them.
+pub trait IntoLox {
+    fn into_lox(self) -> Value;
+}
+
+/// Rust values read back from Lox values, the arguments of native functions and what
+/// `Vm::call` returns. A value of another type is a `TypeMismatch` error.
+pub trait FromLox: Sized {
+    fn from_lox(value: &Value) -> Result<Self, LoxError>;
+}
+
+impl IntoLox for Value {
+    fn into_lox(self) -> Value {
+        self
+    }
+}
+
+impl FromLox for Value {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        Ok(value.clone())
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        value.as_number().ok_or_else(|| mismatch("a number", value))
+    }
+}
+
+/// Integers are Lox numbers, the ones past 2^53 are rounded to the nearest one a double holds.
+/// Reading one back fails on a fraction or a number out of the range of the type.
+macro_rules! integers {
+    ($($integer:ty),*) => {$(
+        impl IntoLox for $integer {
+            fn into_lox(self) -> Value {
+                Value::Number(self as f64)
+            }
+        }
+
+        impl FromLox for $integer {
+            fn from_lox(value: &Value) -> Result<Self, LoxError> {
+                let number = value.as_number().ok_or_else(|| mismatch("an integer", value))?;
+                // `MAX + 1` is a power of two a double holds exactly, unlike `MAX` itself.
+                let (min, max) = (<$integer>::MIN, <$integer>::MAX);
+                if number.fract() == 0.0 && number >= min as f64 && number < max as f64 + 1.0 {
+                    return Ok(number as $integer);
+                }
+                let message = format!(
+                    "Expected an integer from {} to {} but got {}.",
+                    min, max, value
+                );
+                Err(LoxError::runtime(ErrorCode::TypeMismatch, message))
+            }
+        }
+    )*};
+}
+
+integers!(i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+
+impl IntoLox for bool {
+    fn into_lox(self) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        match value {
+            Value::Bool(boolean) => Ok(*boolean),
+            _ => Err(mismatch("a boolean", value)),
+        }
+    }
+}
+
+/// `nil`, for natives that return nothing.
+impl IntoLox for () {
+    fn into_lox(self) -> Value {
+        Value::Nil
+    }
+}
+
+impl FromLox for () {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        match value {
+            Value::Nil => Ok(()),
+            _ => Err(mismatch("nil", value)),
+        }
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self) -> Value {
+        Value::string(self)
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self) -> Value {
+        Value::string(self)
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        let string = value.as_str().ok_or_else(|| mismatch("a string", value))?;
+        Ok(string.to_string())
+    }
+}
+
+/// `None` is `nil`.
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self) -> Value {
+        self.map_or(Value::Nil, T::into_lox)
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        match value {
+            Value::Nil => Ok(None),
+            _ => T::from_lox(value).map(Some),
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Vec<T> {
+    fn into_lox(self) -> Value {
+        Value::list(self.into_iter().map(T::into_lox).collect())
+    }
+}
+
+impl<T: FromLox> FromLox for Vec<T> {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        let values = value.as_list().ok_or_else(|| mismatch("a list", value))?;
+        values
+            .iter()
+            .enumerate()
+            .map(|(index, element)| {
+                T::from_lox(element)
+                    .map_err(|error| noted(error, format!("in element {} of the list", index)))
+            })
+            .collect()
+    }
+}
+
+impl<T: IntoLox> IntoLox for HashMap<String, T> {
+    fn into_lox(self) -> Value {
+        let entries = self.into_iter().map(|(key, value)| (key, value.into_lox()));
+        Value::map(entries.collect())
+    }
+}
+
+impl<T: FromLox> FromLox for HashMap<String, T> {
+    fn from_lox(value: &Value) -> Result<Self, LoxError> {
+        let entries = value.as_map().ok_or_else(|| mismatch("a map", value))?;
+        entries
+            .iter()
+            .map(|(key, entry)| {
+                let entry = T::from_lox(entry)
+                    .map_err(|error| noted(error, format!("in the value of \"{}\"", key)))?;
+                Ok((key.clone(), entry))
+            })
+            .collect()
+    }
+}
+
+/// The arguments `Vm::call` passes: a tuple of Rust values that each become one, `()` for none,
+/// or a `Vec` of them when how many is only known at runtime.
+pub trait IntoArgs {
+    fn into_args(self) -> Vec<Value>;
+}
+
+impl<T: IntoLox> IntoArgs for Vec<T> {
+    fn into_args(self) -> Vec<Value> {
+        self.into_iter().map(T::into_lox).collect()
+    }
+}
+
+macro_rules! tuples {
+    ($(($($arg:ident $index:tt),*)),*) => {$(
+        impl<$($arg: IntoLox),*> IntoArgs for ($($arg,)*) {
+            fn into_args(self) -> Vec<Value> {
+                vec![$(self.$index.into_lox()),*]
+            }
+        }
+    )*};
+}
+
+tuples!(
+    (),
+    (A 0),
+    (A 0, B 1),
+    (A 0, B 1, C 2),
+    (A 0, B 1, C 2, D 3),
+    (A 0, B 1, C 2, D 3, E 4),
+    (A 0, B 1, C 2, D 3, E 4, F 5),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6),
+    (A 0, B 1, C 2, D 3, E 4, F 5, G 6, H 7)
+);
+
+/// The error for `value` where `expected` was wanted.
+fn mismatch(expected: &str, value: &Value) -> LoxError {
+    let message = format!("Expected {} but got {}.", expected, describe(value));
+    LoxError::runtime(ErrorCode::TypeMismatch, message)
+}
+
+/// The type of `value`, as a mismatch names it.
+fn describe(value: &Value) -> &'static str {
+    match value {
+        Value::Nil => "nil",
+        Value::Bool(_) => "a boolean",
+        Value::Number(_) => "a number",
+        Value::Obj(obj) => match obj.as_ref() {
+            Obj::String(_) => "a string",
+            Obj::Native(_) => "a function",
+            Obj::List(_) => "a list",
+            Obj::Map(_) => "a map",
+        },
+    }
+}
+
+/// Says where in a list or a map the value that failed to convert is, the innermost place
+/// first.
+fn noted(error: LoxError, note: String) -> LoxError {
+    match error {
+        LoxError::Runtime(diagnostic) => LoxError::Runtime(diagnostic.with_note(note)),
+        error => error,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FromLox, IntoLox};
+    use crate::error_code::ErrorCode;
+    use crate::value::Value;
+    use std::collections::HashMap;
+    use std::fmt::Debug;
+
+    fn round_trip<T: IntoLox + FromLox + Clone + PartialEq + Debug>(value: T) {
+        let lox = value.clone().into_lox();
+        assert_eq!(T::from_lox(&lox).expect("Converts back"), value);
+    }
+
+    /// The message and the notes of the error converting `value` to `T`.
+    fn mismatch<T: FromLox + Debug>(value: impl IntoLox) -> (String, Vec<String>) {
+        let error = T::from_lox(&value.into_lox()).expect_err("Mismatch");
+        assert_eq!(error.code(), Some(ErrorCode::TypeMismatch));
+        let diagnostic = &error.diagnostics()[0];
+        (diagnostic.message.clone(), diagnostic.notes.clone())
+    }
+
+    #[test]
+    fn convert_back_and_forth() {
+        round_trip(1.5);
+        round_trip(-3i32);
+        round_trip(u8::MAX);
+        round_trip(i64::MIN);
+        round_trip(true);
+        round_trip(());
+        round_trip("lox".to_string());
+        round_trip(Some(2usize));
+        round_trip(None::<String>);
+        round_trip(vec![vec![1.0], vec![], vec![2.0, 3.0]]);
+        round_trip(HashMap::from([
+            ("a".to_string(), Some(1)),
+            ("b".to_string(), None),
+        ]));
+        round_trip(Value::string("as is"));
+
+        assert_eq!("lox".into_lox(), Value::string("lox"));
+        assert_eq!(Some(7u16).into_lox(), Value::Number(7.0));
+        assert_eq!(None::<bool>.into_lox(), Value::Nil);
+    }
+
+    #[test]
+    fn show_lists_and_maps() {
+        let list = vec![Value::string("a, b"), Value::Nil, vec![1, 2].into_lox()];
+        assert_eq!(list.into_lox().to_string(), "[\"a, b\", nil, [1, 2]]");
+        let map = HashMap::from([("y".to_string(), 2), ("x".to_string(), 1)]);
+        assert_eq!(map.into_lox().to_string(), "{\"x\": 1, \"y\": 2}");
+        assert_eq!(Vec::<bool>::new().into_lox().to_string(), "[]");
+    }
+
+    #[test]
+    fn report_mismatches() {
+        let message = |text: &str| (text.to_string(), Vec::<String>::new());
+        assert_eq!(
+            mismatch::<f64>("1"),
+            message("Expected a number but got a string.")
+        );
+        assert_eq!(
+            mismatch::<String>(()),
+            message("Expected a string but got nil.")
+        );
+        assert_eq!(
+            mismatch::<()>(false),
+            message("Expected nil but got a boolean.")
+        );
+        assert_eq!(
+            mismatch::<bool>(vec![true]),
+            message("Expected a boolean but got a list.")
+        );
+        assert_eq!(
+            mismatch::<Option<f64>>(true),
+            message("Expected a number but got a boolean.")
+        );
+        assert_eq!(
+            mismatch::<u8>(256),
+            message("Expected an integer from 0 to 255 but got 256.")
+        );
+        assert_eq!(
+            mismatch::<i32>(0.5),
+            message("Expected an integer from -2147483648 to 2147483647 but got 0.5.")
+        );
+        // 2^63 is one past `i64::MAX`, which a double can't hold.
+        assert!(i64::from_lox(&2f64.powi(63).into_lox()).is_err());
+        assert!(u32::from_lox(&f64::NAN.into_lox()).is_err());
+
+        let inner = HashMap::from([("k".to_string(), "v")]);
+        assert_eq!(
+            mismatch::<Vec<HashMap<String, f64>>>(vec![HashMap::new(), inner]),
+            (
+                "Expected a number but got a string.".to_string(),
+                vec![
+                    "in the value of \"k\"".to_string(),
+                    "in element 1 of the list".to_string()
+                ]
+            )
+        );
+    }
+}
@@ -0,0 +1,421 @@
+use crate::error_code::ErrorCode;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Severity {
+    Error,
+}
+
+/// Byte range of the source a diagnostic points at, `line` is the line `start` is on.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize, line: usize) -> Self {
+        Span { start, end, line }
+    }
+
+    /// From the start of this span to the end of `end`.
+    pub fn to(self, end: Span) -> Self {
+        Span::new(self.start, end.end.max(self.start), self.line)
+    }
+}
+
+/// A span that knows nothing but its line, for code built by hand.
+impl From<usize> for Span {
+    fn from(line: usize) -> Self {
+        Span::new(0, 0, line)
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// Set on every error, `rlox explain` describes it.
+    pub code: Option<ErrorCode>,
+    pub span: Span,
+    /// The token the parser was at, `'x'` or `end`, named by the one line form.
+    pub location: Option<Box<str>>,
+    pub message: String,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(code: ErrorCode, span: Span, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            code: Some(code),
+            span,
+            location: None,
+            message: message.into(),
+            notes: Vec::new(),
+            help: None,
+        }
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.severity == Severity::Error
+    }
+
+    pub fn with_location(mut self, location: impl Into<Box<str>>) -> Self {
+        self.location = Some(location.into());
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help = Some(help.into());
+        self
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+        }
+    }
+}
+
+/// The one line form: `[line 3] Error[E0009] at ';': Expect expression.`, notes and help follow
+/// on their own lines. Errors outside of any script, on line 0, leave the line out.
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.span.line > 0 {
+            write!(f, "[line {}] ", self.span.line)?;
+        }
+        write!(f, "{}", self.severity)?;
+        if let Some(code) = self.code {
+            write!(f, "[{}]", code)?;
+        }
+        if let Some(location) = &self.location {
+            write!(f, " at {}", location)?;
+        }
+        write!(f, ": {}", self.message)?;
+        for note in &self.notes {
+            write!(f, "\n  note: {}", note)?;
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n  help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[1;34m";
+
+/// Spans covering more lines than this only show the first and last of them.
+const MAX_SNIPPET_LINES: usize = 4;
+
+const TAB_WIDTH: usize = 4;
+
+/// Renders diagnostics the long way, with the source they point at:
+///
+/// ```text
+/// error[E0009]: Expect expression.
+///  --> script.lox:1:10
+///   |
+/// 1 | print 1 +;
+///   |          ^
+/// ```
+pub struct Renderer<'a> {
+    name: &'a str,
+    source: &'a str,
+    colour: bool,
+}
+
+/// One source line of a snippet, with the part of the span on it in display columns.
+struct SnippetLine<'a> {
+    number: usize,
+    text: &'a str,
+    underline: (usize, usize),
+}
+
+impl<'a> Renderer<'a> {
+    /// A plain renderer for `source`, `name` is shown as the location of every diagnostic.
+    pub fn new(name: &'a str, source: &'a str) -> Self {
+        Renderer {
+            name,
+            source,
+            colour: false,
+        }
+    }
+
+    /// Highlights the output with ANSI colours.
+    pub fn colour(mut self, colour: bool) -> Self {
+        self.colour = colour;
+        self
+    }
+
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        let severity_style = match diagnostic.severity {
+            Severity::Error => RED,
+        };
+        let mut severity = diagnostic.severity.to_string().to_lowercase();
+        if let Some(code) = diagnostic.code {
+            severity += &format!("[{}]", code);
+        }
+
+        let span = self.clamp(diagnostic.span);
+        let line_start = self.line_start(span.start);
+        let column = self.source[line_start..span.start].chars().count() + 1;
+
+        let lines = self.snippet(span, diagnostic.span.line);
+        let gutter = lines.last().map_or(1, |line| line.number.to_string().len());
+        let blank = " ".repeat(gutter);
+        let bar = self.paint(BLUE, "|");
+
+        let mut out = format!(
+            "{}{}\n",
+            self.paint(severity_style, &severity),
+            self.paint(BOLD, &format!(": {}", diagnostic.message))
+        );
+        out += &format!(
+            "{}{} {}:{}:{}\n",
+            blank,
+            self.paint(BLUE, "-->"),
+            self.name,
+            diagnostic.span.line,
+            column
+        );
+        out += &format!("{} {}\n", blank, bar);
+
+        let mut previous = None;
+        for line in &lines {
+            if previous.is_some_and(|number| number + 1 != line.number) {
+                out += &format!("{}\n", self.paint(BLUE, "..."));
+            }
+            previous = Some(line.number);
+
+            let number = format!("{:>width$}", line.number, width = gutter);
+            out += &format!(
+                "{} {} {}\n",
+                self.paint(BLUE, &number),
+                bar,
+                expand_tabs(line.text).trim_end()
+            );
+
+            let (from, to) = line.underline;
+            let carets = self.paint(severity_style, &"^".repeat(to - from));
+            out += &format!("{} {} {}{}\n", blank, bar, " ".repeat(from), carets);
+        }
+
+        for note in &diagnostic.notes {
+            out += &format!("{} {} note: {}\n", blank, self.paint(BLUE, "="), note);
+        }
+        if let Some(help) = &diagnostic.help {
+            out += &format!("{} {} help: {}\n", blank, self.paint(BLUE, "="), help);
+        }
+        out
+    }
+
+    /// Keeps spans from the scanner's end of input inside the source.
+    fn clamp(&self, span: Span) -> Span {
+        let mut start = span.start.min(self.source.len());
+        while !self.source.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = span.end.clamp(start, self.source.len());
+        while !self.source.is_char_boundary(end) {
+            end += 1;
+        }
+        Span::new(start, end, span.line)
+    }
+
+    fn line_start(&self, offset: usize) -> usize {
+        self.source[..offset]
+            .rfind('\n')
+            .map_or(0, |newline| newline + 1)
+    }
+
+    fn snippet(&self, span: Span, first_line: usize) -> Vec<SnippetLine<'a>> {
+        let mut lines = Vec::new();
+        let mut start = self.line_start(span.start);
+        let mut number = first_line;
+
+        loop {
+            let end = self.source[start..]
+                .find('\n')
+                .map_or(self.source.len(), |newline| start + newline);
+            let text = &self.source[start..end];
+            let from = width(&self.source[start..span.start.max(start)]);
+            let to = width(&self.source[start..span.end.min(end)]);
+            // Empty spans, like the end of input, still get a caret.
+            lines.push(SnippetLine {
+                number,
+                text,
+                underline: (from, to.max(from + 1)),
+            });
+
+            if span.end <= end + 1 || end == self.source.len() {
+                break;
+            }
+            start = end + 1;
+            number += 1;
+        }
+
+        if lines.len() > MAX_SNIPPET_LINES {
+            lines.drain(1..lines.len() - 1);
+        }
+        lines
+    }
+
+    fn paint(&self, style: &str, text: &str) -> String {
+        if self.colour {
+            format!("{}{}{}", style, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+fn expand_tabs(text: &str) -> String {
+    text.replace('\t', &" ".repeat(TAB_WIDTH))
+}
+
+/// Display columns taken by `text` once tabs are expanded.
+fn width(text: &str) -> usize {
+    text.chars()
+        .map(|c| if c == '\t' { TAB_WIDTH } else { 1 })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Diagnostic, Renderer, Span};
+    use crate::error_code::ErrorCode;
+
+    fn render(source: &str, diagnostic: Diagnostic) -> String {
+        Renderer::new("test.lox", source).render(&diagnostic)
+    }
+
+    #[test]
+    fn render_single_line() {
+        let diagnostic = Diagnostic::error(
+            ErrorCode::ExpectExpression,
+            Span::new(9, 10, 1),
+            "Expect expression.",
+        );
+
+        assert_eq!(
+            render("print 1 +;\nprint 2;", diagnostic),
+            "error[E0009]: Expect expression.\n \
+             --> test.lox:1:10\n  \
+              |\n\
+             1 | print 1 +;\n  \
+              |          ^\n"
+        );
+    }
+
+    #[test]
+    fn render_notes_and_help() {
+        let diagnostic = Diagnostic::error(
+            ErrorCode::UnclosedParen,
+            Span::new(12, 13, 1),
+            "Expect ')' after expression.",
+        )
+        .with_note("The '(' to match is on line 1.")
+        .with_help("Add ')' before ';'.");
+
+        assert_eq!(
+            render("print (1 + 2;", diagnostic),
+            "error[E0010]: Expect ')' after expression.\n \
+             --> test.lox:1:13\n  \
+              |\n\
+             1 | print (1 + 2;\n  \
+              |             ^\n  \
+              = note: The '(' to match is on line 1.\n  \
+              = help: Add ')' before ';'.\n"
+        );
+    }
+
+    #[test]
+    fn render_end_of_input() {
+        let source = "print 1\n";
+        let diagnostic = Diagnostic::error(
+            ErrorCode::ExpectSemicolon,
+            Span::new(8, 8, 2),
+            "Expect ';' after value.",
+        );
+
+        assert_eq!(
+            render(source, diagnostic),
+            "error[E0011]: Expect ';' after value.\n \
+             --> test.lox:2:1\n  \
+              |\n\
+             2 | \n  \
+              | ^\n"
+        );
+    }
+
+    #[test]
+    fn render_unicode_and_tabs() {
+        let source = "\t\"\u{e9}t\u{e9} \\q\";";
+        let start = source.find('\\').unwrap();
+        let diagnostic = Diagnostic::error(
+            ErrorCode::InvalidEscape,
+            Span::new(start, start + 2, 1),
+            "Invalid escape sequence '\\q'.",
+        );
+
+        assert_eq!(
+            render(source, diagnostic),
+            "error[E0004]: Invalid escape sequence '\\q'.\n \
+             --> test.lox:1:7\n  \
+              |\n\
+             1 |     \"\u{e9}t\u{e9} \\q\";\n  \
+              |          ^^\n"
+        );
+    }
+
+    #[test]
+    fn render_multiple_lines() {
+        let source = "print \"a\nb\nc\nd\ne";
+        let diagnostic = Diagnostic::error(
+            ErrorCode::UnterminatedString,
+            Span::new(6, source.len(), 1),
+            "Unterminated string.",
+        );
+
+        assert_eq!(
+            render(source, diagnostic),
+            "error[E0002]: Unterminated string.\n \
+             --> test.lox:1:7\n  \
+              |\n\
+             1 | print \"a\n  \
+              |       ^^\n\
+             ...\n\
+             5 | e\n  \
+              | ^\n"
+        );
+    }
+
+    #[test]
+    fn render_colour() {
+        let diagnostic = Diagnostic::error(
+            ErrorCode::UnexpectedCharacter,
+            Span::new(0, 1, 1),
+            "Unexpected character '#'.",
+        );
+        let rendered = Renderer::new("test.lox", "#")
+            .colour(true)
+            .render(&diagnostic);
+
+        assert!(rendered.starts_with(
+            "\x1b[1;31merror[E0001]\x1b[0m\x1b[1m: Unexpected character '#'.\x1b[0m\n"
+        ));
+        assert!(rendered.contains("\x1b[1;31m^\x1b[0m"));
+    }
+}
@@ -0,0 +1,365 @@
+use crate::chunk::Chunk;
+use crate::compiler::{self, OptLevel};
+use crate::convert::{FromLox, IntoArgs, IntoLox};
+use crate::error_code::ErrorCode;
+use crate::lox_error::{self, LoxError};
+use crate::native::Native;
+use crate::object::Obj;
+use crate::value::Value;
+use crate::vm::VirtualMachine;
+use crate::InterpretResult;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::Instant;
+
+/// A Lox interpreter for a Rust program to run scripts on. Scripts run one after the other on
+/// the same globals, which the program can read and write in between.
+///
+/// ```
+/// let mut vm = rlox::Vm::new();
+/// vm.eval("var answer = 6 * 7;")?;
+/// assert_eq!(vm.get_global("answer"), Some(rlox::Value::Number(42.0)));
+/// # Ok::<(), rlox::LoxError>(())
+/// ```
+pub struct Vm {
+    machine: VirtualMachine,
+}
+
+impl Vm {
+    /// A virtual machine that prints to standard output.
+    pub fn new() -> Self {
+        Vm::with_output(io::stdout())
+    }
+
+    /// A virtual machine where `print` writes to `output`. Scripts can call `clock()`, the
+    /// seconds since the virtual machine was made, to time themselves.
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        let mut vm = Vm::from_machine(VirtualMachine::with_output(output));
+        let start = Instant::now();
+        vm.define_native("clock", 0, move |_, _| Ok(start.elapsed().as_secs_f64()));
+        vm
+    }
+
+    /// Wraps a machine as is, for a native function to run scripts on the machine calling it.
+    pub(crate) fn from_machine(machine: VirtualMachine) -> Self {
+        Vm { machine }
+    }
+
+    pub(crate) fn into_machine(self) -> VirtualMachine {
+        self.machine
+    }
+
+    /// Compiles scripts at `level` from now on, `OptLevel::Fuse` by default.
+    pub fn opt_level(mut self, level: OptLevel) -> Self {
+        self.machine.level = level;
+        self
+    }
+
+    /// Compiles and runs `source`. Globals it defines stay for the scripts after it.
+    pub fn eval(&mut self, source: &str) -> Result<(), LoxError> {
+        let chunk = self.compile(source)?;
+        self.run(chunk)
+    }
+
+    /// Reads the script at `path` and runs it like `eval`.
+    pub fn eval_file(&mut self, path: impl AsRef<Path>) -> Result<(), LoxError> {
+        let path = path.as_ref();
+        let source = fs::read_to_string(path).map_err(|error| {
+            io::Error::new(
+                error.kind(),
+                format!("Could not open file '{}': {}", path.display(), error),
+            )
+        })?;
+        self.eval(&source)
+    }
+
+    /// Compiles `source` without running it.
+    pub fn compile(&self, source: &str) -> Result<Chunk, LoxError> {
+        let mut chunk = Chunk::new();
+        lox_error::check(compiler::compile(source, &mut chunk, self.machine.level))?;
+        Ok(chunk)
+    }
+
+    /// Runs code that was compiled already, by `compile` or into a bundle. Code that fails
+    /// `Chunk::verify` is an `InvalidBytecode` error and doesn't run.
+    pub fn run(&mut self, chunk: Chunk) -> Result<(), LoxError> {
+        if let Err(message) = chunk.verify() {
+            let message = format!("Invalid bytecode at {}.", message);
+            return Err(LoxError::runtime(ErrorCode::InvalidBytecode, message));
+        }
+        if cfg!(feature = "debug_print_code") {
+            chunk.disassemble("code");
+        }
+        self.machine.chunks = chunk;
+        match self.machine.run() {
+            InterpretResult::RuntimeError => {
+                let error = self.machine.take_error();
+                Err(LoxError::Runtime(
+                    error.expect("Runtime errors of verified code are reported"),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Calls the function in the global `name` with `args`, returns what it returns as a `T`.
+    pub fn call<T: FromLox>(&mut self, name: &str, args: impl IntoArgs) -> Result<T, LoxError> {
+        let args = args.into_args();
+        let Some(callee) = self.get_global(name) else {
+            let message = format!("Undefined variable '{}'.", name);
+            return Err(LoxError::runtime(ErrorCode::UndefinedVariable, message));
+        };
+        let Some(Obj::Native(native)) = callee.as_obj() else {
+            return Err(LoxError::runtime(
+                ErrorCode::NotCallable,
+                "Can only call functions.",
+            ));
+        };
+        if let Some(message) = native.arity_mismatch(args.len()) {
+            return Err(LoxError::runtime(ErrorCode::ArityMismatch, message));
+        }
+        T::from_lox(&native.call(self, &args)?)
+    }
+
+    /// Defines the global `name` as a function scripts call with `arity` arguments, which runs
+    /// `function`. It reads the arguments with `FromLox::from_lox` and returns any `IntoLox`
+    /// value. Returning `Err` stops the script, `LoxError::native` makes the error.
+    pub fn define_native<T: IntoLox>(
+        &mut self,
+        name: &str,
+        arity: usize,
+        function: impl Fn(&mut Vm, &[Value]) -> Result<T, LoxError> + 'static,
+    ) {
+        let function = move |vm: &mut Vm, args: &[Value]| function(vm, args).map(T::into_lox);
+        let native = Native::new(name, arity, Box::new(function));
+        self.set_global(name, Value::Obj(Rc::new(Obj::Native(native))));
+    }
+
+    /// The value of the global `name`, if a script or `set_global` defined it.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.machine.globals.get(name).cloned()
+    }
+
+    /// Defines the global `name` for scripts run after, or assigns it if it is defined.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        self.machine.globals.insert(name.to_string(), value);
+    }
+
+    /// The instruction pairs counted over every run so far, the most frequent first.
+    #[cfg(feature = "opcode_pairs")]
+    pub fn pair_report(&self) -> String {
+        self.machine.pair_report()
+    }
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Vm::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vm;
+    use crate::chunk::{Chunk, OpCode};
+    use crate::compiler::OptLevel;
+    use crate::convert::FromLox;
+    use crate::diagnostic::Span;
+    use crate::error_code::ErrorCode;
+    use crate::lox_error::LoxError;
+    use crate::optimizer::tests::Output;
+    use crate::value::Value;
+    use std::collections::HashMap;
+    use std::{env, fs, process};
+
+    #[test]
+    fn eval_keeps_globals() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.set_global("greeting", Value::string("hello"));
+        vm.eval("var n = 1; print greeting;").expect("Runs");
+        vm.eval("n = n + 1;").expect("Runs");
+        assert_eq!(vm.get_global("n"), Some(Value::Number(2.0)));
+        assert_eq!(vm.get_global("missing"), None);
+        assert_eq!(output.0.take(), b"hello\n");
+    }
+
+    #[test]
+    fn compile_then_run() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(output.clone()).opt_level(OptLevel::None);
+        let chunk = vm.compile("print 1 + 2;").expect("Compiles");
+        assert!(output.0.borrow().is_empty());
+        vm.run(chunk).expect("Runs");
+        assert_eq!(output.0.take(), b"3\n");
+
+        let mut chunk = Chunk::new();
+        chunk.push_op_code(OpCode::Print, Span::new(0, 0, 1));
+        chunk.push_op_code(OpCode::Return, Span::new(0, 0, 1));
+        let error = vm.run(chunk).expect_err("Doesn't run");
+        assert_eq!(error.code(), Some(ErrorCode::InvalidBytecode));
+        assert_eq!(
+            error.to_string(),
+            "Error[E0029]: Invalid bytecode at 0000: Print pops 1 values off 0."
+        );
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn report_errors() {
+        let mut vm = Vm::with_output(Output::default());
+        let Err(LoxError::Compile(diagnostics)) = vm.eval("print ;") else {
+            panic!("Compile error");
+        };
+        assert_eq!(diagnostics[0].code, Some(ErrorCode::ExpectExpression));
+
+        let Err(LoxError::Runtime(error)) = vm.eval("var a = 1;\nprint -\"a\";") else {
+            panic!("Runtime error");
+        };
+        assert_eq!(
+            (error.code, error.span.line),
+            (Some(ErrorCode::OperandNotNumber), 2)
+        );
+        // The error stopped the script after `a` was defined, and the stack is clean again.
+        assert_eq!(vm.get_global("a"), Some(Value::Number(1.0)));
+        vm.eval("{ var b = a; print b; }").expect("Runs");
+
+        assert!(matches!(
+            vm.eval_file("no/such/script.lox"),
+            Err(LoxError::Io(_))
+        ));
+    }
+
+    #[test]
+    fn eval_files() {
+        let path = env::temp_dir().join(format!("rlox-embed-{}.lox", process::id()));
+        fs::write(&path, "var from_file = 1 + 2;").expect("Write the script");
+        let mut vm = Vm::with_output(Output::default());
+        let result = vm.eval_file(&path);
+        fs::remove_file(&path).expect("Remove the script");
+        result.expect("Runs");
+        assert_eq!(vm.get_global("from_file"), Some(Value::Number(3.0)));
+    }
+
+    #[test]
+    fn call_globals() {
+        let mut vm = Vm::with_output(Output::default());
+        vm.eval("var name = \"lox\";").expect("Runs");
+        let code = |result: Result<Value, LoxError>| result.expect_err("Not callable").code();
+        assert_eq!(code(vm.call("name", ())), Some(ErrorCode::NotCallable));
+        assert_eq!(
+            code(vm.call("missing", ())),
+            Some(ErrorCode::UndefinedVariable)
+        );
+        assert_eq!(
+            code(vm.call("clock", (Value::Nil,))),
+            Some(ErrorCode::ArityMismatch)
+        );
+        let elapsed: f64 = vm.call("clock", ()).expect("Calls clock");
+        assert!(elapsed >= 0.0);
+    }
+
+    #[test]
+    fn call_natives() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.define_native("add", 2, |_, args| {
+            Ok(f64::from_lox(&args[0])? + f64::from_lox(&args[1])?)
+        });
+        vm.eval("{ var a = 1; print add(a, add(2, 3)) * 2; print add; }")
+            .expect("Runs");
+        assert_eq!(output.0.take(), b"12\n<native fn add>\n");
+        let sum: u8 = vm.call("add", (1, 2.0)).expect("Calls add");
+        assert_eq!(sum, 3);
+        let sum: Result<String, LoxError> = vm.call("add", vec![1, 2]);
+        assert_eq!(
+            sum.expect_err("Not a string").code(),
+            Some(ErrorCode::TypeMismatch)
+        );
+
+        let Err(LoxError::Runtime(error)) = vm.eval("print 1;\nadd(1);") else {
+            panic!("Runtime error");
+        };
+        assert_eq!(
+            (error.code, error.span.line),
+            (Some(ErrorCode::ArityMismatch), 2)
+        );
+        assert_eq!(error.message, "Expected 2 arguments but got 1.");
+        let Err(LoxError::Runtime(error)) = vm.eval("add(1, nil);") else {
+            panic!("Runtime error");
+        };
+        assert_eq!(
+            (error.code, error.span.line),
+            (Some(ErrorCode::TypeMismatch), 1)
+        );
+        assert_eq!(error.message, "Expected a number but got nil.");
+        assert_eq!(error.notes, ["in add(), called on line 1"]);
+    }
+
+    #[test]
+    fn trade_rust_values() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.define_native("range", 1, |_, args| {
+            Ok((0..u32::from_lox(&args[0])?).collect::<Vec<_>>())
+        });
+        vm.define_native("sum", 1, |_, args| {
+            Ok(Vec::<f64>::from_lox(&args[0])?.iter().sum::<f64>())
+        });
+        vm.define_native("env", 0, |_, _| {
+            let user = ("user".to_string(), Some("lox"));
+            Ok(HashMap::from([user, ("home".to_string(), None)]))
+        });
+        let Err(LoxError::Runtime(error)) =
+            vm.eval("var r = range(4);\nprint r;\nprint sum(r);\nprint env();\nsum(env());")
+        else {
+            panic!("Runtime error");
+        };
+        assert_eq!(
+            output.0.take(),
+            b"[0, 1, 2, 3]\n6\n{\"home\": nil, \"user\": \"lox\"}\n"
+        );
+        assert_eq!(
+            (error.code, error.span.line),
+            (Some(ErrorCode::TypeMismatch), 5)
+        );
+        assert_eq!(error.message, "Expected a list but got a map.");
+        let r = vm.get_global("r").expect("Defined");
+        assert_eq!(Vec::<u32>::from_lox(&r).expect("A list"), [0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn natives_run_scripts() {
+        let output = Output::default();
+        let mut vm = Vm::with_output(output.clone());
+        vm.define_native("fail", 0, |_, _| {
+            Err::<(), _>(LoxError::native("Out of paper."))
+        });
+        vm.define_native("run", 1, |vm, args| vm.eval(&String::from_lox(&args[0])?));
+        // The script a native runs shares the globals and the output, and leaves the locals of
+        // the script that called it in place.
+        vm.eval("var n = 1;\n{ var a = \"a\"; run(\"print n; n = n + 1;\"); print a + \"b\"; }")
+            .expect("Runs");
+        assert_eq!(vm.get_global("n"), Some(Value::Number(2.0)));
+        assert_eq!(output.0.take(), b"1\nab\n");
+
+        let source = "var inner = \"\nfail();\";\nvar outer = \"run(inner);\";\nrun(outer);";
+        let Err(LoxError::Runtime(error)) = vm.eval(source) else {
+            panic!("Runtime error");
+        };
+        assert_eq!(
+            (error.code, error.span.line),
+            (Some(ErrorCode::NativeFailed), 4)
+        );
+        assert_eq!(
+            error.notes,
+            [
+                "in fail(), called on line 2",
+                "in run(), called on line 1",
+                "in run(), called on line 4"
+            ]
+        );
+    }
+}
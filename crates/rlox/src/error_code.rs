@@ -0,0 +1,588 @@
+use std::fmt;
+
+/// Stable identifiers for every error the compiler and the virtual machine report. Codes are
+/// never reused or renumbered, new errors get the next free number.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ErrorCode {
+    UnexpectedCharacter,
+    UnterminatedString,
+    UnterminatedComment,
+    InvalidEscape,
+    MissingDigits,
+    MissingExponent,
+    InvalidSeparator,
+    InvalidDigit,
+    ExpectExpression,
+    UnclosedParen,
+    ExpectSemicolon,
+    UnterminatedInterpolation,
+    NumberOutOfRange,
+    TooManyConstants,
+    OperandNotNumber,
+    OperandsNotNumbers,
+    OperandsNotAddable,
+    UndefinedVariable,
+    ExpectVariableName,
+    InvalidAssignmentTarget,
+    DuplicateVariable,
+    OwnInitializer,
+    TooManyLocals,
+    UnclosedBrace,
+    ExpectParen,
+    JumpTooLarge,
+    TooManyRegisters,
+    NotCallable,
+    InvalidBytecode,
+    ArityMismatch,
+    TooManyArguments,
+    NativeFailed,
+    UnsupportedOnRegisterVm,
+    TypeMismatch,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 34] = [
+        ErrorCode::UnexpectedCharacter,
+        ErrorCode::UnterminatedString,
+        ErrorCode::UnterminatedComment,
+        ErrorCode::InvalidEscape,
+        ErrorCode::MissingDigits,
+        ErrorCode::MissingExponent,
+        ErrorCode::InvalidSeparator,
+        ErrorCode::InvalidDigit,
+        ErrorCode::ExpectExpression,
+        ErrorCode::UnclosedParen,
+        ErrorCode::ExpectSemicolon,
+        ErrorCode::UnterminatedInterpolation,
+        ErrorCode::NumberOutOfRange,
+        ErrorCode::TooManyConstants,
+        ErrorCode::OperandNotNumber,
+        ErrorCode::OperandsNotNumbers,
+        ErrorCode::OperandsNotAddable,
+        ErrorCode::UndefinedVariable,
+        ErrorCode::ExpectVariableName,
+        ErrorCode::InvalidAssignmentTarget,
+        ErrorCode::DuplicateVariable,
+        ErrorCode::OwnInitializer,
+        ErrorCode::TooManyLocals,
+        ErrorCode::UnclosedBrace,
+        ErrorCode::ExpectParen,
+        ErrorCode::JumpTooLarge,
+        ErrorCode::TooManyRegisters,
+        ErrorCode::NotCallable,
+        ErrorCode::InvalidBytecode,
+        ErrorCode::ArityMismatch,
+        ErrorCode::TooManyArguments,
+        ErrorCode::NativeFailed,
+        ErrorCode::UnsupportedOnRegisterVm,
+        ErrorCode::TypeMismatch,
+    ];
+
+    pub fn number(self) -> u16 {
+        match self {
+            ErrorCode::UnexpectedCharacter => 1,
+            ErrorCode::UnterminatedString => 2,
+            ErrorCode::UnterminatedComment => 3,
+            ErrorCode::InvalidEscape => 4,
+            ErrorCode::MissingDigits => 5,
+            ErrorCode::MissingExponent => 6,
+            ErrorCode::InvalidSeparator => 7,
+            ErrorCode::InvalidDigit => 8,
+            ErrorCode::ExpectExpression => 9,
+            ErrorCode::UnclosedParen => 10,
+            ErrorCode::ExpectSemicolon => 11,
+            ErrorCode::UnterminatedInterpolation => 12,
+            ErrorCode::NumberOutOfRange => 13,
+            ErrorCode::TooManyConstants => 14,
+            ErrorCode::OperandNotNumber => 15,
+            ErrorCode::OperandsNotNumbers => 16,
+            ErrorCode::OperandsNotAddable => 17,
+            ErrorCode::UndefinedVariable => 18,
+            ErrorCode::ExpectVariableName => 19,
+            ErrorCode::InvalidAssignmentTarget => 20,
+            ErrorCode::DuplicateVariable => 21,
+            ErrorCode::OwnInitializer => 22,
+            ErrorCode::TooManyLocals => 23,
+            ErrorCode::UnclosedBrace => 24,
+            ErrorCode::ExpectParen => 25,
+            ErrorCode::JumpTooLarge => 26,
+            ErrorCode::TooManyRegisters => 27,
+            ErrorCode::NotCallable => 28,
+            ErrorCode::InvalidBytecode => 29,
+            ErrorCode::ArityMismatch => 30,
+            ErrorCode::TooManyArguments => 31,
+            ErrorCode::NativeFailed => 32,
+            ErrorCode::UnsupportedOnRegisterVm => 33,
+            ErrorCode::TypeMismatch => 34,
+        }
+    }
+
+    /// Looks up a code as written in diagnostics, like `E0012`.
+    pub fn parse(code: &str) -> Option<ErrorCode> {
+        let number: u16 = code.strip_prefix(['E', 'e'])?.parse().ok()?;
+        ErrorCode::ALL
+            .into_iter()
+            .find(|code| code.number() == number)
+    }
+
+    /// The long form shown by `rlox explain`: what went wrong, an example of the mistake and the
+    /// example fixed.
+    pub fn explanation(self) -> &'static str {
+        match self {
+            ErrorCode::UnexpectedCharacter => {
+                "A character that cannot start any token was found.
+
+Lox source may only contain letters, digits, whitespace and the punctuation
+used by the language. Characters like `#` or `@` outside of strings and
+comments are rejected:
+
+    print 1 # 2;
+
+Put the character in a string if it is meant as text:
+
+    print \"1 # 2\";"
+            }
+            ErrorCode::UnterminatedString => {
+                "A string literal has no closing quote.
+
+Strings may span lines, so a missing `\"` swallows the rest of the file:
+
+    print \"hello;
+
+Add the closing quote where the string should end:
+
+    print \"hello\";"
+            }
+            ErrorCode::UnterminatedComment => {
+                "A block comment has no closing `*/`.
+
+Block comments nest, every `/*` needs its own `*/`:
+
+    /* outer /* inner */
+    print 1;
+
+Close each comment that was opened:
+
+    /* outer /* inner */ */
+    print 1;"
+            }
+            ErrorCode::InvalidEscape => {
+                "A backslash in a string is followed by something that is not an escape.
+
+The recognised escapes are `\\n`, `\\t`, `\\r`, `\\\"`, `\\\\`, `\\$` and `\\u{...}` with
+one to six hex digits naming a Unicode scalar value:
+
+    print \"C:\\path\";
+
+Write `\\\\` for a literal backslash:
+
+    print \"C:\\\\path\";"
+            }
+            ErrorCode::MissingDigits => {
+                "A number literal has a prefix or a separator but no digits.
+
+    print 0x;
+
+Hexadecimal, octal and binary literals need at least one digit after `0x`,
+`0o` or `0b`:
+
+    print 0x1f;"
+            }
+            ErrorCode::MissingExponent => {
+                "A number literal has an exponent marker but no exponent.
+
+    print 1e+;
+
+Follow `e` or `E`, and the optional sign, with at least one digit:
+
+    print 1e+3;"
+            }
+            ErrorCode::InvalidSeparator => {
+                "A `_` digit separator is not between two digits.
+
+    print 1__000;
+    print 1_;
+
+Separators may only appear between digits, one at a time:
+
+    print 1_000;
+    print 1;"
+            }
+            ErrorCode::InvalidDigit => {
+                "A number literal contains a digit its base does not have.
+
+    print 0b102;
+    print 0o9;
+
+Binary literals only take `0` and `1`, octal literals `0` to `7`. Use digits
+of the base, or write the number in another base:
+
+    print 0b110;
+    print 9;"
+            }
+            ErrorCode::ExpectExpression => {
+                "An expression was expected but the code continues with something else.
+
+    print 1 +;
+    print;
+
+Complete the expression, or remove the operator that expects it:
+
+    print 1 + 2;
+    print 1;"
+            }
+            ErrorCode::UnclosedParen => {
+                "A parenthesised expression is not closed.
+
+    print (1 + 2;
+
+The note points at the line of the `(` that needs a matching `)`:
+
+    print (1 + 2);"
+            }
+            ErrorCode::ExpectSemicolon => {
+                "A statement does not end with `;`.
+
+    print 1
+    print 2;
+
+Every expression and `print` statement ends with a semicolon. When the next
+statement starts on a later line the help points at where the `;` belongs:
+
+    print 1;
+    print 2;"
+            }
+            ErrorCode::UnterminatedInterpolation => {
+                "A `${` in a string is not closed by `}`.
+
+    print \"total: ${1 + 2\";
+
+Close the interpolated expression with `}` before the rest of the string:
+
+    print \"total: ${1 + 2}\";"
+            }
+            ErrorCode::NumberOutOfRange => {
+                "A number literal is too large to be represented.
+
+    print 1e400;
+
+Numbers are 64-bit floating point values, the largest finite one is about
+1.8e308. Scale the value down to fit:
+
+    print 1e300;"
+            }
+            ErrorCode::TooManyConstants => {
+                "A chunk of bytecode refers to more than 256 constants.
+
+Each number and string literal takes a constant slot, so a script with more
+than 256 of them does not compile:
+
+    print 1; print 2; print 3; // ... up to print 257;
+
+Split very long scripts into smaller pieces, each with fewer literals:
+
+    print 1; print 2; print 3; // ... up to print 128;"
+            }
+            ErrorCode::OperandNotNumber => {
+                "A unary operator was applied to a value of the wrong type.
+
+    print -\"text\";
+
+Negation only works on numbers:
+
+    print -3;"
+            }
+            ErrorCode::OperandsNotNumbers => {
+                "An arithmetic or comparison operator was applied to a value that is not a number.
+
+    print 2 * \"text\";
+    print \"a\" < \"b\";
+
+`-`, `*`, `/`, `<`, `<=`, `>` and `>=` only work on numbers:
+
+    print 2 * 3;
+    print 1 < 2;"
+            }
+            ErrorCode::OperandsNotAddable => {
+                "`+` was applied to values it cannot combine.
+
+    print 1 + \"text\";
+
+`+` adds two numbers or concatenates two strings. Use an interpolated string
+to combine other values:
+
+    print \"${1}text\";"
+            }
+            ErrorCode::UndefinedVariable => {
+                "A global variable was used before it was declared.
+
+    print count;
+    var count = 1;
+
+Global variables exist from the moment their `var` declaration runs, assigning
+to one does not declare it either. Declare the variable first:
+
+    var count = 1;
+    print count;"
+            }
+            ErrorCode::ExpectVariableName => {
+                "A `var` declaration has no name.
+
+    var = 1;
+
+Write the name of the variable after `var`:
+
+    var a = 1;"
+            }
+            ErrorCode::InvalidAssignmentTarget => {
+                "The left side of `=` is not a variable.
+
+    1 + a = 2;
+
+Only variables can be assigned to. Use `==` to compare values:
+
+    a = 2;
+    print 1 + a == 2;"
+            }
+            ErrorCode::DuplicateVariable => {
+                "A block declares two local variables with the same name.
+
+    {
+      var a = 1;
+      var a = 2;
+    }
+
+Give the second variable another name, or assign to the first one. Global
+variables may be declared again:
+
+    {
+      var a = 1;
+      a = 2;
+    }"
+            }
+            ErrorCode::OwnInitializer => {
+                "A local variable is used in its own initializer.
+
+    var a = 1;
+    {
+      var a = a + 1;
+    }
+
+The new `a` does not have a value yet while its initializer runs. Rename it to
+use the outer variable:
+
+    var a = 1;
+    {
+      var b = a + 1;
+    }"
+            }
+            ErrorCode::TooManyLocals => {
+                "More than 256 local variables are in scope at once.
+
+    {
+      var a0 = 0; var a1 = 1; // ... up to var a256 = 256;
+    }
+
+Move some of the variables into nested blocks, they are dropped at the end of
+their block:
+
+    {
+      {
+        var a0 = 0; // ... up to var a127 = 127;
+      }
+      {
+        var a128 = 128; // ... up to var a256 = 256;
+      }
+    }"
+            }
+            ErrorCode::UnclosedBrace => {
+                "A block is not closed.
+
+    while (a) {
+      print a;
+
+The note points at the line of the `{` that needs a matching `}`:
+
+    while (a) {
+      print a;
+    }"
+            }
+            ErrorCode::ExpectParen => {
+                "The condition of an `if` or a `while` is not in parentheses.
+
+    if a > 1 print a;
+
+Put the condition in parentheses:
+
+    if (a > 1) print a;"
+            }
+            ErrorCode::JumpTooLarge => {
+                "A branch or a loop body is too long to jump over.
+
+    if (a) {
+      print 1; // ... over 65535 bytes of code
+    }
+
+Jumps reach 65535 bytes of bytecode. Split the body into smaller pieces, like
+two `if` statements that test the same condition:
+
+    if (a) {
+      print 1; // ... the first half of the code
+    }
+    if (a) {
+      print 2; // ... the other half
+    }"
+            }
+            ErrorCode::TooManyRegisters => {
+                "Code compiled for the register machine needs more than 256 registers.
+
+    print 1 + (1 + (1 + ... (1 + 1) ... ));  // 256 levels deep
+
+Every local variable in scope takes a register, and so does each intermediate
+result of the expression being evaluated. Move variables into nested blocks or
+split deeply nested expressions into variables:
+
+    var a = 1 + (1 + ... (1 + 1) ... );  // 128 levels deep
+    print 1 + (1 + ... (1 + a) ... );    // and the other 128
+
+Or run the script on the stack machine with `--vm=stack`:
+
+    rlox --vm=stack script.lox"
+            }
+            ErrorCode::NotCallable => {
+                "A value that is not a function was called.
+
+Only functions can be called, calling a number, a string or any other value
+fails:
+
+    var name = \"lox\";
+    name();
+
+Check that the variable holds the function meant to be called, and read a
+global that holds any other value instead:
+
+    vm.eval(\"var name = \\\"lox\\\";\")?;
+    let name = vm.get_global(\"name\");"
+            }
+            ErrorCode::InvalidBytecode => {
+                "Code given to `Vm::run` is not bytecode the virtual machine can run.
+
+The code fails `Chunk::verify`: an instruction is cut off, jumps into another
+one, or reads a constant or a stack slot that isn't there. The message gives
+the offset of the instruction. A chunk built by hand that prints with nothing
+on the stack fails:
+
+    0000    1 OP_PRINT
+    0001    | OP_RETURN
+
+Code from `Vm::compile` always runs, compile the script again, or push the
+value the instruction uses first:
+
+    0000    1 OP_CONSTANT         0 '1'
+    0002    | OP_PRINT
+    0003    | OP_RETURN"
+            }
+            ErrorCode::ArityMismatch => {
+                "A function was called with the wrong number of arguments.
+
+    clock(1);
+
+`clock` takes no arguments. Pass exactly as many arguments as the function
+declares:
+
+    clock();"
+            }
+            ErrorCode::TooManyArguments => {
+                "A call passes more than 255 arguments.
+
+    sum(1, 2, 3, /* ... */ 256);
+
+Calls take at most 255 arguments, like the functions they call. Pass fewer
+values, or add them up in steps:
+
+    var total = sum(1, 2, 3, /* ... */ 255);
+    total = total + 256;"
+            }
+            ErrorCode::NativeFailed => {
+                "A function the host program defined in Rust reported an error.
+
+    print shout(1);
+
+The message comes from the function. The notes list the calls that led to it,
+the innermost first. Pass the function what its message asks for:
+
+    print shout(\"hi\");"
+            }
+            ErrorCode::UnsupportedOnRegisterVm => {
+                "The script uses a feature the register machine does not run yet.
+
+    print clock();
+
+Function calls only run on the stack machine. Run the script with `--vm=stack`:
+
+    rlox --vm=stack script.lox"
+            }
+            ErrorCode::TypeMismatch => {
+                "A value is not of the type the Rust code reading it expects.
+
+A native function was passed a string where it takes a number, or `Vm::call`
+got back a value it can't convert, like `nil` where a `String` is wanted. For a
+value inside a list or a map, the notes say where it is. With a native that
+reads a number:
+
+    vm.define_native(\"double\", 1, |_, args| Ok(f64::from_lox(&args[0])? * 2.0));
+    vm.eval(\"print double(\\\"two\\\");\")?;
+
+Pass a value of the type the message names:
+
+    vm.eval(\"print double(2);\")?;"
+            }
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "E{:04}", self.number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ErrorCode;
+
+    #[test]
+    fn codes_are_sequential() {
+        for (index, code) in ErrorCode::ALL.into_iter().enumerate() {
+            assert_eq!(code.number() as usize, index + 1, "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn parse_codes() {
+        assert_eq!(ErrorCode::ExpectExpression.to_string(), "E0009");
+        assert_eq!(ErrorCode::parse("E0009"), Some(ErrorCode::ExpectExpression));
+        assert_eq!(
+            ErrorCode::parse("e12"),
+            Some(ErrorCode::UnterminatedInterpolation)
+        );
+        assert_eq!(ErrorCode::parse("E0000"), None);
+        assert_eq!(ErrorCode::parse("E9999"), None);
+        assert_eq!(ErrorCode::parse("0009"), None);
+        for code in ErrorCode::ALL {
+            assert_eq!(ErrorCode::parse(&code.to_string()), Some(code));
+        }
+    }
+
+    /// Every explanation shows the mistake and the fix as indented examples.
+    #[test]
+    fn every_code_is_explained() {
+        for code in ErrorCode::ALL {
+            let explanation = code.explanation();
+            let examples = explanation
+                .split("\n\n")
+                .filter(|block| block.starts_with("    "));
+            assert!(examples.count() >= 2, "{:?}", code);
+        }
+    }
+}
@@ -0,0 +1,541 @@
+use crate::chunk::{Chunk, Code, OpCode};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("The `jit` feature only targets x86-64 Linux");
+
+/// What the machine code calls back into for everything beyond arithmetic on the stack. The
+/// virtual machine implements it over its globals and output.
+pub trait Runtime {
+    fn print(&mut self, number: f64);
+    /// `None` when the global is not defined or does not hold a number.
+    fn get_global(&mut self, name: Code) -> Option<f64>;
+    /// False when the global is not defined.
+    fn set_global(&mut self, name: Code, number: f64) -> bool;
+    fn define_global(&mut self, name: Code, number: f64);
+}
+
+/// A chunk translated to x86-64 machine code. Only chunks where every value on the stack is a
+/// number are translated, so the stack is an array of doubles and each slot has a fixed place
+/// in it. A global that turns out not to be a number hands over to the interpreter, which goes
+/// on from that instruction with the numbers on the stack.
+pub struct Compiled<R> {
+    memory: ExecutableMemory,
+    /// The stack height before each instruction, by offset.
+    heights: Vec<Option<usize>>,
+    /// How many slots the stack array needs.
+    slots: usize,
+    runtime: PhantomData<fn(&mut R)>,
+}
+
+impl<R: Runtime> Compiled<R> {
+    /// Runs the machine code until the `Return` at the end or an instruction only the
+    /// interpreter can run. Returns the offset of that instruction and the stack before it.
+    pub fn run(&self, runtime: &mut R) -> (usize, Vec<f64>) {
+        let mut stack = vec![0.0; self.slots];
+        // The code follows the System V calling convention and only touches the stack array,
+        // the runtime through the callbacks and its own saved registers.
+        let offset = unsafe {
+            let entry: extern "C" fn(*mut f64, *mut R) -> u64 =
+                std::mem::transmute(self.memory.pointer);
+            entry(stack.as_mut_ptr(), runtime) as usize
+        };
+        stack.truncate(self.heights[offset].unwrap_or(0));
+        (offset, stack)
+    }
+}
+
+/// Translates `chunk`, `None` if it has an instruction the machine code does not support:
+/// anything that puts a value other than a number on the stack, except comparisons a
+/// conditional jump consumes right away.
+pub fn compile<R: Runtime>(chunk: &Chunk) -> Option<Compiled<R>> {
+    let heights = chunk.stack_heights().ok()?;
+    let starts: Vec<usize> = (0..chunk.code.len())
+        .filter(|&offset| heights[offset].is_some())
+        .collect();
+    let targets: HashSet<usize> = starts
+        .iter()
+        .filter(|&&offset| chunk.get_op_code(offset).is_jump())
+        .map(|&offset| chunk.jump_target(offset))
+        .collect();
+    let number = |idx: Code| chunk.get_constant(idx as usize)?.as_number();
+
+    let mut asm = Assembler::new(chunk.code.len());
+    asm.prologue();
+    // The conditional jump after a comparison, branched on by the comparison itself.
+    let mut fused = None;
+    for &offset in &starts {
+        if fused == Some(offset) {
+            continue;
+        }
+        asm.labels[offset] = Some(asm.code.len());
+        let height = heights[offset]?;
+        let (top, below) = (height.wrapping_sub(1), height.wrapping_sub(2));
+        let operand = || chunk.code[offset + 1];
+        match chunk.get_op_code(offset) {
+            OpCode::Constant => asm.number(number(operand())?, height),
+            op @ (OpCode::Equal
+            | OpCode::Greater
+            | OpCode::GreaterEqual
+            | OpCode::Less
+            | OpCode::LessEqual) => {
+                let next = offset + 1;
+                let jump = chunk.get_op_code(next);
+                let when = match jump {
+                    OpCode::JumpIfTrue => true,
+                    OpCode::JumpIfFalse => false,
+                    _ => return None,
+                };
+                if heights[next].is_none() || targets.contains(&next) {
+                    return None;
+                }
+                asm.compare(op, below, top, when, chunk.jump_target(next));
+                fused = Some(next);
+            }
+            OpCode::Add => asm.arithmetic(0x58, below, top, below),
+            OpCode::Subtract => asm.arithmetic(0x5c, below, top, below),
+            OpCode::Multiply => asm.arithmetic(0x59, below, top, below),
+            OpCode::Divide => asm.arithmetic(0x5e, below, top, below),
+            OpCode::Negate => asm.negate(top),
+            OpCode::Print => {
+                asm.load(0, top);
+                asm.runtime_argument();
+                asm.call(print::<R> as *const ());
+            }
+            OpCode::Pop => {}
+            OpCode::DefineGlobal => {
+                asm.name_arguments(operand());
+                asm.load(0, top);
+                asm.call(define_global::<R> as *const ());
+            }
+            OpCode::GetGlobal => {
+                asm.name_arguments(operand());
+                // lea rdx, [rbx + slot]
+                asm.slot(&[0x48, 0x8d], 2, height);
+                asm.call(get_global::<R> as *const ());
+                asm.bail_out_unless_rax(offset);
+            }
+            OpCode::SetGlobal => {
+                asm.name_arguments(operand());
+                asm.load(0, top);
+                asm.call(set_global::<R> as *const ());
+                asm.bail_out_unless_rax(offset);
+            }
+            OpCode::GetLocal => asm.copy(operand() as usize, height),
+            OpCode::SetLocal | OpCode::SetLocalPop => asm.copy(top, operand() as usize),
+            OpCode::AddConstant => {
+                asm.load(0, top);
+                asm.number_in_xmm1(number(operand())?);
+                asm.emit(&[0xf2, 0x0f, 0x58, 0xc1]);
+                asm.store(0, top);
+            }
+            OpCode::AddLocals => {
+                let second = chunk.code[offset + 2] as usize;
+                asm.arithmetic(0x58, operand() as usize, second, height);
+            }
+            OpCode::Jump | OpCode::Loop => asm.jump(chunk.jump_target(offset)),
+            // A number is never false, these never jump.
+            OpCode::JumpIfFalse | OpCode::JumpIfFalseOrPop => {}
+            OpCode::JumpIfTrue | OpCode::JumpIfTrueOrPop => asm.jump(chunk.jump_target(offset)),
+            OpCode::JumpIfNotLess => {
+                asm.compare(OpCode::Less, below, top, false, chunk.jump_target(offset))
+            }
+            OpCode::Return | OpCode::Eop => asm.exit(offset),
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Not
+            | OpCode::Stringify
+            | OpCode::Call => return None,
+        }
+    }
+
+    let slots = heights
+        .iter()
+        .flatten()
+        .max()
+        .map_or(1, |height| height + 1);
+    let memory = ExecutableMemory::new(&asm.finish()?)?;
+    Some(Compiled {
+        memory,
+        heights,
+        slots,
+        runtime: PhantomData,
+    })
+}
+
+extern "C" fn print<R: Runtime>(runtime: *mut R, number: f64) {
+    unsafe { (*runtime).print(number) }
+}
+
+extern "C" fn get_global<R: Runtime>(runtime: *mut R, name: u64, out: *mut f64) -> u64 {
+    match unsafe { (*runtime).get_global(name as Code) } {
+        Some(number) => {
+            unsafe { *out = number };
+            1
+        }
+        None => 0,
+    }
+}
+
+extern "C" fn set_global<R: Runtime>(runtime: *mut R, name: u64, number: f64) -> u64 {
+    unsafe { (*runtime).set_global(name as Code, number) as u64 }
+}
+
+extern "C" fn define_global<R: Runtime>(runtime: *mut R, name: u64, number: f64) {
+    unsafe { (*runtime).define_global(name as Code, number) }
+}
+
+/// x86-64 machine code under construction. Stack slots are addressed off `rbx` and the
+/// runtime is kept in `r12`, both callee-saved so calls into Rust leave them alone. Values
+/// are worked on in `xmm0` and `xmm1`.
+struct Assembler {
+    code: Vec<u8>,
+    /// Where the code of each instruction starts, by bytecode offset.
+    labels: Vec<Option<usize>>,
+    /// Jumps to patch once every label is known: where their 32-bit displacement is, and the
+    /// bytecode offset they go to.
+    jumps: Vec<(usize, usize)>,
+    /// The same for jumps out to the interpreter, which goes on at the bytecode offset.
+    exits: Vec<(usize, usize)>,
+}
+
+impl Assembler {
+    fn new(length: usize) -> Self {
+        Assembler {
+            code: Vec::new(),
+            labels: vec![None; length],
+            jumps: Vec::new(),
+            exits: Vec::new(),
+        }
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// Keeps the stack array and the runtime, the arguments, in callee-saved registers. Three
+    /// pushes on top of the return address leave the stack aligned for calls.
+    fn prologue(&mut self) {
+        // push rbx; push r12; push rbp; mov rbx, rdi; mov r12, rsi
+        self.emit(&[0x53, 0x41, 0x54, 0x55, 0x48, 0x89, 0xfb, 0x49, 0x89, 0xf4]);
+    }
+
+    /// Returns `offset` to the caller.
+    fn exit(&mut self, offset: usize) {
+        self.emit(&[0xb8]);
+        self.emit(&(offset as u32).to_le_bytes());
+        // pop rbp; pop r12; pop rbx; ret
+        self.emit(&[0x5d, 0x41, 0x5c, 0x5b, 0xc3]);
+    }
+
+    /// An instruction on `[rbx + 8 * slot]`, `register` goes in the middle bits of ModRM.
+    fn slot(&mut self, opcode: &[u8], register: u8, slot: usize) {
+        self.emit(opcode);
+        self.emit(&[0x80 | register << 3 | 3]);
+        self.emit(&(8 * slot as i32).to_le_bytes());
+    }
+
+    /// movsd xmm, [slot]
+    fn load(&mut self, xmm: u8, slot: usize) {
+        self.slot(&[0xf2, 0x0f, 0x10], xmm, slot);
+    }
+
+    /// movsd [slot], xmm
+    fn store(&mut self, xmm: u8, slot: usize) {
+        self.slot(&[0xf2, 0x0f, 0x11], xmm, slot);
+    }
+
+    /// Copies a slot through `rax`.
+    fn copy(&mut self, from: usize, to: usize) {
+        self.slot(&[0x48, 0x8b], 0, from);
+        self.slot(&[0x48, 0x89], 0, to);
+    }
+
+    fn number(&mut self, number: f64, slot: usize) {
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&number.to_bits().to_le_bytes());
+        self.slot(&[0x48, 0x89], 0, slot);
+    }
+
+    fn number_in_xmm1(&mut self, number: f64) {
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&number.to_bits().to_le_bytes());
+        // movq xmm1, rax
+        self.emit(&[0x66, 0x48, 0x0f, 0x6e, 0xc8]);
+    }
+
+    /// `dest = left op right` with one of the scalar double instructions, `addsd` is `0x58`.
+    fn arithmetic(&mut self, opcode: u8, left: usize, right: usize, dest: usize) {
+        self.load(0, left);
+        self.load(1, right);
+        self.emit(&[0xf2, 0x0f, opcode, 0xc1]);
+        self.store(0, dest);
+    }
+
+    /// Flips the sign bit, like `-` does on a double.
+    fn negate(&mut self, slot: usize) {
+        self.slot(&[0x48, 0x8b], 0, slot);
+        // btc rax, 63
+        self.emit(&[0x48, 0x0f, 0xba, 0xf8, 0x3f]);
+        self.slot(&[0x48, 0x89], 0, slot);
+    }
+
+    /// Jumps to `target` if comparing `left` with `right` by `op` gives `when`. A NaN operand
+    /// makes every comparison false, as in the interpreter.
+    fn compare(&mut self, op: OpCode, left: usize, right: usize, when: bool, target: usize) {
+        self.load(0, left);
+        self.load(1, right);
+        // `ucomisd a, b` sets the flags of an unsigned `a - b`, and all of them for a NaN.
+        let (swap, condition) = match op {
+            OpCode::Less => (true, 0x87),
+            OpCode::LessEqual => (true, 0x83),
+            OpCode::Greater => (false, 0x87),
+            OpCode::GreaterEqual => (false, 0x83),
+            _ => {
+                // ucomisd xmm0, xmm1, equal is ZF without PF.
+                self.emit(&[0x66, 0x0f, 0x2e, 0xc1]);
+                if when {
+                    // jne +7; jp +5; jmp target
+                    self.emit(&[0x75, 0x07, 0x7a, 0x05]);
+                    self.jump(target);
+                } else {
+                    self.conditional_jump(0x85, target);
+                    self.conditional_jump(0x8a, target);
+                }
+                return;
+            }
+        };
+        let modrm = if swap { 0xc8 } else { 0xc1 };
+        self.emit(&[0x66, 0x0f, 0x2e, modrm]);
+        // `ja` and `jae` flip to `jbe` and `jb` by their lowest bit.
+        self.conditional_jump(if when { condition } else { condition ^ 1 }, target);
+    }
+
+    fn jump(&mut self, target: usize) {
+        self.emit(&[0xe9]);
+        self.jumps.push((self.code.len(), target));
+        self.emit(&[0; 4]);
+    }
+
+    /// The second byte of a `jcc rel32`, `0x87` for `ja`.
+    fn conditional_jump(&mut self, condition: u8, target: usize) {
+        self.emit(&[0x0f, condition]);
+        self.jumps.push((self.code.len(), target));
+        self.emit(&[0; 4]);
+    }
+
+    /// mov rdi, r12
+    fn runtime_argument(&mut self) {
+        self.emit(&[0x4c, 0x89, 0xe7]);
+    }
+
+    /// The runtime and the index of a name, the first two arguments of the global callbacks.
+    fn name_arguments(&mut self, name: Code) {
+        self.runtime_argument();
+        // mov esi, name
+        self.emit(&[0xbe]);
+        self.emit(&(name as u32).to_le_bytes());
+    }
+
+    fn call(&mut self, function: *const ()) {
+        // mov rax, function; call rax
+        self.emit(&[0x48, 0xb8]);
+        self.emit(&(function as u64).to_le_bytes());
+        self.emit(&[0xff, 0xd0]);
+    }
+
+    /// Hands over to the interpreter at `offset` if the callback returned 0.
+    fn bail_out_unless_rax(&mut self, offset: usize) {
+        // test rax, rax; jz
+        self.emit(&[0x48, 0x85, 0xc0, 0x0f, 0x84]);
+        self.exits.push((self.code.len(), offset));
+        self.emit(&[0; 4]);
+    }
+
+    /// The finished code, `None` if a jump goes to an instruction that was not translated.
+    fn finish(mut self) -> Option<Vec<u8>> {
+        for (at, offset) in std::mem::take(&mut self.exits) {
+            self.patch(at, self.code.len());
+            self.exit(offset);
+        }
+        for (at, target) in std::mem::take(&mut self.jumps) {
+            let label = (*self.labels.get(target)?)?;
+            self.patch(at, label);
+        }
+        Some(self.code)
+    }
+
+    fn patch(&mut self, at: usize, to: usize) {
+        let displacement = to as i32 - (at as i32 + 4);
+        self.code[at..at + 4].copy_from_slice(&displacement.to_le_bytes());
+    }
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 0x02;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(
+        address: *mut u8,
+        length: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut u8;
+    fn mprotect(address: *mut u8, length: usize, prot: i32) -> i32;
+    fn munmap(address: *mut u8, length: usize) -> i32;
+}
+
+/// Pages mapped for machine code, written while they are writable and then made executable.
+struct ExecutableMemory {
+    pointer: *mut u8,
+    length: usize,
+}
+
+impl ExecutableMemory {
+    fn new(code: &[u8]) -> Option<Self> {
+        let length = code.len().max(1);
+        let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+        let pointer = unsafe {
+            mmap(
+                std::ptr::null_mut(),
+                length,
+                PROT_READ | PROT_WRITE,
+                flags,
+                -1,
+                0,
+            )
+        };
+        if pointer as isize == -1 {
+            return None;
+        }
+        let memory = ExecutableMemory { pointer, length };
+        unsafe {
+            std::ptr::copy_nonoverlapping(code.as_ptr(), pointer, code.len());
+            if mprotect(pointer, length, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+        }
+        Some(memory)
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe { munmap(self.pointer, self.length) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::compile;
+    use crate::chunk::Chunk;
+    use crate::compiler::{self, OptLevel};
+    use crate::optimizer::tests::Output;
+    use crate::vm::VirtualMachine;
+    use crate::{optimizer, register_vm};
+
+    /// Programs the machine code runs from start to end, between them every instruction it
+    /// supports.
+    const NUMERIC: [&str; 8] = [
+        "var a = 1; var b = 2; print a + b * 3 - -a / b; a = b = 5; print a;",
+        "{ var i = 0; var sum = 0; while (i < 100) { if (i >= 50) if (i <= 60) sum = sum + i; i = i + 1; } print sum; }",
+        "{ var x = 0; while (x != 10) x = x + 1; if (x == 10) print x; if (x > 9) print -x; }",
+        "var nan = 0 / 0; if (nan < 1) print 1; else print 2; if (nan == nan) print 3; else print 4;\n\
+         if (nan >= 1) print 5; if (nan != nan) print 6; print nan; print 1 / 0;",
+        "print 1 or 2; print 0 and 3; { var a = 4; print a or 5; print a and 6; }",
+        "{ var a = 1.5; var b = -2; print a + b; print a * b; print -(a - b); var c = a; c = c + 1; print c + a; }",
+        "var i = 0; while (i < 5) { print i; i = i + 1; } print i;",
+        "{ var a = 1; { var b = a + 2; { var c = b * b; a = c - a; } } print a; }",
+    ];
+
+    /// Runs `sources` one after the other on one virtual machine, like lines of the REPL.
+    /// Returns what they printed and the line and code of the first runtime error.
+    fn run(sources: &[&str], level: OptLevel, jit: bool) -> (String, Option<String>) {
+        let output = Output::default();
+        let mut vm = VirtualMachine::with_output(output.clone());
+        vm.jit = jit;
+        let mut error = None;
+        for source in sources {
+            vm.chunks = Chunk::new();
+            let diagnostics = compiler::compile(source, &mut vm.chunks, level);
+            if !diagnostics.is_empty() {
+                return (String::new(), Some("compile error".to_string()));
+            }
+            vm.run();
+            if let Some(diagnostic) = vm.take_error() {
+                error = Some(format!("{} {:?}", diagnostic.span.line, diagnostic.code));
+                break;
+            }
+        }
+        let printed = String::from_utf8(output.0.take()).expect("Output is UTF-8");
+        (printed, error)
+    }
+
+    #[test]
+    fn translate_numeric_programs() {
+        for source in NUMERIC {
+            let mut chunk = Chunk::new();
+            assert!(compiler::compile(source, &mut chunk, OptLevel::default()).is_empty());
+            assert!(compile::<VirtualMachine>(&chunk).is_some(), "{}", source);
+        }
+        let mut chunk = Chunk::new();
+        assert!(compiler::compile("print \"text\";", &mut chunk, OptLevel::default()).is_empty());
+        assert!(compile::<VirtualMachine>(&chunk).is_none());
+    }
+
+    /// Every test program runs the same with and without machine code, at every level.
+    #[test]
+    fn jit_matches_interpreter() {
+        let programs = compiler::tests::PROGRAMS
+            .iter()
+            .chain(&optimizer::tests::PROGRAMS)
+            .chain(&register_vm::tests::PROGRAMS)
+            .chain(&NUMERIC);
+        for source in programs {
+            for level in [OptLevel::None, OptLevel::Fold, OptLevel::Peephole] {
+                let expected = run(&[source], level, false);
+                assert_eq!(
+                    run(&[source], level, true),
+                    expected,
+                    "{} at {:?}",
+                    source,
+                    level
+                );
+            }
+        }
+        assert_eq!(run(&[NUMERIC[1]], OptLevel::default(), true).0, "605\n");
+        assert_eq!(
+            run(&[NUMERIC[3]], OptLevel::default(), true).0,
+            "2\n4\n6\nNaN\ninf\n"
+        );
+    }
+
+    #[test]
+    fn bail_out_to_the_interpreter() {
+        let sources = [
+            "var s = \"text\"; var n = 1;",
+            "print n; print s; print n + 1; n = n + 2; print n;",
+            "n = 0;\nprint n;\nprint undefined;\nprint 1;",
+        ];
+        let mut chunk = Chunk::new();
+        assert!(compiler::compile(sources[1], &mut chunk, OptLevel::default()).is_empty());
+        assert!(compile::<VirtualMachine>(&chunk).is_some());
+
+        let expected = run(&sources, OptLevel::default(), false);
+        assert_eq!(run(&sources, OptLevel::default(), true), expected);
+        assert_eq!(
+            expected,
+            (
+                "1\ntext\n2\n3\n0\n".to_string(),
+                Some("3 Some(UndefinedVariable)".to_string())
+            )
+        );
+    }
+}
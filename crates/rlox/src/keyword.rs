@@ -0,0 +1,79 @@
+use crate::token::TokenType;
+
+const KEYWORDS: [(&[u8], TokenType); 16] = [
+    (b"and", TokenType::And),
+    (b"class", TokenType::Class),
+    (b"else", TokenType::Else),
+    (b"false", TokenType::False),
+    (b"for", TokenType::For),
+    (b"fun", TokenType::Fun),
+    (b"if", TokenType::If),
+    (b"nil", TokenType::Nil),
+    (b"or", TokenType::Or),
+    (b"print", TokenType::Print),
+    (b"return", TokenType::Return),
+    (b"super", TokenType::Super),
+    (b"this", TokenType::This),
+    (b"true", TokenType::True),
+    (b"var", TokenType::Var),
+    (b"while", TokenType::While),
+];
+
+const TABLE_SIZE: usize = 32;
+
+/// Perfect hash of the keywords: no two of them share a slot, so a lookup is a single compare.
+const fn hash(word: &[u8]) -> usize {
+    (word[0] as usize * 7 + word[word.len() - 1] as usize + word.len()) % TABLE_SIZE
+}
+
+const TABLE: [Option<(&[u8], TokenType)>; TABLE_SIZE] = {
+    let mut table = [None; TABLE_SIZE];
+    let mut i = 0;
+    while i < KEYWORDS.len() {
+        let slot = hash(KEYWORDS[i].0);
+        assert!(table[slot].is_none(), "keyword hash collision");
+        table[slot] = Some(KEYWORDS[i]);
+        i += 1;
+    }
+    table
+};
+
+/// The keyword token type of an identifier, or `TokenType::Identifier`.
+pub fn keyword_type(word: &[u8]) -> TokenType {
+    if word.len() < 2 || word.len() > 6 {
+        return TokenType::Identifier;
+    }
+    match TABLE[hash(word)] {
+        Some((keyword, kind)) if keyword == word => kind,
+        _ => TokenType::Identifier,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keyword_type, KEYWORDS};
+    use crate::TokenType;
+
+    #[test]
+    fn lookup_keywords() {
+        for (word, kind) in KEYWORDS {
+            assert_eq!(keyword_type(word), kind);
+        }
+    }
+
+    #[test]
+    fn lookup_identifiers() {
+        let words = [
+            "a", "an", "andy", "classy", "f", "fa", "fo", "nils", "prints", "t", "tru", "whilst",
+            "_",
+        ];
+        for word in words {
+            assert_eq!(
+                keyword_type(word.as_bytes()),
+                TokenType::Identifier,
+                "{}",
+                word
+            );
+        }
+    }
+}
@@ -0,0 +1,94 @@
+use crate::diagnostic::Span;
+use crate::error_code::ErrorCode;
+use std::fmt;
+
+/// Why the scanner produced a `TokenType::Error` token.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum LexError {
+    UnexpectedCharacter(char),
+    /// `line`, `offset` and `column` are where the opening quote is, or the `}` the rest of an
+    /// interpolated string follows.
+    UnterminatedString {
+        line: usize,
+        offset: usize,
+        column: usize,
+    },
+    /// `line` is where the outermost `/*` is.
+    UnterminatedComment {
+        line: usize,
+    },
+    /// `escape` is the character after the backslash at byte `offset`.
+    InvalidEscape {
+        escape: char,
+        line: usize,
+        offset: usize,
+    },
+    InvalidNumber(NumberError),
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum NumberError {
+    MissingDigits,
+    MissingExponent,
+    InvalidSeparator,
+    InvalidDigit,
+}
+
+impl LexError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            LexError::UnexpectedCharacter(_) => ErrorCode::UnexpectedCharacter,
+            LexError::UnterminatedString { .. } => ErrorCode::UnterminatedString,
+            LexError::UnterminatedComment { .. } => ErrorCode::UnterminatedComment,
+            LexError::InvalidEscape { .. } => ErrorCode::InvalidEscape,
+            LexError::InvalidNumber(NumberError::MissingDigits) => ErrorCode::MissingDigits,
+            LexError::InvalidNumber(NumberError::MissingExponent) => ErrorCode::MissingExponent,
+            LexError::InvalidNumber(NumberError::InvalidSeparator) => ErrorCode::InvalidSeparator,
+            LexError::InvalidNumber(NumberError::InvalidDigit) => ErrorCode::InvalidDigit,
+        }
+    }
+
+    /// Where to report the error, if narrower than the whole error token.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            LexError::UnterminatedString { line, offset, .. } => {
+                Some(Span::new(*offset, offset + 1, *line))
+            }
+            LexError::InvalidEscape {
+                escape,
+                line,
+                offset,
+            } => Some(Span::new(*offset, offset + 1 + escape.len_utf8(), *line)),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LexError::UnexpectedCharacter(c) => write!(f, "Unexpected character '{}'.", c),
+            LexError::UnterminatedString { .. } => write!(f, "Unterminated string."),
+            LexError::UnterminatedComment { .. } => write!(f, "Unterminated block comment."),
+            LexError::InvalidEscape { escape, .. } => {
+                write!(
+                    f,
+                    "Invalid escape sequence '\\{}'.",
+                    escape.escape_default()
+                )
+            }
+            LexError::InvalidNumber(NumberError::MissingDigits) => {
+                write!(f, "Missing digits in number literal.")
+            }
+            LexError::InvalidNumber(NumberError::MissingExponent) => {
+                write!(f, "Missing exponent digits.")
+            }
+            LexError::InvalidNumber(NumberError::InvalidSeparator) => {
+                write!(f, "Invalid digit separator.")
+            }
+            LexError::InvalidNumber(NumberError::InvalidDigit) => {
+                write!(f, "Invalid digit in number literal.")
+            }
+        }
+    }
+}
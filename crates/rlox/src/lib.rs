@@ -0,0 +1,170 @@
+//! Lox as a library. `Vm` compiles and runs scripts for a Rust program and trades values with
+//! them, `LoxError` is what goes wrong doing so.
+
+use crate::chunk::{Chunk, Code, OpCode};
+use crate::scanner::Scanner;
+use crate::token::{Token, TokenType};
+
+pub use crate::compiler::OptLevel;
+pub use crate::convert::{FromLox, IntoArgs, IntoLox};
+pub use crate::embed::Vm;
+pub use crate::lox_error::LoxError;
+pub use crate::value::Value;
+
+mod ast;
+mod bundle;
+mod c_backend;
+mod chunk;
+mod compiler;
+mod convert;
+mod diagnostic;
+mod embed;
+mod error_code;
+#[cfg(feature = "jit")]
+mod jit;
+mod keyword;
+mod lex_error;
+mod lox_error;
+mod native;
+mod object;
+mod optimizer;
+mod parser;
+mod register;
+mod register_compiler;
+mod register_vm;
+mod scanner;
+mod token;
+mod token_dump;
+mod trivia;
+mod value;
+mod vm;
+
+/// What the `rlox` command line tools are built from besides `Vm`. None of it is a stable
+/// interface, it changes along with the interpreter.
+#[doc(hidden)]
+pub mod internals {
+    pub use crate::chunk::Chunk;
+    pub use crate::diagnostic::{Diagnostic, Renderer};
+    pub use crate::error_code::ErrorCode;
+    pub use crate::register_vm::RegisterVm;
+    pub use crate::scanner::Scanner;
+
+    pub mod ast {
+        pub use crate::ast::parse;
+    }
+
+    pub mod bundle {
+        pub use crate::bundle::{payload, write};
+    }
+
+    pub mod c_backend {
+        pub use crate::c_backend::emit;
+    }
+
+    pub mod token_dump {
+        pub use crate::token_dump::{format_lossless_token, format_token};
+    }
+}
+
+pub enum InterpretResult {
+    Ok,
+    CompileError,
+    RuntimeError,
+}
+
+/// The value stack, it grows as deep as the code needs: locals and nested expressions may
+/// take more than the slots it starts with. Values come and go as `Value`, in between the
+/// stack keeps them as `TValue`.
+struct VmStack<TValue: std::fmt::Debug> {
+    data: Vec<TValue>,
+}
+
+impl<TValue: std::fmt::Debug + Clone + From<Value>> VmStack<TValue>
+where
+    Value: From<TValue>,
+{
+    fn new(capacity: usize) -> Self {
+        VmStack {
+            data: Vec::with_capacity(capacity),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        self.data.push(value.into())
+    }
+
+    fn pop(&mut self) -> Option<Value> {
+        self.data.pop().map(Value::from)
+    }
+
+    fn peek(&self) -> Option<Value> {
+        self.data.last().cloned().map(Value::from)
+    }
+
+    fn get(&self, slot: usize) -> Option<Value> {
+        self.data.get(slot).cloned().map(Value::from)
+    }
+
+    fn top(&self) -> Option<&TValue> {
+        self.data.last()
+    }
+
+    fn replace_top(&mut self, value: Value) {
+        if let Some(top) = self.data.last_mut() {
+            *top = value.into();
+        }
+    }
+
+    /// The value below the top one and the top one, the operands of a binary instruction.
+    fn top_two(&self) -> Option<(&TValue, &TValue)> {
+        match self.data.as_slice() {
+            [.., a, b] => Some((a, b)),
+            _ => None,
+        }
+    }
+
+    /// Replaces the two values on top with the result of the instruction using them.
+    fn replace_top_two(&mut self, value: Value) {
+        if let [.., a, _] = self.data.as_mut_slice() {
+            *a = value.into();
+            self.data.pop();
+        }
+    }
+
+    /// The value `distance` below the top one, the top one at 0.
+    fn peek_at(&self, distance: usize) -> Option<Value> {
+        let slot = self.data.len().checked_sub(distance + 1)?;
+        self.get(slot)
+    }
+
+    /// The `count` values on top, the topmost last.
+    fn top_values(&self, count: usize) -> Vec<Value> {
+        let start = self.data.len() - count;
+        self.data[start..]
+            .iter()
+            .cloned()
+            .map(Value::from)
+            .collect()
+    }
+
+    /// Pops the `count` values on top.
+    fn discard(&mut self, count: usize) {
+        self.data.truncate(self.data.len() - count)
+    }
+
+    fn set(&mut self, slot: usize, value: Value) {
+        self.data[slot] = value.into()
+    }
+
+    fn reset(&mut self) {
+        self.data.clear()
+    }
+
+    #[cfg(feature = "debug_trace_execution")]
+    fn trace(&self) {
+        for val in self.data.iter() {
+            print!("[{:?}]", val);
+        }
+        println!();
+    }
+}
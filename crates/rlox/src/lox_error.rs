@@ -0,0 +1,134 @@
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error_code::ErrorCode;
+use std::{error, fmt, io};
+
+/// Why evaluating Lox failed.
+#[derive(Debug)]
+pub enum LoxError {
+    /// The source did not compile. Every error of the compilation is kept.
+    Compile(Vec<Diagnostic>),
+    /// Running the code stopped. The diagnostic points at the instruction that failed.
+    Runtime(Diagnostic),
+    /// A script could not be read.
+    Io(io::Error),
+}
+
+impl LoxError {
+    /// A runtime error outside of any script, on line 0.
+    pub fn runtime(code: ErrorCode, message: impl Into<String>) -> Self {
+        LoxError::Runtime(Diagnostic::error(code, Span::new(0, 0, 0), message))
+    }
+
+    /// The error a native function returns when it fails, with `message` to show the user.
+    pub fn native(message: impl Into<String>) -> Self {
+        LoxError::runtime(ErrorCode::NativeFailed, message)
+    }
+
+    /// The error as the diagnostic of a script that called a native function which failed
+    /// with it: the first error of a compilation, or the message of an `Io` error.
+    pub(crate) fn into_diagnostic(self) -> Diagnostic {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                let error = diagnostics.into_iter().find(Diagnostic::is_error);
+                error.expect("Compilations fail with an error")
+            }
+            LoxError::Runtime(diagnostic) => diagnostic,
+            LoxError::Io(error) => Diagnostic::error(
+                ErrorCode::NativeFailed,
+                Span::new(0, 0, 0),
+                error.to_string(),
+            ),
+        }
+    }
+
+    /// The diagnostics to show for the error, none for an `Io` error.
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            LoxError::Compile(diagnostics) => diagnostics,
+            LoxError::Runtime(diagnostic) => std::slice::from_ref(diagnostic),
+            LoxError::Io(_) => &[],
+        }
+    }
+
+    /// The code of the first error, for `rlox explain`.
+    pub fn code(&self) -> Option<ErrorCode> {
+        self.diagnostics()
+            .iter()
+            .find_map(|diagnostic| diagnostic.code)
+    }
+}
+
+/// The outcome of a compilation that reported `diagnostics`, it failed if there are any.
+pub(crate) fn check(diagnostics: Vec<Diagnostic>) -> Result<(), LoxError> {
+    if diagnostics.is_empty() {
+        Ok(())
+    } else {
+        Err(LoxError::Compile(diagnostics))
+    }
+}
+
+/// The one line form of each diagnostic, a line apiece.
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoxError::Compile(diagnostics) => {
+                for (index, diagnostic) in diagnostics.iter().enumerate() {
+                    if index > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            LoxError::Runtime(diagnostic) => write!(f, "{}", diagnostic),
+            LoxError::Io(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for LoxError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            LoxError::Io(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoxError {
+    fn from(error: io::Error) -> Self {
+        LoxError::Io(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LoxError;
+    use crate::chunk::Chunk;
+    use crate::compiler::{self, OptLevel};
+    use crate::error_code::ErrorCode;
+    use std::io;
+
+    #[test]
+    fn display_errors() {
+        let mut chunk = Chunk::new();
+        let diagnostics =
+            compiler::compile("print 1 +;\nprint (2;", &mut chunk, OptLevel::default());
+        let error = super::check(diagnostics).expect_err("Compile error");
+        assert_eq!(
+            error.to_string(),
+            "[line 1] Error[E0009] at ';': Expect expression.\n\
+             [line 2] Error[E0010] at ';': Expect ')' after expression.\n  \
+             note: The '(' to match is on line 2."
+        );
+        assert_eq!(error.code(), Some(ErrorCode::ExpectExpression));
+
+        let error = LoxError::runtime(ErrorCode::NotCallable, "Can only call functions.");
+        assert_eq!(error.to_string(), "Error[E0028]: Can only call functions.");
+        let error = LoxError::from(io::Error::new(io::ErrorKind::NotFound, "No such file"));
+        assert_eq!(
+            (error.to_string().as_str(), error.code()),
+            ("No such file", None)
+        );
+    }
+}
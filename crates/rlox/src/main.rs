@@ -1,98 +1,157 @@
-use crate::chunk::{Chunk, Code, OpCode, Value};
-use crate::scanner::Scanner;
-use crate::token::{Token, TokenType};
-use crate::vm::VirtualMachine;
-use crate::parser::Parser;
-use std::io::BufRead;
+use rlox::internals::{ast, bundle, c_backend, token_dump};
+use rlox::internals::{Chunk, Diagnostic, ErrorCode, RegisterVm, Renderer, Scanner};
+use rlox::{LoxError, OptLevel, Vm};
+use std::io::{BufRead, IsTerminal};
+use std::path::{Path, PathBuf};
 use std::{env, fs, io};
 
-mod chunk;
-mod scanner;
-mod parser;
-mod token;
-mod vm;
-
-pub enum InterpretResult {
-    Ok,
-    CompileError,
-    RuntimeError,
-}
-
-struct VmStack<TValue: std::fmt::Debug> {
-    max_size: usize,
-    data: Vec<TValue>,
+/// The virtual machine scripts run on, picked with `--vm=stack` (the default) or `--vm=register`.
+enum Engine {
+    Stack(Vm),
+    Register(RegisterVm),
 }
 
-impl<TValue: std::fmt::Debug> VmStack<TValue> {
-    fn new(max_size: usize) -> Self {
-        VmStack {
-            max_size,
-            data: Vec::with_capacity(max_size),
+impl Engine {
+    fn parse(flag: &str) -> Option<Engine> {
+        match flag {
+            "--vm=stack" => Some(Engine::Stack(Vm::new())),
+            "--vm=register" => Some(Engine::Register(RegisterVm::new())),
+            _ => None,
         }
     }
 
-    fn push(&mut self, value: TValue) {
-        if self.data.len() >= self.max_size {
-            panic!("Stack overflow")
-        }
-        self.data.push(value)
-    }
-
-    fn pop(&mut self) -> Option<TValue> {
-        self.data.pop()
-    }
-
-    fn trace(&self) {
-        for val in self.data.iter() {
-            print!("[{:?}]", val);
+    /// Compiles and runs `source`.
+    fn eval(&mut self, source: &str) -> Result<(), LoxError> {
+        match self {
+            Engine::Stack(vm) => {
+                let chunk = vm.compile(source)?;
+                let result = vm.run(chunk);
+                #[cfg(feature = "opcode_pairs")]
+                eprint!("{}", vm.pair_report());
+                result
+            }
+            Engine::Register(vm) => vm.execute(RegisterVm::compile(source)?),
         }
-        println!();
     }
 }
 
 fn main() {
-    let args: Vec<String> = env::args().collect();
-    let mut vm = VirtualMachine::new();
+    // A copy made by `rlox bundle` runs the script it carries and nothing else.
+    if let Ok(Some(payload)) = env::current_exe().and_then(|path| bundle::payload(&path)) {
+        run_bundle(&payload);
+        return;
+    }
+
+    let mut level = OptLevel::default();
+    let mut engine = None;
+    let args: Vec<String> = env::args()
+        .filter(|arg| {
+            if let Some(flag) = OptLevel::parse(arg) {
+                level = flag;
+            } else if let Some(flag) = Engine::parse(arg) {
+                engine = Some(flag);
+            } else {
+                return true;
+            }
+            false
+        })
+        .collect();
+    let mut vm = match engine.unwrap_or_else(|| Engine::Stack(Vm::new())) {
+        Engine::Stack(vm) => Engine::Stack(vm.opt_level(level)),
+        engine => engine,
+    };
 
-    match args.len() {
-        1 => {
+    match args.as_slice() {
+        [_] => {
             repl(&mut vm);
         }
-        2 => {
-            run_file(&mut vm, args[1].as_str());
+        [_, command, path, flags @ ..]
+            if command == "tokens"
+                && flags
+                    .iter()
+                    .all(|flag| flag == "--json" || flag == "--trivia") =>
+        {
+            let has_flag = |name: &str| flags.iter().any(|flag| flag == name);
+            dump_tokens(path, has_flag("--json"), has_flag("--trivia"));
+        }
+        [_, command, path] if command == "ast" => {
+            dump_ast(path);
+        }
+        [_, command, code] if command == "explain" => {
+            explain(code);
+        }
+        [_, command, emit, target, path, output @ ..]
+            if command == "build"
+                && emit == "--emit"
+                && target == "c"
+                && (output.is_empty() || output.len() == 2 && output[0] == "-o") =>
+        {
+            build_c(path, output.get(1), level);
+        }
+        [_, command, path, output @ ..]
+            if command == "bundle"
+                && (output.is_empty() || output.len() == 2 && output[0] == "-o") =>
+        {
+            bundle(path, output.get(1), level);
+        }
+        [_, path] => {
+            run_file(&mut vm, path);
         }
         _ => {
-            println!("Usage: rlox [path]");
+            println!("Usage: rlox [-O0|-O1|-O2|-O3] [--vm=stack|--vm=register] [path]");
+            println!("       rlox tokens <path> [--json] [--trivia]");
+            println!("       rlox ast <path>");
+            println!("       rlox explain <code>");
+            println!("       rlox [-O0|-O1|-O2|-O3] build --emit c <path> [-o <output>]");
+            println!("       rlox [-O0|-O1|-O2|-O3] bundle <path> [-o <output>]");
 
             std::process::exit(64);
         }
     }
 }
 
-fn repl(vm: &mut VirtualMachine) {
+fn repl(vm: &mut Engine) {
     let stdin = io::stdin();
     let mut lines = stdin.lock().lines();
     loop {
         print!("> ");
         if let Ok(line) = lines.next().unwrap() {
-            interpret(vm, line.as_str());
+            let _ = interpret(vm, "<repl>", line.as_str());
         } else {
             break;
         }
     }
 }
 
-fn run_file(vm: &mut VirtualMachine, path: &str) {
+fn run_file(vm: &mut Engine, path: &str) {
     if let Ok(source) = fs::read_to_string(path) {
-        match interpret(vm, source.as_str()) {
-            InterpretResult::Ok => {}
-            InterpretResult::CompileError => {
+        match interpret(vm, path, source.as_str()) {
+            Ok(()) => {}
+            Err(LoxError::Runtime(_)) => {
+                eprintln!("Runtime error");
+                std::process::exit(70);
+            }
+            Err(_) => {
                 eprintln!("Compilation error");
                 std::process::exit(65);
             }
-            InterpretResult::RuntimeError => {
-                eprintln!("Runtime error");
-                std::process::exit(70);
+        }
+    } else {
+        eprintln!("Could not open file '{}'", path);
+        std::process::exit(64);
+    }
+}
+
+fn dump_tokens(path: &str, json: bool, trivia: bool) {
+    if let Ok(source) = fs::read_to_string(path) {
+        let scanner = Scanner::new(source.as_str());
+        if trivia {
+            for token in scanner.lossless() {
+                println!("{}", token_dump::format_lossless_token(&token, json));
+            }
+        } else {
+            for token in scanner {
+                println!("{}", token_dump::format_token(&token, json));
             }
         }
     } else {
@@ -101,14 +160,126 @@ fn run_file(vm: &mut VirtualMachine, path: &str) {
     }
 }
 
-fn interpret(vm: &mut VirtualMachine, source: &str) -> InterpretResult {
-    let mut scanner = Scanner::new(source);
-    let mut parser = Parser::new(&mut scanner, &mut vm.chunks);
-    let parse_result = parser.parse();
+fn dump_ast(path: &str) {
+    if let Ok(source) = fs::read_to_string(path) {
+        let renderer = Renderer::new(path, &source).colour(io::stderr().is_terminal());
+        let (program, diagnostics) = ast::parse(&source);
+        for diagnostic in &diagnostics {
+            eprintln!("{}", renderer.render(diagnostic));
+        }
+        println!("{}", program);
+    } else {
+        eprintln!("Could not open file '{}'", path);
+        std::process::exit(64);
+    }
+}
+
+/// Writes the C translation of a script next to it, or to `output`.
+fn build_c(path: &str, output: Option<&String>, level: OptLevel) {
+    let chunk = compile_file(path, level);
+    let translated = match c_backend::emit(&chunk, path) {
+        Ok(translated) => translated,
+        Err(message) => {
+            eprintln!("Could not translate '{}': {}", path, message);
+            std::process::exit(70);
+        }
+    };
+    let output = output.map_or_else(|| Path::new(path).with_extension("c"), PathBuf::from);
+    if fs::write(&output, translated).is_err() {
+        eprintln!("Could not write file '{}'", output.display());
+        std::process::exit(74);
+    }
+}
+
+/// Compiles a script, exiting the way `run_file` does on an error.
+fn compile_file(path: &str, level: OptLevel) -> Chunk {
+    let Ok(source) = fs::read_to_string(path) else {
+        eprintln!("Could not open file '{}'", path);
+        std::process::exit(64);
+    };
+    let renderer = Renderer::new(path, &source).colour(io::stderr().is_terminal());
+    match Vm::new().opt_level(level).compile(&source) {
+        Ok(chunk) => chunk,
+        Err(error) => {
+            report(&renderer, error.diagnostics());
+            eprintln!("Compilation error");
+            std::process::exit(65);
+        }
+    }
+}
+
+/// Writes a copy of this executable that runs the bytecode of a script, next to the script
+/// without its extension, or to `output`.
+fn bundle(path: &str, output: Option<&String>, level: OptLevel) {
+    let chunk = compile_file(path, level);
+    let output = output.map_or_else(|| Path::new(path).with_extension(""), PathBuf::from);
+    let written =
+        env::current_exe().and_then(|rlox| bundle::write(&rlox, &chunk.to_bytes(), &output));
+    if written.is_err() {
+        eprintln!("Could not write file '{}'", output.display());
+        std::process::exit(74);
+    }
+}
 
-    if !parse_result {
-        return InterpretResult::CompileError;
+/// Runs the bytecode a bundled executable carries. There is no source to show, runtime errors
+/// take their one line form.
+fn run_bundle(payload: &[u8]) {
+    let chunk = match Chunk::from_bytes(payload) {
+        Ok(chunk) => chunk,
+        Err(error) => {
+            eprintln!("Broken bundle: {}", error);
+            std::process::exit(65);
+        }
+    };
+    if let Err(error) = Vm::new().run(chunk) {
+        eprintln!("{}", error);
+        eprintln!("Runtime error");
+        std::process::exit(70);
     }
+}
 
-    vm.run()
-}
\ No newline at end of file
+fn explain(code: &str) {
+    if let Some(code) = ErrorCode::parse(code) {
+        println!("{}", code.explanation());
+    } else {
+        eprintln!("No error code '{}'", code);
+        std::process::exit(64);
+    }
+}
+
+fn interpret(vm: &mut Engine, name: &str, source: &str) -> Result<(), LoxError> {
+    let renderer = Renderer::new(name, source).colour(io::stderr().is_terminal());
+
+    // Each line of the REPL is a program of its own, only the globals carry over.
+    let result = vm.eval(source);
+    match &result {
+        Err(LoxError::Compile(diagnostics)) => {
+            report(&renderer, diagnostics);
+        }
+        Err(LoxError::Runtime(error)) => {
+            eprintln!("{}", renderer.render(error));
+            if let Some(code) = error.code {
+                eprintln!(
+                    "For more information about this error, try `rlox explain {}`.",
+                    code
+                );
+            }
+        }
+        Err(error) => eprintln!("{}", error),
+        Ok(()) => {}
+    }
+    result
+}
+
+/// Prints the diagnostics of a compilation, and where to read more about the first error.
+fn report(renderer: &Renderer, diagnostics: &[Diagnostic]) {
+    for diagnostic in diagnostics {
+        eprintln!("{}", renderer.render(diagnostic));
+    }
+    if let Some(code) = diagnostics.iter().find_map(|diagnostic| diagnostic.code) {
+        eprintln!(
+            "For more information about an error, try `rlox explain {}`.",
+            code
+        );
+    }
+}
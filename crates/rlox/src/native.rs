@@ -0,0 +1,49 @@
+use crate::embed::Vm;
+use crate::lox_error::LoxError;
+use crate::value::Value;
+use std::fmt;
+
+/// The Rust side of a native function, given the virtual machine that called it and the
+/// arguments, as many as the function's arity.
+pub type NativeFn = dyn Fn(&mut Vm, &[Value]) -> Result<Value, LoxError>;
+
+/// A function the host program defined in Rust for scripts to call, with `Vm::define_native`.
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    function: Box<NativeFn>,
+}
+
+impl Native {
+    pub fn new(name: impl Into<String>, arity: usize, function: Box<NativeFn>) -> Self {
+        Native {
+            name: name.into(),
+            arity,
+            function,
+        }
+    }
+
+    /// Calls the function, `args` already has `arity` values.
+    pub fn call(&self, vm: &mut Vm, args: &[Value]) -> Result<Value, LoxError> {
+        (self.function)(vm, args)
+    }
+
+    /// The message for calling the function with `count` arguments, if that is not its arity.
+    pub(crate) fn arity_mismatch(&self, count: usize) -> Option<String> {
+        (count != self.arity)
+            .then(|| format!("Expected {} arguments but got {}.", self.arity, count))
+    }
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Native({:?}, {})", self.name, self.arity)
+    }
+}
+
+/// Natives are only equal to themselves, two closures are never compared.
+impl PartialEq for Native {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
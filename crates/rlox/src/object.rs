@@ -0,0 +1,53 @@
+use crate::native::Native;
+use crate::value::Value;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Heap allocated values, shared between the constant pool and the stack through `Rc`.
+#[derive(Debug, PartialEq)]
+pub enum Obj {
+    String(String),
+    Native(Native),
+    List(Vec<Value>),
+    Map(HashMap<String, Value>),
+}
+
+impl fmt::Display for Obj {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Obj::String(string) => write!(f, "{}", string),
+            Obj::Native(native) => write!(f, "<native fn {}>", native.name),
+            Obj::List(values) => {
+                write!(f, "[")?;
+                for (index, value) in values.iter().enumerate() {
+                    let separator = if index > 0 { ", " } else { "" };
+                    write!(f, "{}{}", separator, Element(value))?;
+                }
+                write!(f, "]")
+            }
+            // Sorted, the order of a `HashMap` changes from run to run.
+            Obj::Map(entries) => {
+                let mut keys: Vec<&String> = entries.keys().collect();
+                keys.sort();
+                write!(f, "{{")?;
+                for (index, key) in keys.into_iter().enumerate() {
+                    let separator = if index > 0 { ", " } else { "" };
+                    write!(f, "{}\"{}\": {}", separator, key, Element(&entries[key]))?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+/// A value inside a list or a map, strings are quoted to tell `["a, b"]` from `["a", "b"]`.
+struct Element<'a>(&'a Value);
+
+impl fmt::Display for Element<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.as_str() {
+            Some(string) => write!(f, "\"{}\"", string),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
@@ -0,0 +1,536 @@
+use crate::chunk::{Chunk, Code, OpCode};
+use crate::diagnostic::Span;
+use crate::value::Value;
+
+/// An instruction of the chunk being optimized. Jumps point at instructions rather than at
+/// offsets, so instructions can be removed without breaking them.
+#[derive(Debug, Clone)]
+struct Instruction {
+    /// `Loop` is read as a `Jump` backward.
+    op: OpCode,
+    operand: Code,
+    /// The second operand of `AddLocals`.
+    second: Code,
+    /// For jumps, the index of the instruction jumped to.
+    target: usize,
+    span: Span,
+}
+
+/// Rewrites common instruction sequences of a finished chunk into cheaper ones: loads that are
+/// popped right away are dropped, jumps to jumps go straight to the final target, `Not` before
+/// a conditional jump flips the jump, negated number constants are negated in the pool and
+/// unreachable code is removed. Last, with `fuse`, the sequences that run most often are fused
+/// into superinstructions. Jump offsets and spans are recomputed, the chunk is left as it was if
+/// a jump no longer fits.
+pub fn optimize(chunk: &mut Chunk, fuse: bool) {
+    let mut instructions = decode(chunk);
+    loop {
+        let mut changed = thread_jumps(&mut instructions);
+        changed |= rewrite_pairs(&mut instructions, chunk);
+        changed |= remove_unreachable(&mut instructions);
+        if !changed {
+            break;
+        }
+    }
+    if fuse {
+        fuse_sequences(&mut instructions);
+    }
+
+    if let Some((code, spans)) = encode(&instructions) {
+        chunk.truncate(0, chunk.constant_count());
+        for (code, span) in code.into_iter().zip(spans) {
+            chunk.push_chunk(code, span);
+        }
+    }
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    // The index of the instruction at each offset, and of the end for the offset past it.
+    let mut indices = vec![0; chunk.code.len() + 1];
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        indices[offset] = instructions.len();
+        let op = chunk.get_op_code(offset);
+        let (op, operand, second, target) = if op.is_jump() {
+            let jump = chunk.read_u16(offset + 1) as usize;
+            match op {
+                OpCode::Loop => (OpCode::Jump, 0, 0, offset + 3 - jump),
+                op => (op, 0, 0, offset + 3 + jump),
+            }
+        } else {
+            let byte = |n: usize| {
+                if n <= op.operand_bytes() {
+                    chunk.code[offset + n]
+                } else {
+                    0
+                }
+            };
+            (op, byte(1), byte(2), 0)
+        };
+        instructions.push(Instruction {
+            op,
+            operand,
+            second,
+            target,
+            span: chunk.get_span(offset),
+        });
+        offset += 1 + op.operand_bytes();
+    }
+    indices[offset] = instructions.len();
+
+    for instruction in &mut instructions {
+        if instruction.op.is_jump() {
+            instruction.target = indices[instruction.target];
+        }
+    }
+    instructions
+}
+
+/// The code and the span of every byte, `None` when a jump is too long for its operand.
+fn encode(instructions: &[Instruction]) -> Option<(Vec<Code>, Vec<Span>)> {
+    let mut offsets = Vec::with_capacity(instructions.len() + 1);
+    let mut offset = 0;
+    for instruction in instructions {
+        offsets.push(offset);
+        offset += 1 + instruction.op.operand_bytes();
+    }
+    offsets.push(offset);
+
+    let mut code = Vec::with_capacity(offset);
+    let mut spans = Vec::with_capacity(offset);
+    for (index, instruction) in instructions.iter().enumerate() {
+        let mut op = instruction.op;
+        let mut operand = vec![instruction.operand, instruction.second];
+        if op.is_jump() {
+            let from = offsets[index] + 3;
+            let to = offsets[instruction.target];
+            let jump = if to >= from {
+                to - from
+            } else {
+                op = OpCode::Loop;
+                from - to
+            };
+            operand = u16::try_from(jump).ok()?.to_be_bytes().to_vec();
+        }
+        code.push(op as Code);
+        code.extend(&operand[..op.operand_bytes()]);
+        spans.resize(code.len(), instruction.span);
+    }
+    Some((code, spans))
+}
+
+/// Removes the instruction at `index`, jumps to it go to the instruction after it.
+fn remove(instructions: &mut Vec<Instruction>, index: usize) {
+    instructions.remove(index);
+    for instruction in instructions.iter_mut() {
+        if instruction.op.is_jump() && instruction.target > index {
+            instruction.target -= 1;
+        }
+    }
+}
+
+fn is_target(instructions: &[Instruction], index: usize) -> bool {
+    instructions
+        .iter()
+        .any(|instruction| instruction.op.is_jump() && instruction.target == index)
+}
+
+/// Jumps to an unconditional jump go to its target instead, a jump to the next instruction is
+/// dropped. Conditional jumps only move forward, there is no conditional `Loop`.
+fn thread_jumps(instructions: &mut Vec<Instruction>) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index < instructions.len() {
+        let instruction = &instructions[index];
+        if !instruction.op.is_jump() {
+            index += 1;
+            continue;
+        }
+        if instruction.op == OpCode::Jump && instruction.target == index + 1 {
+            remove(instructions, index);
+            changed = true;
+            continue;
+        }
+
+        let mut target = instruction.target;
+        let mut hops = 0;
+        // Bounded, the jumps might form a cycle.
+        while hops < instructions.len()
+            && target != index
+            && instructions
+                .get(target)
+                .is_some_and(|next| next.op == OpCode::Jump)
+        {
+            target = instructions[target].target;
+            hops += 1;
+        }
+        let backward = target <= index && instruction.op != OpCode::Jump;
+        if target != instruction.target && !backward {
+            instructions[index].target = target;
+            changed = true;
+        }
+        index += 1;
+    }
+    changed
+}
+
+/// Rewrites two instructions in a row, unless something jumps to the second one.
+fn rewrite_pairs(instructions: &mut Vec<Instruction>, chunk: &mut Chunk) -> bool {
+    let mut changed = false;
+    let mut index = 0;
+    while index + 1 < instructions.len() {
+        if is_target(instructions, index + 1) {
+            index += 1;
+            continue;
+        }
+        let first = &instructions[index];
+        let second = &instructions[index + 1];
+        match (first.op, second.op) {
+            // A value loaded only to be popped.
+            (
+                OpCode::Constant | OpCode::Nil | OpCode::True | OpCode::False | OpCode::GetLocal,
+                OpCode::Pop,
+            ) => {
+                remove(instructions, index + 1);
+                remove(instructions, index);
+                changed = true;
+                // The instruction before might load a value for the next `Pop` now.
+                index = index.saturating_sub(1);
+                continue;
+            }
+            (OpCode::Not, OpCode::JumpIfFalse | OpCode::JumpIfTrue) => {
+                instructions[index + 1].op = match second.op {
+                    OpCode::JumpIfFalse => OpCode::JumpIfTrue,
+                    _ => OpCode::JumpIfFalse,
+                };
+                remove(instructions, index);
+                changed = true;
+                continue;
+            }
+            (OpCode::Constant, OpCode::Negate) => {
+                let number = chunk
+                    .get_constant(first.operand as usize)
+                    .and_then(Value::as_number);
+                // The negated number needs a slot of its own in the pool.
+                if let (Some(number), Ok(constant)) =
+                    (number, Code::try_from(chunk.constant_count()))
+                {
+                    chunk.push_constant(Value::Number(-number));
+                    instructions[index].operand = constant;
+                    remove(instructions, index + 1);
+                    changed = true;
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    changed
+}
+
+/// Replaces the instruction sequences that run most often, as the `opcode_pairs` feature
+/// counts them, with a single superinstruction. Nothing may jump into the middle of one.
+fn fuse_sequences(instructions: &mut Vec<Instruction>) {
+    let mut index = 0;
+    while index + 1 < instructions.len() {
+        let ops = [index, index + 1, index + 2].map(|i| instructions.get(i).map(|i| i.op));
+        let jumped_into = |length: usize| (1..length).any(|i| is_target(instructions, index + i));
+        // The fused instruction, how many it replaces and the one whose span it reports.
+        let (op, length, span) = match ops {
+            [Some(OpCode::GetLocal), Some(OpCode::GetLocal), Some(OpCode::Add)] => {
+                (OpCode::AddLocals, 3, 2)
+            }
+            [Some(OpCode::SetLocal), Some(OpCode::Pop), _] => (OpCode::SetLocalPop, 2, 0),
+            [Some(OpCode::Constant), Some(OpCode::Add), _] => (OpCode::AddConstant, 2, 1),
+            [Some(OpCode::Less), Some(OpCode::JumpIfFalse), _] => (OpCode::JumpIfNotLess, 2, 0),
+            _ => {
+                index += 1;
+                continue;
+            }
+        };
+        if jumped_into(length) {
+            index += 1;
+            continue;
+        }
+
+        let fused = Instruction {
+            op,
+            operand: instructions[index].operand,
+            second: instructions[index + 1].operand,
+            target: instructions[index + 1].target,
+            span: instructions[index + span].span,
+        };
+        instructions[index] = fused;
+        for _ in 1..length {
+            remove(instructions, index + 1);
+        }
+        index += 1;
+    }
+}
+
+/// Removes the instructions that no path from the first one reaches, like code after a
+/// `Return` or jumped over by every branch.
+fn remove_unreachable(instructions: &mut Vec<Instruction>) -> bool {
+    let mut reachable = vec![false; instructions.len()];
+    let mut pending = vec![0];
+    while let Some(index) = pending.pop() {
+        if index >= instructions.len() || reachable[index] {
+            continue;
+        }
+        reachable[index] = true;
+        let instruction = &instructions[index];
+        match instruction.op {
+            OpCode::Return | OpCode::Eop => {}
+            OpCode::Jump => pending.push(instruction.target),
+            op if op.is_jump() => pending.extend([index + 1, instruction.target]),
+            _ => pending.push(index + 1),
+        }
+    }
+
+    let mut changed = false;
+    for index in (0..instructions.len()).rev() {
+        if !reachable[index] {
+            remove(instructions, index);
+            changed = true;
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{decode, optimize, Instruction};
+    use crate::chunk::{Chunk, OpCode};
+    use crate::compiler::{compile, OptLevel};
+    use crate::diagnostic::Span;
+    use crate::value::Value;
+    use crate::vm::VirtualMachine;
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    fn unoptimized(source: &str) -> Chunk {
+        let mut chunk = Chunk::new();
+        let diagnostics = compile(source, &mut chunk, OptLevel::None);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        chunk
+    }
+
+    fn optimized(source: &str) -> Vec<Instruction> {
+        let mut chunk = unoptimized(source);
+        optimize(&mut chunk, false);
+        decode(&chunk)
+    }
+
+    fn fused(source: &str) -> Vec<Instruction> {
+        let mut chunk = unoptimized(source);
+        optimize(&mut chunk, true);
+        decode(&chunk)
+    }
+
+    fn ops(instructions: &[Instruction]) -> Vec<OpCode> {
+        instructions
+            .iter()
+            .map(|instruction| instruction.op)
+            .collect()
+    }
+
+    #[test]
+    fn drop_values_popped_right_away() {
+        let source = "1; nil; true; { var a = 1; a; }\nprint 2;";
+        let instructions = optimized(source);
+        assert_eq!(
+            ops(&instructions),
+            [OpCode::Constant, OpCode::Print, OpCode::Return]
+        );
+        let two = source.rfind('2').expect("A constant");
+        assert_eq!(instructions[0].span, Span::new(two, two + 1, 2));
+    }
+
+    #[test]
+    fn thread_jumps_to_jumps() {
+        let source = "if (a) { if (b) print 1; else print 2; } else print 3;";
+        let unoptimized = decode(&unoptimized(source));
+        let instructions = optimized(source);
+
+        for instruction in &instructions {
+            if instruction.op.is_jump() {
+                let target = instructions.get(instruction.target);
+                assert!(target.is_none_or(|target| target.op != OpCode::Jump));
+            }
+        }
+        // The jump over the inner else branch now goes past the outer one as well.
+        let return_index = instructions.len() - 1;
+        let jumps_to_end = |instructions: &[Instruction]| {
+            let end = instructions.len() - 1;
+            instructions
+                .iter()
+                .filter(|i| i.op == OpCode::Jump && i.target == end)
+                .count()
+        };
+        assert_eq!(instructions[return_index].op, OpCode::Return);
+        assert_eq!(jumps_to_end(&unoptimized), 1);
+        assert_eq!(jumps_to_end(&instructions), 2);
+    }
+
+    #[test]
+    fn flip_jumps_on_negated_conditions() {
+        let instructions = optimized("if (!a) print 1;\nwhile (!!b) b = false;");
+        let ops = ops(&instructions);
+
+        assert!(!ops.contains(&OpCode::Not), "{:?}", ops);
+        assert_eq!(
+            ops.iter().filter(|op| **op == OpCode::JumpIfTrue).count(),
+            1
+        );
+        assert_eq!(
+            ops.iter().filter(|op| **op == OpCode::JumpIfFalse).count(),
+            1
+        );
+    }
+
+    #[test]
+    fn negate_constants_in_the_pool() {
+        let mut chunk = unoptimized("print 1;\nprint\n  -2;");
+        optimize(&mut chunk, false);
+        let instructions = decode(&chunk);
+
+        assert_eq!(
+            ops(&instructions),
+            [
+                OpCode::Constant,
+                OpCode::Print,
+                OpCode::Constant,
+                OpCode::Print,
+                OpCode::Return
+            ]
+        );
+        let constant = instructions[2].operand as usize;
+        assert_eq!(chunk.get_constant(constant), Some(&Value::Number(-2.0)));
+        let lines: Vec<usize> = instructions.iter().map(|i| i.span.line).collect();
+        assert_eq!(lines, [1, 1, 3, 2, 3]);
+    }
+
+    #[test]
+    fn remove_unreachable_code() {
+        let mut chunk = Chunk::new();
+        chunk.push_constant(Value::Number(1.0));
+        for (op, line) in [(OpCode::Nil, 1), (OpCode::Print, 1), (OpCode::Return, 1)] {
+            chunk.push_op_code(op, line);
+        }
+        for (op, line) in [(OpCode::True, 2), (OpCode::Print, 2), (OpCode::Return, 2)] {
+            chunk.push_op_code(op, line);
+        }
+        optimize(&mut chunk, false);
+
+        let mut expected = Chunk::new();
+        expected.push_constant(Value::Number(1.0));
+        for op in [OpCode::Nil, OpCode::Print, OpCode::Return] {
+            expected.push_op_code(op, 1);
+        }
+        assert_eq!(chunk, expected);
+    }
+
+    #[test]
+    fn remove_branches_that_are_never_taken() {
+        // Jumping over the else branch leaves nothing to jump over once it is gone.
+        let instructions = optimized("if (a) print 1; else {}");
+        assert!(!ops(&instructions).contains(&OpCode::Jump));
+    }
+
+    #[test]
+    fn fuse_frequent_sequences() {
+        let source = "{ var i = 0; var a = 1; while (i < 10) { i = i + 1; a = a + i; } print a; }";
+        let ops = ops(&fused(source));
+        for op in [
+            OpCode::AddLocals,
+            OpCode::AddConstant,
+            OpCode::SetLocalPop,
+            OpCode::JumpIfNotLess,
+        ] {
+            assert!(ops.contains(&op), "{:?} in {:?}", op, ops);
+        }
+        for op in [
+            OpCode::Add,
+            OpCode::Less,
+            OpCode::JumpIfFalse,
+            OpCode::SetLocal,
+        ] {
+            assert!(!ops.contains(&op), "{:?} in {:?}", op, ops);
+        }
+    }
+
+    #[test]
+    fn keep_jump_targets_apart() {
+        // `Add` is where `or` jumps to when `x` is true, it stays on its own.
+        let ops = ops(&fused("var x; print 1 + (x or 2);"));
+        assert!(ops.contains(&OpCode::Add), "{:?}", ops);
+        assert!(!ops.contains(&OpCode::AddConstant), "{:?}", ops);
+    }
+
+    /// `print` output shared with the test while the virtual machine owns it.
+    #[derive(Clone, Default)]
+    pub(crate) struct Output(pub(crate) Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// The output of `source` and the code of the error that stopped it, if any.
+    fn run(source: &str, level: OptLevel) -> (String, Option<String>) {
+        let output = Output::default();
+        let mut vm = VirtualMachine::with_output(output.clone());
+        let diagnostics = compile(source, &mut vm.chunks, level);
+        assert!(diagnostics.is_empty(), "{:?}", diagnostics);
+        vm.run();
+
+        let error = vm.take_error().and_then(|error| error.code);
+        let printed = String::from_utf8(output.0.take()).expect("Output is UTF-8");
+        (printed, error.map(|code| code.to_string()))
+    }
+
+    pub(crate) const PROGRAMS: [&str; 11] = [
+        "var i = 0; while (i < 5) { if (!(i == 2)) print i; i = i + 1; }",
+        "var a = 1; { var a = a0; }",
+        "{ var a = 1; { var b = a + 1; print b; a = b * -3; } print a; }",
+        "print nil or \"x\"; print 1 and 2; print false and 1; print !true or !false;",
+        "var n = 10; var sum = 0; while (n > 0) { if (n / 2 == 3) { print \"six\"; } else if (n < 3) print n; else { sum = sum + n; } n = n - 1; } print sum;",
+        "var s = \"\"; var i = 0; while (!(i >= 3)) { s = \"${s}${i}, \"; i = i + 1; } print s;",
+        "1; true; nil; -2; { var x = -1; x; -x; } print -(-3);",
+        "var a = 1; if (a) print -\"text\"; print \"unreachable\";",
+        "var x; print 1 + (x or 2); x = true; print 1 + (x or 2);",
+        "{ var a = \"a\"; var b = \"b\"; var i = 0; while (i < 3) { a = a + b; i = i + 0.5; } print a + \"!\"; }",
+        "{ var a = 1; var b = \"b\";\n print a\n + b; }",
+    ];
+
+    #[test]
+    fn optimized_programs_behave_the_same() {
+        for source in PROGRAMS {
+            let expected = run(source, OptLevel::None);
+            assert_eq!(run(source, OptLevel::Fold), expected, "{}", source);
+            assert_eq!(run(source, OptLevel::Peephole), expected, "{}", source);
+            assert_eq!(run(source, OptLevel::Fuse), expected, "{}", source);
+        }
+        assert_eq!(run(PROGRAMS[0], OptLevel::Peephole).0, "0\n1\n3\n4\n");
+        assert_eq!(
+            run(PROGRAMS[1], OptLevel::Peephole).1,
+            Some("E0018".to_string())
+        );
+        assert_eq!(
+            run(PROGRAMS[7], OptLevel::Peephole).1,
+            Some("E0015".to_string())
+        );
+        assert_eq!(
+            run(PROGRAMS[8], OptLevel::Fuse),
+            ("3\n".to_string(), Some("E0017".to_string()))
+        );
+    }
+}
@@ -1,354 +1,755 @@
-use std::collections::HashMap;
+use crate::ast::{BinaryOp, ErrorKind, LogicalOp, UnaryOp};
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error_code::ErrorCode;
+use crate::{scanner, Scanner, Token, TokenType};
 use std::str::FromStr;
-use crate::{Chunk, Code, OpCode, Scanner, scanner, Token, TokenType, Value};
+
+/// Receives the program from the parser in postfix order: the operands of a node, and the
+/// expression of a statement, are reported before the node itself. `Compiler` turns the events
+/// into bytecode as they come, `TreeBuilder` into a syntax tree.
+pub trait Builder {
+    fn nil(&mut self, span: Span);
+    fn boolean(&mut self, value: bool, span: Span);
+    fn number(&mut self, value: f64, span: Span);
+    fn string(&mut self, value: String, span: Span);
+    fn begin_interpolation(&mut self);
+    /// A literal part of the interpolation, `span` covers the token it is in.
+    fn interpolation_text(&mut self, text: String, span: Span);
+    /// The expression spanning `span` was interpolated.
+    fn interpolation_value(&mut self, span: Span);
+    fn end_interpolation(&mut self, span: Span);
+    fn grouping(&mut self, span: Span);
+    fn unary(&mut self, op: UnaryOp, operator: Span);
+    fn binary(&mut self, op: BinaryOp, operator: Span);
+    fn variable(&mut self, name: String, span: Span);
+    /// The callee and then `arg_count` arguments have been reported, `span` covers the
+    /// parentheses.
+    fn call(&mut self, arg_count: usize, span: Span);
+    /// The value assigned to `name` has been reported, `span` covers the whole assignment.
+    fn assignment(&mut self, name: String, span: Span);
+    /// The left operand of `and` or `or` has been reported, the right one follows.
+    fn logical_operator(&mut self, op: LogicalOp, operator: Span);
+    fn logical(&mut self, op: LogicalOp, operator: Span);
+    /// An expression is missing or broken, it wraps the last `children` expressions. The
+    /// parser has already reported why.
+    fn expression_error(&mut self, kind: ErrorKind, span: Span, children: usize);
+    fn print_statement(&mut self, span: Span);
+    fn expression_statement(&mut self, span: Span);
+    /// `var name`, reported before the initializer.
+    fn declare_variable(&mut self, name: String, span: Span);
+    /// The end of the declaration started by `declare_variable`, after its initializer if it
+    /// has one.
+    fn define_variable(&mut self, initialized: bool, span: Span);
+    fn begin_block(&mut self);
+    fn end_block(&mut self, span: Span);
+    /// The condition of an `if` spanning `span` has been reported, the then branch follows.
+    fn if_condition(&mut self, span: Span);
+    /// The then branch has been reported, the branch after the `else` at `span` follows.
+    fn if_else(&mut self, span: Span);
+    fn end_if(&mut self, has_else: bool, span: Span);
+    /// A loop starts, its condition follows.
+    fn begin_loop(&mut self);
+    /// The condition of the loop spanning `span` has been reported, the body follows.
+    fn loop_condition(&mut self, span: Span);
+    fn end_loop(&mut self, span: Span);
+    /// A declaration did not end well, it wraps the last `children` declarations.
+    fn declaration_error(&mut self, kind: ErrorKind, span: Span, children: usize);
+    /// The input ended at `span`, returns the builder's own diagnostics.
+    fn end(&mut self, span: Span) -> Vec<Diagnostic>;
+}
+
+/// How a statement ended.
+#[derive(PartialEq)]
+enum Ending {
+    Complete,
+    /// Without the closing token named, the next statement follows.
+    Missing(&'static str),
+    /// Tokens were skipped to find its end.
+    Skipped,
+}
 
 pub struct Parser<'a> {
     scanner: &'a mut Scanner<'a>,
     previous: Token<'a>,
     current: Token<'a>,
-    had_error: bool,
     panic_mode: bool,
-    chunk: &'a mut Chunk,
-    rules: HashMap<TokenType, ParseRule>,
-    last_error: String
+    /// How many blocks the current token is in.
+    blocks: usize,
+    builder: &'a mut dyn Builder,
+    diagnostics: Vec<Diagnostic>,
 }
 
 impl<'a> Parser<'a> {
-    pub fn new(scanner: &'a mut scanner::Scanner<'a>, chunk: &'a mut Chunk) -> Self {
+    pub fn new(scanner: &'a mut scanner::Scanner<'a>, builder: &'a mut dyn Builder) -> Self {
         Parser {
             previous: Token::default(),
             current: Token::default(),
             scanner,
-            had_error: false,
             panic_mode: false,
-            chunk,
-            rules: Parser::get_rules(),
-            last_error: "".to_owned()
+            blocks: 0,
+            builder,
+            diagnostics: Vec::new(),
         }
     }
 
-    pub fn parse(&mut self) -> bool {
+    /// Parses the whole program into the builder, returns every error found on the way.
+    /// The builder always gets a complete program, the parts that failed to parse become
+    /// error nodes. After an error, further errors are only reported from the next statement
+    /// on, so one mistake is reported once.
+    pub fn parse(&mut self) -> Vec<Diagnostic> {
         self.advance();
-        self.expression();
-        self.consume(TokenType::Eof, "Expect end of expression.");
-        self.end();
-        !self.had_error
+        while !self.match_token(TokenType::Eof) {
+            self.declaration();
+        }
+        let diagnostics = self.builder.end(self.previous.span());
+        self.diagnostics.extend(diagnostics);
+        self.diagnostics
+            .sort_by_key(|diagnostic| diagnostic.span.start);
+        std::mem::take(&mut self.diagnostics)
     }
 
-    fn get_rules() -> HashMap<TokenType, ParseRule> {
-        let mut map = HashMap::new();
-        use TokenType::*;
+    fn advance(&mut self) {
+        self.previous = self.current;
 
-        map.insert(
-            LeftParen,
-            ParseRule::new(ParseFn::Groping, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            RightParen,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            LeftBrace,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            RightBrace,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Comma,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Dot,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Minus,
-            ParseRule::new(ParseFn::Unary, ParseFn::Binary, Precedence::Term),
-        );
-        map.insert(
-            Plus,
-            ParseRule::new(ParseFn::None, ParseFn::Binary, Precedence::Term),
-        );
-        map.insert(
-            Semicolon,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Slash,
-            ParseRule::new(ParseFn::None, ParseFn::Binary, Precedence::Factor),
-        );
-        map.insert(
-            Star,
-            ParseRule::new(ParseFn::None, ParseFn::Binary, Precedence::Factor),
-        );
-        map.insert(
-            Bang,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            BangEqual,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Equal,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            EqualEqual,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Greater,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            GreaterEqual,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Less,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            LessEqual,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Identifier,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            String,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Number,
-            ParseRule::new(ParseFn::Number, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            And,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Class,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Else,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            False,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            For,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Fun,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            If,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Nil,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Or,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Print,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Return,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Super,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            This,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            True,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Var,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            While,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Error,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map.insert(
-            Eof,
-            ParseRule::new(ParseFn::None, ParseFn::None, Precedence::None),
-        );
-        map
+        // Doc comments are only of interest to documentation tools.
+        loop {
+            self.current = self.scanner.scan_token();
+            if self.current.kind != TokenType::DocComment {
+                break;
+            }
+        }
     }
 
-    fn emit_byte(&mut self, byte: Code) {
-        self.chunk.push_chunk(byte, self.previous.line);
+    fn expression(&mut self) {
+        self.parse_precedence(Precedence::Assignment)
     }
 
-    fn emit_bytes(&mut self, byte1: Code, byte2: Code) {
-        self.emit_byte(byte1);
-        self.emit_byte(byte2);
+    fn declaration(&mut self) {
+        let start = self.current.span();
+        let ending = if self.match_token(TokenType::Var) {
+            self.var_declaration()
+        } else {
+            self.statement()
+        };
+        self.wrap_statement(start, ending);
+
+        // Back in step with the grammar.
+        self.panic_mode = false;
     }
 
-    fn end(&mut self) {
-        self.emit_byte(OpCode::Return as Code);
+    /// The branch of an `if` or the body of a loop.
+    fn body(&mut self) {
+        let start = self.current.span();
+        let ending = self.statement();
+        self.wrap_statement(start, ending);
+    }
 
-        if cfg!(feature = "debug_print_code") && !self.had_error {
-            self.chunk.disassemble("code");
+    /// Puts a statement that did not end well into an error node.
+    fn wrap_statement(&mut self, start: Span, ending: Ending) {
+        let span = start.to(self.previous.span());
+        match ending {
+            Ending::Complete => {}
+            Ending::Missing(what) => {
+                self.builder
+                    .declaration_error(ErrorKind::Missing(what), span, 1)
+            }
+            Ending::Skipped => self
+                .builder
+                .declaration_error(ErrorKind::Unexpected, span, 1),
         }
     }
 
-    fn advance(&mut self) {
-        self.previous = self.current;
-
-        loop {
-            self.current = self.scanner.scan_token();
-            if self.current.kind != TokenType::Error {
-                break;
-            }
+    fn var_declaration(&mut self) -> Ending {
+        let start = self.previous.span();
+        if self.current.kind != TokenType::Identifier {
+            self.error_at_current(ErrorCode::ExpectVariableName, "Expect variable name.");
+            self.synchronize();
+            let span = start.to(self.previous.span());
+            self.builder
+                .declaration_error(ErrorKind::Missing("variable name"), span, 0);
+            return Ending::Complete;
+        }
+        self.advance();
+        self.builder
+            .declare_variable(self.previous.src.to_string(), self.previous.span());
 
-            self.error_at_current(self.current.src)
+        let initialized = self.match_token(TokenType::Equal);
+        if initialized {
+            self.expression();
         }
+        self.end_statement(
+            start,
+            "Expect ';' after variable declaration.",
+            |builder, span| builder.define_variable(initialized, span),
+        )
     }
 
-    fn expression(&mut self) {
-        self.parse_precedence(&Precedence::Assignment)
+    fn statement(&mut self) -> Ending {
+        if self.match_token(TokenType::Print) {
+            self.print_statement()
+        } else if self.match_token(TokenType::If) {
+            self.if_statement()
+        } else if self.match_token(TokenType::While) {
+            self.while_statement()
+        } else if self.match_token(TokenType::LeftBrace) {
+            self.block()
+        } else {
+            self.expression_statement()
+        }
     }
 
-    fn consume(&mut self, kind: TokenType, message: &str) {
-        if self.current.kind == kind {
-            self.advance()
+    fn block(&mut self) -> Ending {
+        let open = self.previous.span();
+        self.blocks += 1;
+        self.builder.begin_block();
+        while !matches!(self.current.kind, TokenType::RightBrace | TokenType::Eof) {
+            self.declaration();
+        }
+        self.blocks -= 1;
+
+        let ending = if self.match_token(TokenType::RightBrace) {
+            Ending::Complete
         } else {
-            self.error_at_current(message)
+            let error = Diagnostic::error(
+                ErrorCode::UnclosedBrace,
+                self.current.span(),
+                "Expect '}' after block.",
+            )
+            .with_note(format!("The '{{' to match is on line {}.", open.line));
+            self.report(located(error, self.current));
+            Ending::Missing("'}'")
+        };
+        self.builder.end_block(open.to(self.previous.span()));
+        ending
+    }
+
+    fn if_statement(&mut self) -> Ending {
+        let start = self.previous.span();
+        let condition = self.condition("if");
+        self.builder.if_condition(condition);
+        self.body();
+
+        let has_else = self.match_token(TokenType::Else);
+        if has_else {
+            self.builder.if_else(self.previous.span());
+            self.body();
         }
+        self.builder
+            .end_if(has_else, start.to(self.previous.span()));
+        Ending::Complete
     }
 
-    fn error_at_current(&mut self, message: &str) {
-        self.error_at(self.previous, message)
+    fn while_statement(&mut self) -> Ending {
+        let start = self.previous.span();
+        self.builder.begin_loop();
+        let condition = self.condition("while");
+        self.builder.loop_condition(condition);
+        self.body();
+        self.builder.end_loop(start.to(self.previous.span()));
+        Ending::Complete
     }
 
-    fn error_at(&mut self, token: Token, message: &str) {
-        if self.panic_mode {
-            return;
+    /// The parenthesised condition after `keyword`, returns its span without the parentheses.
+    fn condition(&mut self, keyword: &str) -> Span {
+        let open = self.match_token(TokenType::LeftParen);
+        if !open {
+            self.error_at_current(
+                ErrorCode::ExpectParen,
+                &format!("Expect '(' after '{}'.", keyword),
+            );
+        }
+        let start = self.current.span();
+        self.expression();
+        let span = start.to(self.previous.span());
+
+        let close = self.match_token(TokenType::RightParen);
+        if !close {
+            self.error_at_current(ErrorCode::ExpectParen, "Expect ')' after condition.");
         }
+        if !open {
+            self.builder
+                .expression_error(ErrorKind::Missing("'('"), span, 1);
+        } else if !close {
+            self.builder
+                .expression_error(ErrorKind::Missing("')'"), span, 1);
+        }
+        span
+    }
 
-        self.panic_mode = true;
-        let line_prefix = format!("[line {}] Error", token.line);
+    fn print_statement(&mut self) -> Ending {
+        let start = self.previous.span();
+        self.expression();
+        self.end_statement(start, "Expect ';' after value.", |builder, span| {
+            builder.print_statement(span)
+        })
+    }
 
-        let token = if token.kind == TokenType::Eof {
-            " at end".to_owned()
-        } else if token.kind == TokenType::Error {
-            todo!()
+    fn expression_statement(&mut self) -> Ending {
+        let start = self.current.span();
+        self.expression();
+        self.end_statement(start, "Expect ';' after expression.", |builder, span| {
+            builder.expression_statement(span)
+        })
+    }
+
+    /// Finds the end of the statement started at `start` and reports it with `statement`, any
+    /// tokens skipped on the way are left out of its span.
+    fn end_statement(
+        &mut self,
+        start: Span,
+        message: &str,
+        statement: impl FnOnce(&mut dyn Builder, Span),
+    ) -> Ending {
+        let end = self.previous.span();
+        let ending = self.statement_ending(start, message);
+        let end = if ending == Ending::Complete {
+            self.previous.span()
         } else {
-            format!(" at '{}'", token.src)
+            end
         };
+        statement(self.builder, start.to(end));
+        ending
+    }
 
-        self.had_error = true;
-        self.last_error = format!("{}{}: {}", line_prefix, token, message);
+    fn statement_ending(&mut self, start: Span, message: &str) -> Ending {
+        if self.match_token(TokenType::Semicolon) {
+            return Ending::Complete;
+        }
 
-        eprintln!("{}", self.last_error);
+        // A statement that consumed nothing has no line of its own to end on.
+        let line = self.previous.line;
+        let next_line = self.current.span().line > line && self.current.span() != start;
+        if self.current.kind != TokenType::Error && next_line {
+            // The statement most likely ended on the previous line.
+            let error = Diagnostic::error(ErrorCode::ExpectSemicolon, self.current.span(), message)
+                .with_help(format!(
+                    "Add ';' after '{}' on line {}.",
+                    self.previous.src, line
+                ));
+            self.report(error);
+        } else {
+            self.error_at_current(ErrorCode::ExpectSemicolon, message);
+        }
+
+        if next_line
+            || self.current.kind == TokenType::Eof
+            || self.starts_statement()
+            || self.at_block_end()
+        {
+            return Ending::Missing("';'");
+        }
+        self.synchronize();
+        Ending::Skipped
     }
 
-    fn number(&mut self) {
-        let value: Value = Value::from_str(self.previous.src).unwrap();
-        self.emit_constant(value)
+    /// Skips the rest of a statement that went wrong, up to and including its `;`, or to
+    /// something that looks like the start of the next statement.
+    fn synchronize(&mut self) {
+        while self.current.kind != TokenType::Eof
+            && !self.starts_statement()
+            && !self.at_block_end()
+        {
+            self.advance();
+            if self.previous.kind == TokenType::Semicolon {
+                return;
+            }
+        }
+    }
+
+    fn starts_statement(&self) -> bool {
+        use TokenType::*;
+        matches!(
+            self.current.kind,
+            Class | Fun | Var | For | If | While | Print | Return
+        )
+    }
+
+    /// The `}` of an enclosing block, a stray one is skipped like any other token.
+    fn at_block_end(&self) -> bool {
+        self.current.kind == TokenType::RightBrace && self.blocks > 0
     }
 
-    fn emit_constant(&mut self, value: Value) {
-        let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant as Code, constant)
+    /// A `}` continuing the string around an interpolated expression.
+    fn at_interpolation_end(&self) -> bool {
+        matches!(
+            self.current.kind,
+            TokenType::String | TokenType::Interpolation
+        ) && self.current.src.starts_with('}')
     }
-    fn make_constant(&mut self, value: Value) -> Code {
-        let constant_idx = self.chunk.push_constant(value);
-        if constant_idx > u8::MAX.into() {
-            self.error_at_current("Too many constants in one chunk")
+
+    /// Tokens that close or follow an expression, where a broken expression stops skipping.
+    fn at_recovery_point(&self) -> bool {
+        match self.current.kind {
+            TokenType::Semicolon
+            | TokenType::RightParen
+            | TokenType::RightBrace
+            | TokenType::Eof => true,
+            _ => self.starts_statement() || self.at_interpolation_end(),
         }
-        constant_idx.try_into().unwrap()
     }
 
-    fn parse_precedence(&mut self, precedence: &Precedence) {
-        self.advance();
-        let token = self.previous.kind;
-        let prefix_rule = self.get_rule(&token);
+    /// Returns whether anything was skipped.
+    fn skip_to_recovery_point(&mut self) -> bool {
+        let mut skipped = false;
+        while !self.at_recovery_point() {
+            self.advance();
+            skipped = true;
+        }
+        skipped
+    }
+
+    fn match_token(&mut self, kind: TokenType) -> bool {
+        if self.current.kind == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn error(&mut self, code: ErrorCode, message: &str) {
+        self.error_at(self.previous, code, message)
+    }
+
+    fn error_at_current(&mut self, code: ErrorCode, message: &str) {
+        self.error_at(self.current, code, message)
+    }
+
+    /// Error tokens carry the scanner's complaint, which explains the problem better than
+    /// whatever the parser expected in their place.
+    fn error_at(&mut self, token: Token, code: ErrorCode, message: &str) {
+        let diagnostic = match token.error {
+            Some(error) => Diagnostic::error(error.code(), token.span(), error.to_string()),
+            None => Diagnostic::error(code, token.span(), message),
+        };
+        self.report(located(diagnostic, token));
+    }
+
+    /// Records `diagnostic`, errors are dropped while recovering from the previous one.
+    fn report(&mut self, diagnostic: Diagnostic) {
+        if diagnostic.is_error() {
+            if self.panic_mode {
+                return;
+            }
+            self.panic_mode = true;
+        }
+        self.diagnostics.push(diagnostic);
+    }
+
+    fn number(&mut self) {
+        match parse_number(self.previous.src) {
+            Some(value) => self.builder.number(value, self.previous.span()),
+            None => {
+                self.error(ErrorCode::NumberOutOfRange, "Invalid number literal.");
+                self.builder
+                    .expression_error(ErrorKind::Invalid, self.previous.span(), 0)
+            }
+        }
+    }
+
+    fn literal(&mut self) {
+        match self.previous.kind {
+            TokenType::Nil => self.builder.nil(self.previous.span()),
+            TokenType::True => self.builder.boolean(true, self.previous.span()),
+            TokenType::False => self.builder.boolean(false, self.previous.span()),
+            _ => {}
+        }
+    }
+
+    fn string(&mut self) {
+        let segment = self.previous.src;
+        self.builder.string(
+            unescape(&segment[1..segment.len() - 1]),
+            self.previous.span(),
+        )
+    }
+
+    /// `"a ${b} c"` arrives as an `Interpolation` token for `"a ${`, the expression `b`, and
+    /// the `String` token `} c"`.
+    fn interpolation(&mut self) {
+        let start = self.previous.span();
+        let mut skipped = false;
+        let mut closed = false;
+        self.builder.begin_interpolation();
+        loop {
+            let segment = self.previous.src;
+            self.builder.interpolation_text(
+                unescape(&segment[1..segment.len() - 2]),
+                self.previous.span(),
+            );
+
+            let value_start = self.current.span();
+            self.expression();
+            self.builder
+                .interpolation_value(value_start.to(self.previous.span()));
+
+            if !self.at_interpolation_end() {
+                self.error_at_current(
+                    ErrorCode::UnterminatedInterpolation,
+                    "Expect end of string interpolation.",
+                );
+                skipped |= self.skip_to_recovery_point();
+                if !self.at_interpolation_end() {
+                    break;
+                }
+            }
+            if !self.match_token(TokenType::Interpolation) {
+                self.advance();
+                let segment = self.previous.src;
+                self.builder.interpolation_text(
+                    unescape(&segment[1..segment.len() - 1]),
+                    self.previous.span(),
+                );
+                closed = true;
+                break;
+            }
+        }
 
-        self.execute_parse_fn(&prefix_rule.prefix, true);
+        let span = start.to(self.previous.span());
+        self.builder.end_interpolation(span);
+        if !closed {
+            self.builder
+                .expression_error(ErrorKind::Missing("'}'"), span, 1);
+        } else if skipped {
+            self.builder
+                .expression_error(ErrorKind::Unexpected, span, 1);
+        }
+    }
 
-        while precedence <= &self.get_rule(&self.current.kind).precedence {
+    fn parse_precedence(&mut self, precedence: Precedence) {
+        let start = self.current.span();
+        let can_assign = precedence <= Precedence::Assignment;
+        match get_rule(self.current.kind).prefix {
+            Some(prefix) if !self.at_interpolation_end() => {
+                self.advance();
+                prefix(self, can_assign);
+            }
+            _ => self.expression_expected(),
+        }
+
+        while precedence <= get_rule(self.current.kind).precedence {
             self.advance();
-            let infix_rule = &self.get_rule(&self.previous.kind).infix;
-            self.execute_parse_fn(infix_rule, false)
+            let infix = get_rule(self.previous.kind)
+                .infix
+                .expect("Missing infix rule");
+            infix(self, can_assign);
+        }
+
+        // A variable would have taken the `=` itself.
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.error(
+                ErrorCode::InvalidAssignmentTarget,
+                "Invalid assignment target.",
+            );
+            self.expression();
+            self.builder
+                .expression_error(ErrorKind::Invalid, start.to(self.previous.span()), 2);
         }
     }
 
+    /// Stands in an error node for the expression that should start at the current token.
+    fn expression_expected(&mut self) {
+        self.error_at_current(ErrorCode::ExpectExpression, "Expect expression.");
+
+        if self.at_recovery_point() {
+            // Leave the token to whatever it closes.
+            let at = self.current.span();
+            let span = Span::new(at.start, at.start, at.line);
+            return self
+                .builder
+                .expression_error(ErrorKind::Missing("expression"), span, 0);
+        }
+
+        self.advance();
+        let kind = match self.previous.kind {
+            TokenType::Error => ErrorKind::Invalid,
+            _ => ErrorKind::Unexpected,
+        };
+        self.builder.expression_error(kind, self.previous.span(), 0);
+    }
+
     fn grouping(&mut self) {
+        let open = self.previous;
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression.")
+        if self.match_token(TokenType::RightParen) {
+            return self.builder.grouping(open.span().to(self.previous.span()));
+        }
+
+        let error = Diagnostic::error(
+            ErrorCode::UnclosedParen,
+            self.current.span(),
+            "Expect ')' after expression.",
+        )
+        .with_note(format!("The '(' to match is on line {}.", open.span().line));
+        self.report(located(error, self.current));
+
+        let kind = if self.skip_to_recovery_point() && self.match_token(TokenType::RightParen) {
+            ErrorKind::Unexpected
+        } else {
+            ErrorKind::Missing("')'")
+        };
+        self.builder
+            .expression_error(kind, open.span().to(self.previous.span()), 1);
+    }
+
+    /// `self.previous` is the `(` after the callee.
+    fn call(&mut self) {
+        let open = self.previous;
+        let mut arg_count = 0;
+        if self.current.kind != TokenType::RightParen {
+            loop {
+                if arg_count == 255 {
+                    self.error_at_current(
+                        ErrorCode::TooManyArguments,
+                        "Can't have more than 255 arguments.",
+                    );
+                }
+                self.expression();
+                arg_count += 1;
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        if self.match_token(TokenType::RightParen) {
+            return self
+                .builder
+                .call(arg_count, open.span().to(self.previous.span()));
+        }
+
+        let error = Diagnostic::error(
+            ErrorCode::UnclosedParen,
+            self.current.span(),
+            "Expect ')' after arguments.",
+        )
+        .with_note(format!("The '(' to match is on line {}.", open.span().line));
+        self.report(error);
+
+        let kind = if self.skip_to_recovery_point() && self.match_token(TokenType::RightParen) {
+            ErrorKind::Unexpected
+        } else {
+            ErrorKind::Missing("')'")
+        };
+        self.builder
+            .expression_error(kind, open.span().to(self.previous.span()), arg_count + 1);
+    }
+
+    fn variable(&mut self, can_assign: bool) {
+        let name = self.previous.src.to_string();
+        let span = self.previous.span();
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.expression();
+            self.builder.assignment(name, span.to(self.previous.span()));
+        } else {
+            self.builder.variable(name, span);
+        }
+    }
+
+    /// `and` and `or` short-circuit, the right operand is only evaluated when it decides the result.
+    fn logical(&mut self) {
+        let op_kind = self.previous.kind;
+        let operator = self.previous.span();
+        let op = if op_kind == TokenType::And {
+            LogicalOp::And
+        } else {
+            LogicalOp::Or
+        };
+
+        self.builder.logical_operator(op, operator);
+        self.parse_precedence(get_rule(op_kind).get_next_precedence());
+        self.builder.logical(op, operator);
     }
 
     fn unary(&mut self) {
         let op_kind = self.previous.kind;
+        let operator = self.previous.span();
 
-        self.expression();
+        self.parse_precedence(Precedence::Unary);
 
-        if op_kind == TokenType::Minus { self.emit_byte(OpCode::Negate as Code) }
+        match op_kind {
+            TokenType::Minus => self.builder.unary(UnaryOp::Negate, operator),
+            TokenType::Bang => self.builder.unary(UnaryOp::Not, operator),
+            _ => {}
+        }
     }
 
     fn binary(&mut self) {
         let op_kind = self.previous.kind;
-        let rule = self.get_rule(&op_kind);
-        self.parse_precedence(&rule.get_next_precedence());
+        let operator = self.previous.span();
+        let rule = get_rule(op_kind);
+        self.parse_precedence(rule.get_next_precedence());
 
         match op_kind {
-            TokenType::Plus => self.emit_byte(OpCode::Add as Code),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract as Code),
-            TokenType::Star => self.emit_byte(OpCode::Multiply as Code),
-            TokenType::Slash => self.emit_byte(OpCode::Divide as Code),
+            TokenType::Plus => self.builder.binary(BinaryOp::Add, operator),
+            TokenType::Minus => self.builder.binary(BinaryOp::Subtract, operator),
+            TokenType::Star => self.builder.binary(BinaryOp::Multiply, operator),
+            TokenType::Slash => self.builder.binary(BinaryOp::Divide, operator),
+            TokenType::EqualEqual => self.builder.binary(BinaryOp::Equal, operator),
+            TokenType::BangEqual => self.builder.binary(BinaryOp::NotEqual, operator),
+            TokenType::Greater => self.builder.binary(BinaryOp::Greater, operator),
+            TokenType::GreaterEqual => self.builder.binary(BinaryOp::GreaterEqual, operator),
+            TokenType::Less => self.builder.binary(BinaryOp::Less, operator),
+            TokenType::LessEqual => self.builder.binary(BinaryOp::LessEqual, operator),
             _ => {},
         }
     }
+}
 
-    fn get_rule(&self, token_type: &TokenType) -> ParseRule {
-        *self.rules.get(token_type).expect("Missing parser rule")
+/// Names the token `diagnostic` was reported at, ` at 'x'` or ` at end` in the one line form.
+fn located(diagnostic: Diagnostic, token: Token) -> Diagnostic {
+    match token.kind {
+        TokenType::Eof => diagnostic.with_location("end"),
+        // The lexical error message already describes the offending text.
+        TokenType::Error => diagnostic,
+        _ => diagnostic.with_location(format!("'{}'", token.src)),
     }
+}
 
-    fn execute_parse_fn(&mut self, fun: &ParseFn, strict: bool) {
-        match fun {
-            ParseFn::None => {
-                if strict {
-                    self.error_at_current("Expect expression.")
-                }
+/// Converts a number literal accepted by the scanner, `None` if it does not fit into an `f64`.
+fn parse_number(src: &str) -> Option<f64> {
+    let digits: String = src.chars().filter(|c| *c != '_').collect();
+    let radix = match digits.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0o" | "0O") => 8,
+        Some("0b" | "0B") => 2,
+        _ => {
+            return f64::from_str(&digits)
+                .ok()
+                .filter(|value| value.is_finite())
+        }
+    };
+    u64::from_str_radix(&digits[2..], radix)
+        .ok()
+        .map(|value| value as f64)
+}
+
+/// Decodes the escape sequences of a string literal the scanner has already validated.
+fn unescape(src: &str) -> String {
+    let mut result = String::with_capacity(src.len());
+    let mut chars = src.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('u') => {
+                let digits: String = chars.by_ref().skip(1).take_while(|c| *c != '}').collect();
+                let code = u32::from_str_radix(&digits, 16)
+                    .ok()
+                    .and_then(char::from_u32);
+                result.push(code.unwrap_or(char::REPLACEMENT_CHARACTER));
             }
-            ParseFn::Groping => self.grouping(),
-            ParseFn::Unary => self.unary(),
-            ParseFn::Binary => self.binary(),
-            ParseFn::Number => self.number(),
+            Some(c) => result.push(c),
+            None => {}
         }
     }
+    result
 }
 
-
-
 #[repr(u8)]
 #[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
 #[allow(dead_code)]
@@ -366,24 +767,20 @@ enum Precedence {
     Primary,
 }
 
-#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
-enum ParseFn {
-    None,
-    Groping,
-    Unary,
-    Binary,
-    Number,
-}
+/// Takes whether the expression may be the target of an assignment.
+type ParseFn = fn(&mut Parser, bool);
 
-#[derive(Debug, PartialEq, PartialOrd, Copy, Clone)]
+#[derive(Copy, Clone)]
 struct ParseRule {
-    prefix: ParseFn,
-    infix: ParseFn,
+    prefix: Option<ParseFn>,
+    infix: Option<ParseFn>,
     precedence: Precedence,
 }
 
 impl ParseRule {
-    fn new(prefix: ParseFn, infix: ParseFn, precedence: Precedence) -> Self {
+    const NONE: ParseRule = ParseRule::new(None, None, Precedence::None);
+
+    const fn new(prefix: Option<ParseFn>, infix: Option<ParseFn>, precedence: Precedence) -> Self {
         ParseRule {
             prefix,
             infix,
@@ -403,39 +800,475 @@ impl ParseRule {
     }
 }
 
+const RULE_COUNT: usize = TokenType::Init as usize + 1;
+
+/// Pratt parser rules indexed by `TokenType`, token types not listed here can't start or
+/// continue an expression.
+static RULES: [ParseRule; RULE_COUNT] = {
+    use TokenType::*;
+
+    // Closures rather than `Parser::grouping`, a method path is tied to one `Parser<'a>`.
+    let grouping: ParseFn = |parser, _| parser.grouping();
+    let call: ParseFn = |parser, _| parser.call();
+    let unary: ParseFn = |parser, _| parser.unary();
+    let binary: ParseFn = |parser, _| parser.binary();
+    let logical: ParseFn = |parser, _| parser.logical();
+    let variable: ParseFn = |parser, can_assign| parser.variable(can_assign);
+    let literal: ParseFn = |parser, _| parser.literal();
+    let number: ParseFn = |parser, _| parser.number();
+    let string: ParseFn = |parser, _| parser.string();
+    let interpolation: ParseFn = |parser, _| parser.interpolation();
 
+    let mut rules = [ParseRule::NONE; RULE_COUNT];
+    rules[LeftParen as usize] = ParseRule::new(Some(grouping), Some(call), Precedence::Call);
+    rules[Minus as usize] = ParseRule::new(Some(unary), Some(binary), Precedence::Term);
+    rules[Plus as usize] = ParseRule::new(None, Some(binary), Precedence::Term);
+    rules[Slash as usize] = ParseRule::new(None, Some(binary), Precedence::Factor);
+    rules[Star as usize] = ParseRule::new(None, Some(binary), Precedence::Factor);
+    rules[Bang as usize] = ParseRule::new(Some(unary), None, Precedence::None);
+    rules[BangEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Equality);
+    rules[EqualEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Equality);
+    rules[Greater as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[GreaterEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[Less as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[LessEqual as usize] = ParseRule::new(None, Some(binary), Precedence::Comparison);
+    rules[Identifier as usize] = ParseRule::new(Some(variable), None, Precedence::None);
+    rules[String as usize] = ParseRule::new(Some(string), None, Precedence::None);
+    rules[Interpolation as usize] = ParseRule::new(Some(interpolation), None, Precedence::None);
+    rules[Number as usize] = ParseRule::new(Some(number), None, Precedence::None);
+    rules[And as usize] = ParseRule::new(None, Some(logical), Precedence::And);
+    rules[False as usize] = ParseRule::new(Some(literal), None, Precedence::None);
+    rules[Nil as usize] = ParseRule::new(Some(literal), None, Precedence::None);
+    rules[Or as usize] = ParseRule::new(None, Some(logical), Precedence::Or);
+    rules[True as usize] = ParseRule::new(Some(literal), None, Precedence::None);
+    rules
+};
+
+fn get_rule(kind: TokenType) -> &'static ParseRule {
+    &RULES[kind as usize]
+}
 
 #[cfg(test)]
 mod tests {
-    use crate::{Scanner, Parser, Chunk, OpCode};
+    use super::{parse_number, unescape};
+    use crate::compiler::{compile, OptLevel};
+    use crate::diagnostic::{Diagnostic, Span};
+    use crate::{Chunk, OpCode, Value};
 
-    fn parse(source: &str) -> (bool, String, Chunk) {
+    fn parse(source: &str) -> (Vec<Diagnostic>, Chunk) {
         let mut chunks = Chunk::new();
-        let mut scanner = Scanner::new(source);
-        let mut parser = Parser::new(&mut scanner, &mut chunks);
-        let parse_result = parser.parse();
-        (parse_result, parser.last_error, chunks)
+        let diagnostics = compile(source, &mut chunks, OptLevel::None);
+        (diagnostics, chunks)
+    }
+
+    fn messages(source: &str) -> Vec<String> {
+        let (diagnostics, _) = parse(source);
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.to_string())
+            .collect()
     }
 
     #[test]
     fn parse_empty_source() {
-        let (result, last_error, _) = parse("");
+        let (diagnostics, chunks) = parse("");
+
+        let mut expected_chunks = Chunk::new();
+        expected_chunks.push_op_code(OpCode::Return, 1);
 
-        assert_eq!(result, false);
-        assert_eq!(last_error, "[line 1] Error at end: Expect expression.")
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks, expected_chunks)
     }
 
     #[test]
-    fn parse_one_constant () {
-        let (result, _, chunks) = parse("42");
+    fn parse_missing_expression() {
+        assert_eq!(
+            messages("print;"),
+            ["[line 1] Error[E0009] at ';': Expect expression."]
+        );
+    }
+
+    #[test]
+    fn parse_one_constant() {
+        let (diagnostics, chunks) = parse("42;");
 
         let mut expected_chunks = Chunk::new();
         expected_chunks.push_op_code(OpCode::Constant, 1);
         expected_chunks.push_chunk(0, 1); // index of the constant
-        expected_chunks.push_constant(42.0);
+        expected_chunks.push_constant(Value::Number(42.0));
+        expected_chunks.push_op_code(OpCode::Pop, 1);
         expected_chunks.push_op_code(OpCode::Return, 1);
 
-        assert_eq!(result, true);
+        assert!(diagnostics.is_empty());
         assert_eq!(chunks, expected_chunks)
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn parse_print_statements() {
+        let (diagnostics, chunks) = parse("print 1;\nprint 2;");
+
+        let mut expected_chunks = Chunk::new();
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(0, 1);
+        expected_chunks.push_op_code(OpCode::Print, 1);
+        expected_chunks.push_op_code(OpCode::Constant, 2);
+        expected_chunks.push_chunk(1, 2);
+        expected_chunks.push_op_code(OpCode::Print, 2);
+        expected_chunks.push_op_code(OpCode::Return, 2);
+        expected_chunks.push_constant(Value::Number(1.0));
+        expected_chunks.push_constant(Value::Number(2.0));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks, expected_chunks)
+    }
+
+    #[test]
+    fn parse_skips_comments() {
+        let (diagnostics, chunks) = parse("/// The answer\n/* to /* everything */ */ 42; // !");
+
+        let mut expected_chunks = Chunk::new();
+        expected_chunks.push_op_code(OpCode::Constant, 2);
+        expected_chunks.push_chunk(0, 2);
+        expected_chunks.push_constant(Value::Number(42.0));
+        expected_chunks.push_op_code(OpCode::Pop, 2);
+        expected_chunks.push_op_code(OpCode::Return, 2);
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks, expected_chunks)
+    }
+
+    #[test]
+    fn parse_string_escapes() {
+        let (diagnostics, chunks) = parse(r#"print "tab\t\"quoted\" \\ \u{1F600}\u{e9} \${}";"#);
+
+        let mut expected_chunks = Chunk::new();
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(0, 1);
+        expected_chunks.push_constant(Value::string("tab\t\"quoted\" \\ \u{1F600}\u{e9} ${}"));
+        expected_chunks.push_op_code(OpCode::Print, 1);
+        expected_chunks.push_op_code(OpCode::Return, 1);
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks, expected_chunks)
+    }
+
+    #[test]
+    fn parse_string_interpolation() {
+        let (diagnostics, chunks) = parse(r#"print "Hello ${"a" + "b"}! ${1}";"#);
+
+        let mut expected_chunks = Chunk::new();
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(0, 1);
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(1, 1);
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(2, 1);
+        expected_chunks.push_op_code(OpCode::Add, 1);
+        expected_chunks.push_op_code(OpCode::Stringify, 1);
+        expected_chunks.push_op_code(OpCode::Add, 1);
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(3, 1);
+        expected_chunks.push_op_code(OpCode::Add, 1);
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(4, 1);
+        expected_chunks.push_op_code(OpCode::Stringify, 1);
+        expected_chunks.push_op_code(OpCode::Add, 1);
+        expected_chunks.push_op_code(OpCode::Print, 1);
+        expected_chunks.push_op_code(OpCode::Return, 1);
+        expected_chunks.push_constant(Value::string("Hello "));
+        expected_chunks.push_constant(Value::string("a"));
+        expected_chunks.push_constant(Value::string("b"));
+        expected_chunks.push_constant(Value::string("! "));
+        expected_chunks.push_constant(Value::Number(1.0));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks, expected_chunks)
+    }
+
+    #[test]
+    fn parse_unterminated_interpolation() {
+        assert_eq!(
+            messages(r#"print "a ${1"#),
+            ["[line 1] Error[E0012] at end: Expect end of string interpolation."]
+        );
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert_eq!(unescape(r"plain"), "plain");
+        assert_eq!(unescape(r"a\nb\tc\rd"), "a\nb\tc\rd");
+        assert_eq!(unescape(r#"\"\\\$"#), "\"\\$");
+        assert_eq!(unescape(r"\u{41}\u{10FFFF}"), "A\u{10FFFF}");
+    }
+
+    #[test]
+    fn parse_number_literals() {
+        assert_eq!(parse_number("42"), Some(42.0));
+        assert_eq!(parse_number("1_000.5"), Some(1000.5));
+        assert_eq!(parse_number("1e-9"), Some(1e-9));
+        assert_eq!(parse_number("2.5E+3"), Some(2500.0));
+        assert_eq!(parse_number("0xff_FF"), Some(65535.0));
+        assert_eq!(parse_number("0o17"), Some(15.0));
+        assert_eq!(parse_number("0b1010"), Some(10.0));
+        assert_eq!(parse_number("1e400"), None);
+        assert_eq!(parse_number("0x1_0000_0000_0000_0000"), None);
+    }
+
+    #[test]
+    fn parse_too_large_number() {
+        assert_eq!(
+            messages("1e400;"),
+            ["[line 1] Error[E0013] at '1e400': Invalid number literal."]
+        );
+    }
+
+    #[test]
+    fn parse_malformed_number() {
+        assert_eq!(
+            messages("0b102;"),
+            ["[line 1] Error[E0008]: Invalid digit in number literal."]
+        );
+    }
+
+    #[test]
+    fn parse_reports_lex_errors() {
+        let cases = [
+            ("#", "[line 1] Error[E0001]: Unexpected character '#'."),
+            ("\n\"abc\n\n", "[line 2] Error[E0002]: Unterminated string."),
+            (
+                "1 + /* \n",
+                "[line 1] Error[E0003]: Unterminated block comment.",
+            ),
+            (
+                "\"a\nb\\qc\";",
+                "[line 2] Error[E0004]: Invalid escape sequence '\\q'.",
+            ),
+            ("\"\\", "[line 1] Error[E0002]: Unterminated string."),
+            (
+                "0x;",
+                "[line 1] Error[E0005]: Missing digits in number literal.",
+            ),
+            ("1e+;", "[line 1] Error[E0006]: Missing exponent digits."),
+            ("1__0;", "[line 1] Error[E0007]: Invalid digit separator."),
+            (
+                "0b12;",
+                "[line 1] Error[E0008]: Invalid digit in number literal.",
+            ),
+        ];
+        for (source, message) in cases {
+            assert_eq!(messages(source), [message], "{}", source);
+        }
+    }
+
+    #[test]
+    fn parse_error_spans() {
+        let (diagnostics, _) = parse("print 1 +;\n\"x\\q\";\nprint (1;");
+
+        let spans: Vec<_> = diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.span)
+            .collect();
+        assert_eq!(
+            spans,
+            [
+                Span::new(9, 10, 1),
+                Span::new(13, 15, 2),
+                Span::new(26, 27, 3)
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_missing_semicolon_help() {
+        assert_eq!(
+            messages("print 1\nprint 2;"),
+            ["[line 2] Error[E0011]: Expect ';' after value.\n  help: Add ';' after '1' on line 1."]
+        );
+    }
+
+    #[test]
+    fn parse_reports_every_statement() {
+        assert_eq!(
+            messages("print 1 +;\nprint 2;\nprint (3;\n4 5;\nprint # 6;\n"),
+            [
+                "[line 1] Error[E0009] at ';': Expect expression.",
+                "[line 3] Error[E0010] at ';': Expect ')' after expression.\n  note: The '(' to match is on line 3.",
+                "[line 4] Error[E0011] at '5': Expect ';' after expression.",
+                "[line 5] Error[E0001]: Unexpected character '#'.",
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_unary_binds_tighter_than_binary() {
+        let (diagnostics, chunks) = parse("-1 + 2;");
+
+        let mut expected_chunks = Chunk::new();
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(0, 1);
+        expected_chunks.push_op_code(OpCode::Negate, 1);
+        expected_chunks.push_op_code(OpCode::Constant, 1);
+        expected_chunks.push_chunk(1, 1);
+        expected_chunks.push_op_code(OpCode::Add, 1);
+        expected_chunks.push_op_code(OpCode::Pop, 1);
+        expected_chunks.push_op_code(OpCode::Return, 1);
+        expected_chunks.push_constant(Value::Number(1.0));
+        expected_chunks.push_constant(Value::Number(2.0));
+
+        assert!(diagnostics.is_empty());
+        assert_eq!(chunks, expected_chunks)
+    }
+
+    /// Xorshift, enough randomness to generate expressions without a dependency.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// A random expression as space separated tokens.
+    fn random_expression(rng: &mut Rng, depth: u32, out: &mut Vec<String>) {
+        random_operand(rng, depth, out);
+        for _ in 0..rng.below(4) {
+            out.push(["+", "-", "*", "/"][rng.below(4) as usize].to_string());
+            random_operand(rng, depth, out);
+        }
+    }
+
+    fn random_operand(rng: &mut Rng, depth: u32, out: &mut Vec<String>) {
+        for _ in 0..rng.below(3) {
+            out.push("-".to_string());
+        }
+        if depth > 0 && rng.below(4) == 0 {
+            out.push("(".to_string());
+            random_expression(rng, depth - 1, out);
+            out.push(")".to_string());
+        } else {
+            out.push((1 + rng.below(9)).to_string());
+        }
+    }
+
+    /// Reference recursive descent parser over the generated tokens, puts every operation in
+    /// parentheses so the compiler has no precedence decisions left to make.
+    struct Reference<'t> {
+        tokens: &'t [String],
+        position: usize,
+    }
+
+    impl Reference<'_> {
+        fn peek(&self) -> Option<&str> {
+            self.tokens.get(self.position).map(String::as_str)
+        }
+
+        fn next(&mut self) -> &str {
+            self.position += 1;
+            &self.tokens[self.position - 1]
+        }
+
+        fn term(&mut self) -> String {
+            let mut left = self.factor();
+            while let Some(op @ ("+" | "-")) = self.peek() {
+                let op = op.to_string();
+                self.next();
+                left = format!("({} {} {})", left, op, self.factor());
+            }
+            left
+        }
+
+        fn factor(&mut self) -> String {
+            let mut left = self.unary();
+            while let Some(op @ ("*" | "/")) = self.peek() {
+                let op = op.to_string();
+                self.next();
+                left = format!("({} {} {})", left, op, self.unary());
+            }
+            left
+        }
+
+        fn unary(&mut self) -> String {
+            if self.peek() == Some("-") {
+                self.next();
+                return format!("(-{})", self.unary());
+            }
+            if self.peek() == Some("(") {
+                self.next();
+                let inner = self.term();
+                assert_eq!(self.next(), ")");
+                return inner;
+            }
+            self.next().to_string()
+        }
+    }
+
+    fn parenthesise(source: &str) -> String {
+        let tokens: Vec<String> = source.split(' ').map(str::to_string).collect();
+        let mut reference = Reference {
+            tokens: &tokens,
+            position: 0,
+        };
+        let result = reference.term();
+        assert_eq!(reference.position, tokens.len());
+        result
+    }
+
+    #[test]
+    fn reference_parenthesises_everything() {
+        assert_eq!(parenthesise("- 1 + 2"), "((-1) + 2)");
+        assert_eq!(
+            parenthesise("1 - 2 - 3 * - - 4"),
+            "((1 - 2) - (3 * (-(-4))))"
+        );
+        assert_eq!(parenthesise("2 * ( 3 + 4 ) / 5"), "((2 * (3 + 4)) / 5)");
+    }
+
+    #[test]
+    fn parse_matches_parenthesised_reference() {
+        let mut rng = Rng(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..500 {
+            let mut tokens = Vec::new();
+            random_expression(&mut rng, 3, &mut tokens);
+            let source = tokens.join(" ");
+            let reference = parenthesise(&source);
+
+            let (diagnostics, chunks) = parse(&format!("{};", source));
+            let (reference_diagnostics, reference_chunks) = parse(&format!("{};", reference));
+
+            assert!(diagnostics.is_empty(), "{}", source);
+            assert!(reference_diagnostics.is_empty(), "{}", reference);
+            assert_eq!(
+                chunks, reference_chunks,
+                "{} should parse as {}",
+                source, reference
+            );
+        }
+    }
+
+    #[test]
+    fn parse_always_yields_a_tree() {
+        const PIECES: [&str; 14] = [
+            "print", "1", "+", "-", "*", "(", ")", ";", "\n", "\"a ${", "} b\"", "}", "#", "1e400",
+        ];
+        let mut rng = Rng(0x2545_F491_4F6C_DD1D);
+        for _ in 0..2000 {
+            let pieces: Vec<&str> = (0..rng.below(16))
+                .map(|_| PIECES[rng.below(14) as usize])
+                .collect();
+            let source = pieces.join(" ");
+
+            let (program, diagnostics) = crate::ast::parse(&source);
+            let (compile_diagnostics, _) = parse(&source);
+            assert_eq!(diagnostics, compile_diagnostics, "{}", source);
+            assert_eq!(
+                program.to_string().contains("(error"),
+                diagnostics.iter().any(|d| d.is_error()),
+                "{}",
+                source
+            );
+        }
+    }
+}
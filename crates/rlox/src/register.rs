@@ -0,0 +1,258 @@
+use crate::chunk::Code;
+use crate::diagnostic::Span;
+use crate::value::Value;
+
+/// Indexes the registers of the frame an instruction runs in.
+pub type Register = u8;
+
+/// An instruction of the register machine. Operands name registers, so `a + b` on two locals
+/// is a single `Add` that reads both in place instead of copying them onto a stack first.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Instruction {
+    Constant {
+        dest: Register,
+        index: Code,
+    },
+    Nil {
+        dest: Register,
+    },
+    Bool {
+        dest: Register,
+        value: bool,
+    },
+    Move {
+        dest: Register,
+        src: Register,
+    },
+    Equal {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    NotEqual {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Greater {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    GreaterEqual {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Less {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    LessEqual {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Add {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Subtract {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Multiply {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Divide {
+        dest: Register,
+        left: Register,
+        right: Register,
+    },
+    Negate {
+        dest: Register,
+        src: Register,
+    },
+    Not {
+        dest: Register,
+        src: Register,
+    },
+    Stringify {
+        dest: Register,
+        src: Register,
+    },
+    Print {
+        src: Register,
+    },
+    /// The name of a global is a string constant.
+    DefineGlobal {
+        name: Code,
+        src: Register,
+    },
+    GetGlobal {
+        dest: Register,
+        name: Code,
+    },
+    SetGlobal {
+        name: Code,
+        src: Register,
+    },
+    /// Jumps to the instruction at `target`, forward or backward.
+    Jump {
+        target: u32,
+    },
+    JumpIfFalse {
+        cond: Register,
+        target: u32,
+    },
+    JumpIfTrue {
+        cond: Register,
+        target: u32,
+    },
+    Return,
+}
+
+/// Code for the register machine, the counterpart of `Chunk`.
+#[derive(Debug, PartialEq)]
+pub struct RegisterChunk {
+    pub code: Vec<Instruction>,
+    constants: Vec<Value>,
+    /// The source of each instruction, runtime errors point at it.
+    spans: Vec<Span>,
+    /// How many registers the code uses, the size of the frame it runs in.
+    pub registers: usize,
+}
+
+impl RegisterChunk {
+    pub fn new() -> Self {
+        RegisterChunk {
+            code: Vec::new(),
+            constants: Vec::new(),
+            spans: Vec::new(),
+            registers: 0,
+        }
+    }
+
+    /// Appends `instruction`, returns its index.
+    pub fn push(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.code.push(instruction);
+        self.spans.push(span);
+        self.code.len() - 1
+    }
+
+    pub fn push_constant(&mut self, value: Value) -> usize {
+        self.constants.push(value);
+        self.constants.len() - 1
+    }
+
+    pub fn get_constant(&self, idx: usize) -> Option<&Value> {
+        self.constants.get(idx)
+    }
+
+    pub fn get_line(&self, index: usize) -> usize {
+        self.spans[index].line
+    }
+
+    pub fn get_span(&self, index: usize) -> Span {
+        self.spans[index]
+    }
+
+    pub fn disassemble(&self, name: &str) {
+        println!("== {} ({} registers) ==", name, self.registers);
+        for index in 0..self.code.len() {
+            self.disassemble_instruction(index);
+        }
+    }
+
+    pub fn disassemble_instruction(&self, index: usize) {
+        print!("{:0>4} ", index);
+        if index > 0 && self.get_line(index) == self.get_line(index - 1) {
+            print!("   | ");
+        } else {
+            print!("{: >4} ", self.get_line(index));
+        }
+
+        match self.code[index] {
+            Instruction::Constant { dest, index } => {
+                self.constant_instruction("R_CONSTANT", dest, index)
+            }
+            Instruction::Nil { dest } => println!("{: <16} r{}", "R_NIL", dest),
+            Instruction::Bool { dest, value } => println!("{: <16} r{}, {}", "R_BOOL", dest, value),
+            Instruction::Move { dest, src } => unary_instruction("R_MOVE", dest, src),
+            Instruction::Equal { dest, left, right } => {
+                binary_instruction("R_EQUAL", dest, left, right)
+            }
+            Instruction::NotEqual { dest, left, right } => {
+                binary_instruction("R_NOT_EQUAL", dest, left, right)
+            }
+            Instruction::Greater { dest, left, right } => {
+                binary_instruction("R_GREATER", dest, left, right)
+            }
+            Instruction::GreaterEqual { dest, left, right } => {
+                binary_instruction("R_GREATER_EQUAL", dest, left, right)
+            }
+            Instruction::Less { dest, left, right } => {
+                binary_instruction("R_LESS", dest, left, right)
+            }
+            Instruction::LessEqual { dest, left, right } => {
+                binary_instruction("R_LESS_EQUAL", dest, left, right)
+            }
+            Instruction::Add { dest, left, right } => {
+                binary_instruction("R_ADD", dest, left, right)
+            }
+            Instruction::Subtract { dest, left, right } => {
+                binary_instruction("R_SUBTRACT", dest, left, right)
+            }
+            Instruction::Multiply { dest, left, right } => {
+                binary_instruction("R_MULTIPLY", dest, left, right)
+            }
+            Instruction::Divide { dest, left, right } => {
+                binary_instruction("R_DIVIDE", dest, left, right)
+            }
+            Instruction::Negate { dest, src } => unary_instruction("R_NEGATE", dest, src),
+            Instruction::Not { dest, src } => unary_instruction("R_NOT", dest, src),
+            Instruction::Stringify { dest, src } => unary_instruction("R_STRINGIFY", dest, src),
+            Instruction::Print { src } => println!("{: <16} r{}", "R_PRINT", src),
+            Instruction::DefineGlobal { name, src } => {
+                self.global_instruction("R_DEFINE_GLOBAL", src, name)
+            }
+            Instruction::GetGlobal { dest, name } => {
+                self.global_instruction("R_GET_GLOBAL", dest, name)
+            }
+            Instruction::SetGlobal { name, src } => {
+                self.global_instruction("R_SET_GLOBAL", src, name)
+            }
+            Instruction::Jump { target } => println!("{: <16} -> {}", "R_JUMP", target),
+            Instruction::JumpIfFalse { cond, target } => {
+                println!("{: <16} r{} -> {}", "R_JUMP_IF_FALSE", cond, target)
+            }
+            Instruction::JumpIfTrue { cond, target } => {
+                println!("{: <16} r{} -> {}", "R_JUMP_IF_TRUE", cond, target)
+            }
+            Instruction::Return => println!("R_RETURN"),
+        }
+    }
+
+    fn constant_instruction(&self, name: &str, register: Register, index: Code) {
+        let constant = &self.constants[index as usize];
+        println!("{: <16} r{}, {: >4} '{}'", name, register, index, constant);
+    }
+
+    fn global_instruction(&self, name: &str, register: Register, index: Code) {
+        let constant = &self.constants[index as usize];
+        println!("{: <16} r{}, {}", name, register, constant);
+    }
+}
+
+fn unary_instruction(name: &str, dest: Register, src: Register) {
+    println!("{: <16} r{}, r{}", name, dest, src);
+}
+
+fn binary_instruction(name: &str, dest: Register, left: Register, right: Register) {
+    println!("{: <16} r{}, r{}, r{}", name, dest, left, right);
+}
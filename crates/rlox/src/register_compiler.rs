@@ -0,0 +1,606 @@
+use crate::ast::{
+    BinaryOp, Declaration, Expr, ExprKind, InterpolationPart, LogicalOp, Program, StmtKind, UnaryOp,
+};
+use crate::chunk::Code;
+use crate::diagnostic::{Diagnostic, Span};
+use crate::error_code::ErrorCode;
+use crate::register::{Instruction, Register, RegisterChunk};
+use crate::value::Value;
+
+/// Compiles a syntax tree for the register machine, returns every error found on the way.
+///
+/// Local variables live in the registers from 0 up, in the order they are declared, and the
+/// temporaries of an expression are allocated above them. Unlike the stack compiler no
+/// operations are folded at compile time.
+pub fn generate(program: &Program) -> (RegisterChunk, Vec<Diagnostic>) {
+    let mut compiler = RegisterCompiler::new();
+    for declaration in &program.declarations {
+        compiler.declaration(declaration);
+    }
+    compiler.chunk.push(Instruction::Return, program.end);
+    (compiler.chunk, compiler.diagnostics)
+}
+
+struct RegisterCompiler {
+    chunk: RegisterChunk,
+    /// The local variables in scope, the index of each is its register.
+    locals: Vec<Local>,
+    scope_depth: usize,
+    /// The first register that holds neither a local nor a live temporary.
+    top: usize,
+    diagnostics: Vec<Diagnostic>,
+}
+
+struct Local {
+    name: String,
+    /// `None` until the initializer has run.
+    depth: Option<usize>,
+}
+
+impl RegisterCompiler {
+    fn new() -> Self {
+        RegisterCompiler {
+            chunk: RegisterChunk::new(),
+            locals: Vec::new(),
+            scope_depth: 0,
+            top: 0,
+            diagnostics: Vec::new(),
+        }
+    }
+
+    fn declaration(&mut self, declaration: &Declaration) {
+        match declaration {
+            Declaration::Var {
+                name,
+                name_span,
+                initializer,
+                span,
+            } => {
+                if self.scope_depth == 0 {
+                    let src = match initializer {
+                        Some(initializer) => self.expression(initializer, None),
+                        None => self.nil(None, *span),
+                    };
+                    let name = self.make_constant(Value::string(name.clone()), *name_span);
+                    self.emit(Instruction::DefineGlobal { name, src }, *span);
+                } else {
+                    let dest = self.declare_local(name.clone(), *name_span);
+                    match initializer {
+                        Some(initializer) => self.expression(initializer, Some(dest)),
+                        None => self.nil(Some(dest), *span),
+                    };
+                    let local = self.locals.last_mut().expect("Missing local");
+                    local.depth = Some(self.scope_depth);
+                }
+            }
+            Declaration::Statement(stmt) => match &stmt.kind {
+                StmtKind::Print(expr) => {
+                    let src = self.expression(expr, None);
+                    self.emit(Instruction::Print { src }, stmt.span);
+                }
+                StmtKind::Expression(expr) => {
+                    self.expression(expr, None);
+                }
+                StmtKind::Block(declarations) => {
+                    self.scope_depth += 1;
+                    for declaration in declarations {
+                        self.declaration(declaration);
+                    }
+                    self.scope_depth -= 1;
+                    while self
+                        .locals
+                        .last()
+                        .is_some_and(|local| local.depth.is_none_or(|d| d > self.scope_depth))
+                    {
+                        self.locals.pop();
+                    }
+                }
+                StmtKind::If {
+                    condition,
+                    then_branch,
+                    else_branch,
+                } => {
+                    let cond = self.expression(condition, None);
+                    let jump =
+                        self.emit(Instruction::JumpIfFalse { cond, target: 0 }, condition.span);
+                    self.free_temporaries();
+                    self.declaration(then_branch);
+                    if let Some((keyword, else_branch)) = else_branch {
+                        let skip_else = self.emit(Instruction::Jump { target: 0 }, *keyword);
+                        self.patch_jump(jump);
+                        self.declaration(else_branch);
+                        self.patch_jump(skip_else);
+                    } else {
+                        self.patch_jump(jump);
+                    }
+                }
+                StmtKind::While { condition, body } => {
+                    let start = self.chunk.code.len() as u32;
+                    let cond = self.expression(condition, None);
+                    let exit =
+                        self.emit(Instruction::JumpIfFalse { cond, target: 0 }, condition.span);
+                    self.free_temporaries();
+                    self.declaration(body);
+                    self.emit(Instruction::Jump { target: start }, stmt.span);
+                    self.patch_jump(exit);
+                }
+            },
+            Declaration::Error { children, .. } => {
+                for child in children {
+                    self.declaration(child);
+                }
+            }
+        }
+        self.free_temporaries();
+    }
+
+    /// Emits `expr`, returns the register its value ends up in: `dest` when given, otherwise
+    /// a local variable's own register or a new temporary.
+    fn expression(&mut self, expr: &Expr, dest: Option<Register>) -> Register {
+        let mark = self.top;
+        let span = expr.span;
+        match &expr.kind {
+            ExprKind::Nil => self.nil(dest, span),
+            ExprKind::Bool(value) => {
+                let dest = self.result(dest, mark, span);
+                self.emit(
+                    Instruction::Bool {
+                        dest,
+                        value: *value,
+                    },
+                    span,
+                );
+                dest
+            }
+            ExprKind::Number(value) => self.constant(Value::Number(*value), dest, span),
+            ExprKind::String(value) => self.constant(Value::string(value.clone()), dest, span),
+            ExprKind::Interpolation(parts) => self.interpolation(parts, dest, span),
+            ExprKind::Grouping(inner) => self.expression(inner, dest),
+            ExprKind::Unary {
+                op,
+                operator,
+                operand,
+            } => {
+                let src = self.expression(operand, None);
+                let dest = self.result(dest, mark, span);
+                let instruction = match op {
+                    UnaryOp::Negate => Instruction::Negate { dest, src },
+                    UnaryOp::Not => Instruction::Not { dest, src },
+                };
+                self.emit(instruction, *operator);
+                dest
+            }
+            ExprKind::Binary {
+                op,
+                operator,
+                left,
+                right,
+            } => {
+                let mut left_register = self.expression(left, None);
+                // A local read in place would see an assignment made by the right operand.
+                if (left_register as usize) < self.locals.len() && has_assignment(right) {
+                    let copy = self.allocate(left.span);
+                    let src = left_register;
+                    self.emit(Instruction::Move { dest: copy, src }, left.span);
+                    left_register = copy;
+                }
+                let right = self.expression(right, None);
+                let dest = self.result(dest, mark, span);
+                let left = left_register;
+                let instruction = match op {
+                    BinaryOp::Add => Instruction::Add { dest, left, right },
+                    BinaryOp::Subtract => Instruction::Subtract { dest, left, right },
+                    BinaryOp::Multiply => Instruction::Multiply { dest, left, right },
+                    BinaryOp::Divide => Instruction::Divide { dest, left, right },
+                    BinaryOp::Equal => Instruction::Equal { dest, left, right },
+                    BinaryOp::NotEqual => Instruction::NotEqual { dest, left, right },
+                    BinaryOp::Greater => Instruction::Greater { dest, left, right },
+                    BinaryOp::GreaterEqual => Instruction::GreaterEqual { dest, left, right },
+                    BinaryOp::Less => Instruction::Less { dest, left, right },
+                    BinaryOp::LessEqual => Instruction::LessEqual { dest, left, right },
+                };
+                self.emit(instruction, *operator);
+                dest
+            }
+            ExprKind::Logical {
+                op,
+                operator,
+                left,
+                right,
+            } => {
+                // Built in a temporary, the right operand may read the variable being assigned.
+                let result = self.result(None, mark, span);
+                self.expression(left, Some(result));
+                let jump = match op {
+                    LogicalOp::And => Instruction::JumpIfFalse {
+                        cond: result,
+                        target: 0,
+                    },
+                    LogicalOp::Or => Instruction::JumpIfTrue {
+                        cond: result,
+                        target: 0,
+                    },
+                };
+                let jump = self.emit(jump, *operator);
+                self.expression(right, Some(result));
+                self.patch_jump(jump);
+                self.move_to(dest, result, mark, span)
+            }
+            ExprKind::Variable(name) => match self.resolve_local(name, span) {
+                Some(local) => self.move_to(dest, local, mark, span),
+                None => {
+                    let name = self.make_constant(Value::string(name.clone()), span);
+                    let dest = self.result(dest, mark, span);
+                    self.emit(Instruction::GetGlobal { dest, name }, span);
+                    dest
+                }
+            },
+            ExprKind::Assign { name, value } => match self.resolve_local(name, span) {
+                Some(local) => {
+                    self.expression(value, Some(local));
+                    self.move_to(dest, local, mark, span)
+                }
+                None => {
+                    let src = self.expression(value, dest);
+                    let name = self.make_constant(Value::string(name.clone()), span);
+                    self.emit(Instruction::SetGlobal { name, src }, span);
+                    src
+                }
+            },
+            ExprKind::Call {
+                callee, arguments, ..
+            } => {
+                let error = Diagnostic::error(
+                    ErrorCode::UnsupportedOnRegisterVm,
+                    span,
+                    "The register machine can't call functions.",
+                );
+                self.diagnostics.push(error);
+                for child in std::iter::once(&**callee).chain(arguments) {
+                    self.expression(child, None);
+                }
+                self.top = mark;
+                self.nil(dest, span)
+            }
+            ExprKind::Error { children, .. } => {
+                for child in children {
+                    self.expression(child, None);
+                }
+                self.top = mark;
+                self.nil(dest, span)
+            }
+        }
+    }
+
+    /// `"a ${b} c"` is built up in a temporary as `"a " + str(b) + " c"`, empty texts are
+    /// left out.
+    fn interpolation(
+        &mut self,
+        parts: &[InterpolationPart],
+        dest: Option<Register>,
+        span: Span,
+    ) -> Register {
+        let mark = self.top;
+        let result = self.result(None, mark, span);
+        let mut first = true;
+        for part in parts {
+            let part = match part {
+                InterpolationPart::Text(text, _) if text.is_empty() => continue,
+                InterpolationPart::Text(text, span) => {
+                    let part = if first { result } else { self.allocate(*span) };
+                    self.constant_into(Value::string(text.clone()), part, *span);
+                    part
+                }
+                InterpolationPart::Expr(value) => {
+                    let src = self.expression(value, None);
+                    let part = if first {
+                        result
+                    } else {
+                        self.allocate(value.span)
+                    };
+                    self.emit(Instruction::Stringify { dest: part, src }, value.span);
+                    part
+                }
+            };
+            if !first {
+                let instruction = Instruction::Add {
+                    dest: result,
+                    left: result,
+                    right: part,
+                };
+                self.emit(instruction, span);
+            }
+            first = false;
+            self.top = result as usize + 1;
+        }
+        if first {
+            self.constant_into(Value::string(""), result, span);
+        }
+        self.move_to(dest, result, mark, span)
+    }
+
+    fn nil(&mut self, dest: Option<Register>, span: Span) -> Register {
+        let dest = self.result(dest, self.top, span);
+        self.emit(Instruction::Nil { dest }, span);
+        dest
+    }
+
+    fn constant(&mut self, value: Value, dest: Option<Register>, span: Span) -> Register {
+        let dest = self.result(dest, self.top, span);
+        self.constant_into(value, dest, span);
+        dest
+    }
+
+    fn constant_into(&mut self, value: Value, dest: Register, span: Span) {
+        let index = self.make_constant(value, span);
+        self.emit(Instruction::Constant { dest, index }, span);
+    }
+
+    /// Frees the temporaries from `mark` up, which the operands of an instruction were
+    /// in, and picks the register for its result.
+    fn result(&mut self, dest: Option<Register>, mark: usize, span: Span) -> Register {
+        self.top = mark;
+        dest.unwrap_or_else(|| self.allocate(span))
+    }
+
+    /// Copies a value that is already in `src` into `dest`, if there is one.
+    fn move_to(
+        &mut self,
+        dest: Option<Register>,
+        src: Register,
+        mark: usize,
+        span: Span,
+    ) -> Register {
+        match dest {
+            Some(dest) if dest != src => {
+                self.top = mark;
+                self.emit(Instruction::Move { dest, src }, span);
+                dest
+            }
+            _ => src,
+        }
+    }
+
+    fn allocate(&mut self, span: Span) -> Register {
+        let register = self.top;
+        self.top += 1;
+        self.chunk.registers = self.chunk.registers.max(self.top);
+        match Register::try_from(register) {
+            Ok(register) => register,
+            Err(_) => {
+                // Reported once, every later register overflows as well.
+                if register == Register::MAX as usize + 1 {
+                    let error = Diagnostic::error(
+                        ErrorCode::TooManyRegisters,
+                        span,
+                        "Expression needs too many registers.",
+                    );
+                    self.diagnostics.push(error);
+                }
+                Register::MAX
+            }
+        }
+    }
+
+    /// Temporaries only live until the end of their statement.
+    fn free_temporaries(&mut self) {
+        self.top = self.locals.len();
+    }
+
+    /// Adds a local variable and reserves the register after the ones in use for it.
+    fn declare_local(&mut self, name: String, span: Span) -> Register {
+        let depth = self.scope_depth;
+        let mut in_scope =
+            (self.locals.iter().rev()).take_while(|local| local.depth.is_none_or(|d| d == depth));
+        if in_scope.any(|local| local.name == name) {
+            let error = Diagnostic::error(
+                ErrorCode::DuplicateVariable,
+                span,
+                "Already a variable with this name in this scope.",
+            );
+            self.diagnostics.push(error);
+        }
+        if self.locals.len() == Register::MAX as usize + 1 {
+            let error = Diagnostic::error(
+                ErrorCode::TooManyLocals,
+                span,
+                "Too many local variables in scope.",
+            );
+            self.diagnostics.push(error);
+        }
+        self.locals.push(Local { name, depth: None });
+        self.allocate(span)
+    }
+
+    /// The register of the innermost local variable called `name`, `None` for globals.
+    fn resolve_local(&mut self, name: &str, span: Span) -> Option<Register> {
+        let slot = self.locals.iter().rposition(|local| local.name == name)?;
+        if self.locals[slot].depth.is_none() {
+            let error = Diagnostic::error(
+                ErrorCode::OwnInitializer,
+                span,
+                "Can't read local variable in its own initializer.",
+            );
+            self.diagnostics.push(error);
+        }
+        Some(Register::try_from(slot).unwrap_or(Register::MAX))
+    }
+
+    fn make_constant(&mut self, value: Value, span: Span) -> Code {
+        let constant_idx = self.chunk.push_constant(value);
+        match Code::try_from(constant_idx) {
+            Ok(constant) => constant,
+            Err(_) => {
+                if constant_idx == Code::MAX as usize + 1 {
+                    let error = Diagnostic::error(
+                        ErrorCode::TooManyConstants,
+                        span,
+                        "Too many constants in one chunk",
+                    );
+                    self.diagnostics.push(error);
+                }
+                Code::MAX
+            }
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction, span: Span) -> usize {
+        self.chunk.push(instruction, span)
+    }
+
+    /// Points the jump at `index` to the next instruction.
+    fn patch_jump(&mut self, index: usize) {
+        let next = self.chunk.code.len() as u32;
+        match &mut self.chunk.code[index] {
+            Instruction::Jump { target }
+            | Instruction::JumpIfFalse { target, .. }
+            | Instruction::JumpIfTrue { target, .. } => *target = next,
+            instruction => panic!("Not a jump: {:?}", instruction),
+        }
+    }
+}
+
+/// Whether evaluating `expr` may assign to a variable.
+fn has_assignment(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Nil
+        | ExprKind::Bool(_)
+        | ExprKind::Number(_)
+        | ExprKind::String(_)
+        | ExprKind::Variable(_) => false,
+        // A native function can assign to any global.
+        ExprKind::Assign { .. } | ExprKind::Call { .. } => true,
+        ExprKind::Interpolation(parts) => parts.iter().any(|part| match part {
+            InterpolationPart::Text(..) => false,
+            InterpolationPart::Expr(value) => has_assignment(value),
+        }),
+        ExprKind::Grouping(inner) => has_assignment(inner),
+        ExprKind::Unary { operand, .. } => has_assignment(operand),
+        ExprKind::Binary { left, right, .. } | ExprKind::Logical { left, right, .. } => {
+            has_assignment(left) || has_assignment(right)
+        }
+        ExprKind::Error { children, .. } => children.iter().any(has_assignment),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use crate::ast;
+    use crate::register::{Instruction, RegisterChunk};
+
+    fn chunk(source: &str) -> RegisterChunk {
+        let (program, diagnostics) = ast::parse(source);
+        assert!(diagnostics.is_empty(), "{}", source);
+        let (chunk, diagnostics) = generate(&program);
+        assert!(diagnostics.is_empty(), "{}", source);
+        chunk
+    }
+
+    #[test]
+    fn operate_on_locals_in_place() {
+        let chunk = chunk("{ var a = 1; var b = 2; print a + b * a; }");
+        assert_eq!(
+            chunk.code,
+            [
+                Instruction::Constant { dest: 0, index: 0 },
+                Instruction::Constant { dest: 1, index: 1 },
+                Instruction::Multiply {
+                    dest: 2,
+                    left: 1,
+                    right: 0
+                },
+                Instruction::Add {
+                    dest: 2,
+                    left: 0,
+                    right: 2
+                },
+                Instruction::Print { src: 2 },
+                Instruction::Return,
+            ]
+        );
+        assert_eq!(chunk.registers, 3);
+    }
+
+    #[test]
+    fn assign_straight_into_locals() {
+        let chunk = chunk("{ var i = 0; i = i + 1; }");
+        assert_eq!(
+            chunk.code,
+            [
+                Instruction::Constant { dest: 0, index: 0 },
+                Instruction::Constant { dest: 1, index: 1 },
+                Instruction::Add {
+                    dest: 0,
+                    left: 0,
+                    right: 1
+                },
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn copy_locals_assigned_by_the_right_operand() {
+        let chunk = chunk("{ var a = 1; print a + (a = 2); }");
+        assert_eq!(
+            chunk.code,
+            [
+                Instruction::Constant { dest: 0, index: 0 },
+                Instruction::Move { dest: 1, src: 0 },
+                Instruction::Constant { dest: 0, index: 1 },
+                Instruction::Add {
+                    dest: 1,
+                    left: 1,
+                    right: 0
+                },
+                Instruction::Print { src: 1 },
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn loop_with_absolute_jumps() {
+        let chunk = chunk("{ var i = 0; while (i < 3) i = i + 1; }");
+        assert_eq!(
+            chunk.code,
+            [
+                Instruction::Constant { dest: 0, index: 0 },
+                Instruction::Constant { dest: 1, index: 1 },
+                Instruction::Less {
+                    dest: 1,
+                    left: 0,
+                    right: 1
+                },
+                Instruction::JumpIfFalse { cond: 1, target: 7 },
+                Instruction::Constant { dest: 1, index: 2 },
+                Instruction::Add {
+                    dest: 0,
+                    left: 0,
+                    right: 1
+                },
+                Instruction::Jump { target: 1 },
+                Instruction::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn report_errors_in_scopes() {
+        let messages = |source: &str| {
+            let (program, _) = ast::parse(source);
+            let (_, diagnostics) = generate(&program);
+            diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.code.expect("Error code").to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(messages("{ var a = 1; var a = 2; }"), ["E0021"]);
+        assert_eq!(messages("{ var a = a; }"), ["E0022"]);
+        let locals = (0..300)
+            .map(|i| format!("var a{} = nil;", i))
+            .collect::<String>();
+        assert_eq!(messages(&format!("{{ {} }}", locals)), ["E0023", "E0027"]);
+    }
+}
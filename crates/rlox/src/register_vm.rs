@@ -0,0 +1,419 @@
+use crate::diagnostic::Diagnostic;
+use crate::error_code::ErrorCode;
+use crate::lox_error::{self, LoxError};
+use crate::register::{Instruction, Register, RegisterChunk};
+use crate::value::Value;
+use crate::{ast, register_compiler, InterpretResult};
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+/// Runs the code of the register compiler. Operands are read from the registers of a single
+/// frame and results written straight back, so nothing is pushed or popped.
+pub struct RegisterVm {
+    pub chunk: RegisterChunk,
+    registers: Vec<Value>,
+    globals: HashMap<String, Value>,
+    error: Option<Diagnostic>,
+    /// Where `print` writes to.
+    output: Box<dyn Write>,
+}
+
+impl RegisterVm {
+    pub fn new() -> Self {
+        RegisterVm::with_output(io::stdout())
+    }
+
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        RegisterVm {
+            chunk: RegisterChunk::new(),
+            registers: Vec::new(),
+            globals: HashMap::new(),
+            error: None,
+            output: Box::new(output),
+        }
+    }
+
+    /// Compiles `source` through its syntax tree, as `Vm::compile` does for the stack machine.
+    pub fn compile(source: &str) -> Result<RegisterChunk, LoxError> {
+        let (program, diagnostics) = ast::parse(source);
+        lox_error::check(diagnostics)?;
+        let (chunk, errors) = register_compiler::generate(&program);
+        lox_error::check(errors)?;
+        Ok(chunk)
+    }
+
+    /// Runs `chunk` from the start, as `Vm::run` does on the stack machine.
+    pub fn execute(&mut self, chunk: RegisterChunk) -> Result<(), LoxError> {
+        if cfg!(feature = "debug_print_code") {
+            chunk.disassemble("code");
+        }
+        self.chunk = chunk;
+        match self.run() {
+            InterpretResult::RuntimeError => {
+                let error = self.take_error();
+                Err(LoxError::Runtime(
+                    error.expect("Runtime errors are reported"),
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `chunk` from the start, globals defined by earlier runs are kept.
+    pub fn run(&mut self) -> InterpretResult {
+        self.registers.clear();
+        self.registers.resize(self.chunk.registers, Value::Nil);
+        let mut ip = 0;
+        loop {
+            if cfg!(feature = "debug_trace_execution") {
+                self.trace();
+                self.chunk.disassemble_instruction(ip);
+            }
+            let instruction = self.chunk.code[ip];
+            ip += 1;
+            match instruction {
+                Instruction::Constant { dest, index } => {
+                    let value = self.chunk.get_constant(index as usize).cloned();
+                    self.registers[dest as usize] = value.unwrap_or(Value::Nil);
+                }
+                Instruction::Nil { dest } => {
+                    self.registers[dest as usize] = Value::Nil;
+                }
+                Instruction::Bool { dest, value } => {
+                    self.registers[dest as usize] = Value::Bool(value);
+                }
+                Instruction::Move { dest, src } => {
+                    self.registers[dest as usize] = self.registers[src as usize].clone();
+                }
+                Instruction::Equal { dest, left, right } => {
+                    let equal = self.registers[left as usize] == self.registers[right as usize];
+                    self.registers[dest as usize] = Value::Bool(equal);
+                }
+                Instruction::NotEqual { dest, left, right } => {
+                    let equal = self.registers[left as usize] == self.registers[right as usize];
+                    self.registers[dest as usize] = Value::Bool(!equal);
+                }
+                Instruction::Greater { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Bool(a > b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::GreaterEqual { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Bool(a >= b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::Less { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Bool(a < b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::LessEqual { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Bool(a <= b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::Add { dest, left, right } => {
+                    let sum = match (
+                        &self.registers[left as usize],
+                        &self.registers[right as usize],
+                    ) {
+                        (Value::Number(a), Value::Number(b)) => Value::Number(a + b),
+                        (a, b) => match (a.as_str(), b.as_str()) {
+                            (Some(a), Some(b)) => Value::string(format!("{}{}", a, b)),
+                            _ => {
+                                let message = "Operands must be two numbers or two strings.";
+                                return self.runtime_error(
+                                    ErrorCode::OperandsNotAddable,
+                                    message,
+                                    ip,
+                                );
+                            }
+                        },
+                    };
+                    self.registers[dest as usize] = sum;
+                }
+                Instruction::Subtract { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Number(a - b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::Multiply { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Number(a * b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::Divide { dest, left, right } => {
+                    if !self.numbers(dest, left, right, |a, b| Value::Number(a / b)) {
+                        return self.operands_not_numbers(ip);
+                    }
+                }
+                Instruction::Negate { dest, src } => {
+                    if let Value::Number(value) = self.registers[src as usize] {
+                        self.registers[dest as usize] = Value::Number(-value);
+                    } else {
+                        let message = "Operand must be a number.";
+                        return self.runtime_error(ErrorCode::OperandNotNumber, message, ip);
+                    }
+                }
+                Instruction::Not { dest, src } => {
+                    let value = self.registers[src as usize].is_falsey();
+                    self.registers[dest as usize] = Value::Bool(value);
+                }
+                Instruction::Stringify { dest, src } => {
+                    let value = &self.registers[src as usize];
+                    let string = match value.as_str() {
+                        Some(_) => value.clone(),
+                        None => Value::string(value.to_string()),
+                    };
+                    self.registers[dest as usize] = string;
+                }
+                Instruction::Print { src } => {
+                    let value = &self.registers[src as usize];
+                    writeln!(self.output, "{}", value).expect("Failed to write output");
+                }
+                Instruction::DefineGlobal { name, src } => {
+                    let name = self.read_name(name);
+                    self.globals
+                        .insert(name, self.registers[src as usize].clone());
+                }
+                Instruction::GetGlobal { dest, name } => {
+                    let name = self.read_name(name);
+                    match self.globals.get(&name) {
+                        Some(value) => self.registers[dest as usize] = value.clone(),
+                        None => return self.undefined_variable(&name, ip),
+                    }
+                }
+                Instruction::SetGlobal { name, src } => {
+                    let name = self.read_name(name);
+                    match self.globals.get_mut(&name) {
+                        Some(global) => *global = self.registers[src as usize].clone(),
+                        None => return self.undefined_variable(&name, ip),
+                    }
+                }
+                Instruction::Jump { target } => {
+                    ip = target as usize;
+                }
+                Instruction::JumpIfFalse { cond, target } => {
+                    if self.registers[cond as usize].is_falsey() {
+                        ip = target as usize;
+                    }
+                }
+                Instruction::JumpIfTrue { cond, target } => {
+                    if !self.registers[cond as usize].is_falsey() {
+                        ip = target as usize;
+                    }
+                }
+                Instruction::Return => {
+                    break;
+                }
+            }
+        }
+        InterpretResult::Ok
+    }
+
+    /// Writes `apply` of two number operands to `dest`, false if either is not a number.
+    fn numbers(
+        &mut self,
+        dest: Register,
+        left: Register,
+        right: Register,
+        apply: fn(f64, f64) -> Value,
+    ) -> bool {
+        match (
+            &self.registers[left as usize],
+            &self.registers[right as usize],
+        ) {
+            (Value::Number(a), Value::Number(b)) => {
+                self.registers[dest as usize] = apply(*a, *b);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// The error that stopped the last `run`, it points at the failing instruction.
+    pub fn take_error(&mut self) -> Option<Diagnostic> {
+        self.error.take()
+    }
+
+    fn operands_not_numbers(&mut self, ip: usize) -> InterpretResult {
+        self.runtime_error(
+            ErrorCode::OperandsNotNumbers,
+            "Operands must be numbers.",
+            ip,
+        )
+    }
+
+    fn undefined_variable(&mut self, name: &str, ip: usize) -> InterpretResult {
+        let message = format!("Undefined variable '{}'.", name);
+        self.runtime_error(ErrorCode::UndefinedVariable, &message, ip)
+    }
+
+    /// `ip` is already past the failing instruction.
+    fn runtime_error(&mut self, code: ErrorCode, message: &str, ip: usize) -> InterpretResult {
+        let span = self.chunk.get_span(ip - 1);
+        self.error = Some(Diagnostic::error(code, span, message));
+        InterpretResult::RuntimeError
+    }
+
+    /// The name of a global variable, a string constant.
+    fn read_name(&self, index: u8) -> String {
+        let name = self
+            .chunk
+            .get_constant(index as usize)
+            .and_then(Value::as_str);
+        name.unwrap_or_default().to_string()
+    }
+
+    fn trace(&self) {
+        for value in self.registers.iter() {
+            print!("[{:?}]", value);
+        }
+        println!();
+    }
+}
+
+impl Default for RegisterVm {
+    fn default() -> Self {
+        RegisterVm::new()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::RegisterVm;
+    use crate::compiler::{self, OptLevel};
+    use crate::optimizer::tests::Output;
+    use crate::vm::VirtualMachine;
+    use crate::{ast, register_compiler, InterpretResult};
+    use std::io::{self, Write};
+    use std::time::{Duration, Instant};
+
+    fn register_vm(source: &str, output: impl Write + 'static) -> RegisterVm {
+        let (program, diagnostics) = ast::parse(source);
+        assert!(diagnostics.is_empty(), "{}", source);
+        let (chunk, diagnostics) = register_compiler::generate(&program);
+        assert!(diagnostics.is_empty(), "{}", source);
+        let mut vm = RegisterVm::with_output(output);
+        vm.chunk = chunk;
+        vm
+    }
+
+    fn stack_vm(source: &str, output: impl Write + 'static) -> VirtualMachine {
+        let mut vm = VirtualMachine::with_output(output);
+        let diagnostics = compiler::compile(source, &mut vm.chunks, OptLevel::default());
+        assert!(diagnostics.is_empty(), "{}", source);
+        vm
+    }
+
+    /// What a program prints and the error it stops with, on either machine.
+    fn run(source: &str, registers: bool) -> (String, Option<String>) {
+        let output = Output::default();
+        let error = if registers {
+            let mut vm = register_vm(source, output.clone());
+            vm.run();
+            vm.take_error()
+        } else {
+            let mut vm = stack_vm(source, output.clone());
+            vm.run();
+            vm.take_error()
+        };
+        let printed = String::from_utf8(output.0.take()).expect("Output is UTF-8");
+        let line_and_code = error.map(|error| format!("{} {:?}", error.span.line, error.code));
+        (printed, line_and_code)
+    }
+
+    pub(crate) const PROGRAMS: [&str; 10] = [
+        "print 1 + 2 * 3 - 4 / 5;\nprint -(1 - -2);\nprint !nil == (1 < 2) != (3 >= 4);",
+        r#"var a = "x"; print "${a} ${1 + 2} ${"${3}"}${4}${nil}"; print "" + a;"#,
+        "var i = 0; while (i < 5) { if (i == 2) print \"two\"; else print i; i = i + 1; }",
+        "{ var a = 1; { var a = 2; print a; } print a; }",
+        "var a2; { var a = 1; { var b = a2 = 2; print b + a; } print a; }",
+        "{ var a = 1; print a + (a = 2); print a; var b = a = 3; print b; print a = a + b; }",
+        "{ var a = false; a = a or \"default\"; print a; print a and nil; print nil and a; }",
+        "var g = 1; { var l = g + 1; g = l * 2; } print g; g = g = 7; print g;",
+        "print 1;\n{ var s = \"s\";\n  print s - 1; }",
+        "var x = 1;\nprint x;\nprint -\"a\";\nprint y;",
+    ];
+
+    #[test]
+    fn register_vm_matches_stack_vm() {
+        for source in PROGRAMS {
+            assert_eq!(run(source, true), run(source, false), "{}", source);
+        }
+        assert_eq!(run(PROGRAMS[2], true).0, "0\n1\ntwo\n3\n4\n");
+        assert_eq!(run(PROGRAMS[5], true).0, "3\n2\n3\n6\n");
+        assert_eq!(
+            run(PROGRAMS[8], true),
+            (
+                "1\n".to_string(),
+                Some("3 Some(OperandsNotNumbers)".to_string())
+            )
+        );
+    }
+
+    #[test]
+    fn globals_carry_over_between_runs() {
+        let output = Output::default();
+        let mut vm = register_vm("var a = 1;", output.clone());
+        assert!(matches!(vm.run(), InterpretResult::Ok));
+        vm.chunk = register_vm("print a + 1;", io::sink()).chunk;
+        assert!(matches!(vm.run(), InterpretResult::Ok));
+        assert_eq!(output.0.take(), b"2\n");
+    }
+
+    /// The same Lox programs on both machines.
+    const BENCHMARKS: [(&str, &str); 4] = [
+        (
+            "local loop",
+            "{ var i = 0; var sum = 0; while (i < 3000000) { sum = sum + i * 2 - 1; i = i + 1; } print sum; }",
+        ),
+        (
+            "global loop",
+            "var i = 0; var sum = 0; while (i < 1000000) { sum = sum + i * 2 - 1; i = i + 1; } print sum;",
+        ),
+        (
+            "fibonacci",
+            "{ var n = 0; while (n < 20000) { var a = 0; var b = 1; var i = 0; \
+             while (i < 90) { var t = a + b; a = b; b = t; i = i + 1; } n = n + 1; } }",
+        ),
+        (
+            "strings",
+            "{ var i = 0; var s = \"\"; while (i < 200000) { s = \"${i}\" + \"!\"; \
+             if (s == \"7!\" or !(i < 0)) i = i + 1; } print s; }",
+        ),
+    ];
+
+    /// The fastest of `runs` calls of `run`, benchmarks report it to keep noise out.
+    pub(crate) fn best_of(runs: usize, mut run: impl FnMut()) -> Duration {
+        let mut best = Duration::MAX;
+        for _ in 0..runs {
+            let start = Instant::now();
+            run();
+            best = best.min(start.elapsed());
+        }
+        best
+    }
+
+    /// Run with `cargo test --release -p rlox bench_register_vm -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_register_vm() {
+        for (name, source) in BENCHMARKS {
+            let mut stack = stack_vm(source, io::sink());
+            let mut registers = register_vm(source, io::sink());
+            let stack_time = best_of(5, || assert!(matches!(stack.run(), InterpretResult::Ok)));
+            let register_time = best_of(5, || {
+                assert!(matches!(registers.run(), InterpretResult::Ok))
+            });
+            println!(
+                "{}: stack {:?}, register {:?} ({:.2}x)",
+                name,
+                stack_time,
+                register_time,
+                stack_time.as_secs_f64() / register_time.as_secs_f64()
+            );
+        }
+    }
+}
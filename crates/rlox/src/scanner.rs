@@ -1,28 +1,55 @@
+use crate::keyword::keyword_type;
+use crate::lex_error::{LexError, NumberError};
 use crate::token::Token;
+use crate::trivia::{LosslessToken, Trivia, TriviaKind};
 use crate::TokenType;
 
+/// Scans the UTF-8 bytes of the source in place, `start` and `current` are byte offsets.
 pub struct Scanner<'a> {
     source: &'a str,
-    chars: Vec<char>,
+    bytes: &'a [u8],
     start: usize,
     current: usize,
     line: usize,
+    /// Line of `start`, tokens report the line they start on.
+    start_line: usize,
+    /// Offset of the first byte of the current line.
+    line_start: usize,
+    /// Column of `start`, counted in characters from 1.
+    start_column: usize,
+    /// Offset `start_column` was last computed at, so columns are counted incrementally.
+    column_offset: usize,
+    /// Cleared when the current line has a non-ASCII character, columns then count characters.
+    line_is_ascii: bool,
+    /// Set once `Eof` has been produced by the iterator.
+    done: bool,
+    /// Brace depth of every string interpolation we are currently inside.
+    interpolations: Vec<usize>,
 }
 
 impl<'a> Scanner<'a> {
     pub fn new(source: &'a str) -> Self {
         Scanner {
             source,
-            chars: source.chars().collect::<Vec<char>>(),
+            bytes: source.as_bytes(),
             start: 0,
             current: 0,
             line: 1,
+            start_line: 1,
+            line_start: 0,
+            start_column: 1,
+            column_offset: 0,
+            line_is_ascii: true,
+            done: false,
+            interpolations: Vec::new(),
         }
     }
 
     pub fn scan_token(&mut self) -> Token<'a> {
-        self.skip_whitespace();
-        self.start = self.current;
+        if let Err(error) = self.skip_whitespace() {
+            return self.error_token(error);
+        }
+        self.begin_token();
 
         if self.is_at_end() {
             return self.make_token(TokenType::Eof);
@@ -30,100 +57,248 @@ impl<'a> Scanner<'a> {
 
         let c = self.advance();
 
-        return match c {
-            c if c == '_' || c.is_ascii_alphabetic() => self.identifier(),
-            '(' => self.make_token(TokenType::LeftParen),
-            ')' => self.make_token(TokenType::RightParen),
-            '{' => self.make_token(TokenType::LeftBrace),
-            '}' => self.make_token(TokenType::RightBrace),
-            ';' => self.make_token(TokenType::Semicolon),
-            ',' => self.make_token(TokenType::Comma),
-            '.' => self.make_token(TokenType::Dot),
-            '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
-            '/' => self.make_token(TokenType::Slash),
-            '*' => self.make_token(TokenType::Star),
-            '!' => {
-                let token = if self.match_current('=') {
+        match c {
+            c if c == b'_' || c.is_ascii_alphabetic() => self.identifier(),
+            b'(' => self.make_token(TokenType::LeftParen),
+            b')' => self.make_token(TokenType::RightParen),
+            b'{' => {
+                if let Some(depth) = self.interpolations.last_mut() {
+                    *depth += 1;
+                }
+                self.make_token(TokenType::LeftBrace)
+            }
+            b'}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    // Closes `${`, the rest of the string literal follows.
+                    self.interpolations.pop();
+                    self.string()
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.make_token(TokenType::RightBrace)
+                }
+                None => self.make_token(TokenType::RightBrace),
+            },
+            b';' => self.make_token(TokenType::Semicolon),
+            b',' => self.make_token(TokenType::Comma),
+            b'.' => self.make_token(TokenType::Dot),
+            b'-' => self.make_token(TokenType::Minus),
+            b'+' => self.make_token(TokenType::Plus),
+            b'/' if self.is_doc_comment() => self.doc_comment(),
+            b'/' => self.make_token(TokenType::Slash),
+            b'*' => self.make_token(TokenType::Star),
+            b'!' => {
+                let token = if self.match_current(b'=') {
                     TokenType::BangEqual
                 } else {
                     TokenType::Bang
                 };
                 self.make_token(token)
             }
-            '=' => {
-                let token = if self.match_current('=') {
+            b'=' => {
+                let token = if self.match_current(b'=') {
                     TokenType::EqualEqual
                 } else {
                     TokenType::Equal
                 };
                 self.make_token(token)
             }
-            '<' => {
-                let token = if self.match_current('=') {
+            b'<' => {
+                let token = if self.match_current(b'=') {
                     TokenType::LessEqual
                 } else {
                     TokenType::Less
                 };
                 self.make_token(token)
             }
-            '>' => {
-                let token = if self.match_current('=') {
+            b'>' => {
+                let token = if self.match_current(b'=') {
                     TokenType::GreaterEqual
                 } else {
                     TokenType::Greater
                 };
                 self.make_token(token)
             }
-            '"' => self.string(),
-            c if c.is_digit(10) => self.number(),
-            _ => self.error_token("Unexpected character."),
-        };
+            b'"' => self.string(),
+            c if c.is_ascii_digit() => self.number(),
+            _ => {
+                // Step over the whole character so the token stays valid UTF-8.
+                let c = self.source[self.start..].chars().next().unwrap_or_default();
+                self.current = self.start + c.len_utf8();
+                self.line_is_ascii &= c.is_ascii();
+                self.error_token(LexError::UnexpectedCharacter(c))
+            }
+        }
     }
 
-    fn advance(&mut self) -> char {
+    fn advance(&mut self) -> u8 {
         self.current += 1;
-        self.chars[self.current - 1]
+        self.bytes[self.current - 1]
     }
 
-    fn peek(&mut self) -> char {
-        self.chars[self.current]
+    /// Advances over a byte of string or comment text, which may be part of a wider character.
+    fn advance_text(&mut self) {
+        self.line_is_ascii &= self.advance().is_ascii();
     }
 
-    fn peek_next(&mut self) -> char {
-        if self.is_at_end() {
-            '\0'
-        } else {
-            self.chars[self.current + 1]
+    fn newline(&mut self) {
+        self.advance();
+        self.line += 1;
+        self.line_start = self.current;
+        self.line_is_ascii = true;
+    }
+
+    fn begin_token(&mut self) {
+        self.start = self.current;
+        self.start_line = self.line;
+        if self.column_offset < self.line_start {
+            self.column_offset = self.line_start;
+            self.start_column = 1;
         }
+        self.start_column += if self.line_is_ascii {
+            self.start - self.column_offset
+        } else {
+            // Continuation bytes of multi-byte characters do not move the column.
+            let skipped = &self.bytes[self.column_offset..self.start];
+            skipped
+                .iter()
+                .filter(|byte| (**byte as i8) >= -0x40)
+                .count()
+        };
+        self.column_offset = self.start;
     }
 
-    fn skip_whitespace(&mut self) {
-        while !self.is_at_end() {
-            match self.peek() {
-                '\n' => {
-                    self.line += 1;
+    fn peek(&self) -> u8 {
+        self.peek_at(0)
+    }
+
+    fn peek_next(&self) -> u8 {
+        self.peek_at(1)
+    }
+
+    fn peek_at(&self, distance: usize) -> u8 {
+        *self.bytes.get(self.current + distance).unwrap_or(&b'\0')
+    }
+
+    fn skip_whitespace(&mut self) -> Result<(), LexError> {
+        while self.trivia()?.is_some() {}
+        Ok(())
+    }
+
+    /// Consumes one piece of whitespace or comment, `None` if the next character starts a token.
+    fn trivia(&mut self) -> Result<Option<TriviaKind>, LexError> {
+        let kind = match self.peek() {
+            _ if self.is_at_end() => return Ok(None),
+            b'\n' => {
+                self.newline();
+                TriviaKind::Newline
+            }
+            b' ' | b'\r' | b'\t' => {
+                while matches!(self.peek(), b' ' | b'\r' | b'\t') {
                     self.advance();
                 }
-                ' ' | '\r' | '\t' => {
-                    self.advance();
+                TriviaKind::Whitespace
+            }
+            // Doc comments are tokens, leave them for `scan_token`.
+            b'/' if self.peek_next() == b'/' && self.starts_doc_comment() => return Ok(None),
+            b'/' if self.peek_next() == b'/' => {
+                while !self.is_at_end() && self.peek() != b'\n' {
+                    self.advance_text();
+                }
+                TriviaKind::LineComment
+            }
+            b'/' if self.peek_next() == b'*' => {
+                self.block_comment()?;
+                TriviaKind::BlockComment
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(kind))
+    }
+
+    /// Scans the next token together with the trivia around it. Trailing trivia is the
+    /// whitespace and line comment up to the end of the line, everything else leads the
+    /// next token, so concatenating all tokens and trivia gives back the source exactly.
+    pub fn scan_lossless(&mut self) -> LosslessToken<'a> {
+        let mut leading = Vec::new();
+        let token = loop {
+            let start = self.current;
+            match self.trivia() {
+                Ok(Some(kind)) => {
+                    leading.push(Trivia::new(kind, &self.source[start..self.current]))
+                }
+                Ok(None) => break self.scan_token(),
+                Err(error) => break self.error_token(error),
+            }
+        };
+
+        let mut trailing = Vec::new();
+        while matches!(self.peek(), b' ' | b'\r' | b'\t')
+            || (self.peek() == b'/' && self.peek_next() == b'/' && !self.starts_doc_comment())
+        {
+            let start = self.current;
+            if let Ok(Some(kind)) = self.trivia() {
+                trailing.push(Trivia::new(kind, &self.source[start..self.current]));
+            }
+        }
+
+        LosslessToken {
+            leading,
+            token,
+            trailing,
+        }
+    }
+
+    /// Switches to the trivia preserving mode, see `scan_lossless`.
+    pub fn lossless(self) -> LosslessScanner<'a> {
+        LosslessScanner { scanner: self }
+    }
+
+    /// Skips a `/* ... */` comment, the opening `/*` is not consumed yet.
+    /// Block comments nest, so `/* a /* b */ c */` is a single comment.
+    fn block_comment(&mut self) -> Result<(), LexError> {
+        self.begin_token();
+        let line = self.line;
+        let mut depth = 0;
+        while !self.is_at_end() {
+            match (self.peek(), self.peek_next()) {
+                (b'/', b'*') => {
+                    depth += 1;
+                    self.current += 2;
                 }
-                '/' => {
-                    if self.peek_next() == '/' {
-                        while !self.is_at_end() && self.peek() != '\n' {
-                            self.advance();
-                        }
-                    } else {
-                        return;
+                (b'*', b'/') => {
+                    depth -= 1;
+                    self.current += 2;
+                    if depth == 0 {
+                        return Ok(());
                     }
                 }
-                _ => return,
+                (b'\n', _) => self.newline(),
+                _ => self.advance_text(),
             }
         }
+        Err(LexError::UnterminatedComment { line })
+    }
+
+    /// `///` starts a doc comment, but `////` and longer runs are plain comments.
+    fn starts_doc_comment(&self) -> bool {
+        self.peek_at(2) == b'/' && self.peek_at(3) != b'/'
+    }
+
+    /// Called from `scan_token` after the first `/` has been consumed.
+    fn is_doc_comment(&self) -> bool {
+        self.peek_at(0) == b'/' && self.peek_at(1) == b'/' && self.peek_at(2) != b'/'
     }
 
-    fn match_current(&mut self, expected: char) -> bool {
-        if self.is_at_end() || self.chars[self.current] != expected {
+    fn doc_comment(&mut self) -> Token<'a> {
+        while !self.is_at_end() && self.peek() != b'\n' {
+            self.advance_text();
+        }
+        self.make_token(TokenType::DocComment)
+    }
+
+    fn match_current(&mut self, expected: u8) -> bool {
+        if self.is_at_end() || self.bytes[self.current] != expected {
             false
         } else {
             self.current += 1;
@@ -140,111 +315,242 @@ impl<'a> Scanner<'a> {
             token_type,
             self.start,
             &self.source[self.start..self.current],
-            self.line,
+            self.start_line,
+            self.start_column,
         )
     }
 
-    fn error_token(&self, msg: &'static str) -> Token<'a> {
-        Token::new(TokenType::Error, 0, msg, self.line)
+    /// The error token spans the offending source text, `error` tells what is wrong with it.
+    fn error_token(&self, error: LexError) -> Token<'a> {
+        Token::error(
+            error,
+            self.start,
+            &self.source[self.start..self.current],
+            self.start_line,
+            self.start_column,
+        )
     }
 
+    /// Scans a string literal or the segment of one that follows an interpolated expression.
+    /// A segment ending in `${` is an `Interpolation` token, the last segment is a `String`.
     fn string(&mut self) -> Token<'a> {
-        while !self.is_at_end() && self.peek() != '"' {
-            if self.peek() == '\n' {
-                self.line += 1
+        let (line, offset, column) = (self.line, self.start, self.start_column);
+        let mut invalid_escape = None;
+        while !self.is_at_end() && self.peek() != b'"' {
+            match self.peek() {
+                b'\n' => self.newline(),
+                b'\\' => {
+                    self.advance();
+                    let error = self.escape();
+                    invalid_escape = invalid_escape.or(error);
+                }
+                b'$' if self.peek_next() == b'{' => {
+                    self.current += 2;
+                    self.interpolations.push(0);
+                    return match invalid_escape {
+                        Some(error) => self.error_token(error),
+                        None => self.make_token(TokenType::Interpolation),
+                    };
+                }
+                _ => self.advance_text(),
             }
-            self.advance();
         }
         if self.is_at_end() {
-            self.error_token("Unterminated string")
+            return self.error_token(LexError::UnterminatedString {
+                line,
+                offset,
+                column,
+            });
+        }
+        self.advance();
+        match invalid_escape {
+            Some(error) => self.error_token(error),
+            None => self.make_token(TokenType::String),
+        }
+    }
+
+    /// Checks the escape sequence after a `\`, see `parser::unescape` for its meaning.
+    fn escape(&mut self) -> Option<LexError> {
+        if self.is_at_end() {
+            // Reported as an unterminated string.
+            return None;
+        }
+        let line = self.line;
+        let offset = self.current - 1;
+        let escape = self.source[self.current..]
+            .chars()
+            .next()
+            .unwrap_or_default();
+        self.current += escape.len_utf8();
+        let valid = match escape {
+            'n' | 't' | 'r' | '"' | '\\' | '$' => true,
+            'u' => self.unicode_escape(),
+            '\n' => {
+                self.line += 1;
+                self.line_start = self.current;
+                self.line_is_ascii = true;
+                false
+            }
+            c if !c.is_ascii() => {
+                self.line_is_ascii = false;
+                false
+            }
+            _ => false,
+        };
+        if valid {
+            None
         } else {
+            Some(LexError::InvalidEscape {
+                escape,
+                line,
+                offset,
+            })
+        }
+    }
+
+    /// `\u{...}` takes one to six hex digits naming a unicode scalar value.
+    fn unicode_escape(&mut self) -> bool {
+        if !self.match_current(b'{') {
+            return false;
+        }
+        let digits_start = self.current;
+        while !self.is_at_end() && self.peek().is_ascii_hexdigit() {
             self.advance();
-            self.make_token(TokenType::String)
         }
+        let digits = &self.source[digits_start..self.current];
+        if !self.match_current(b'}') || digits.is_empty() || digits.len() > 6 {
+            return false;
+        }
+        u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(char::from_u32)
+            .is_some()
     }
 
+    /// Scans `42`, `4.2`, `4.2e-1`, `0x2A`, `0b101010` and `0o52`,
+    /// digits may be grouped with single `_` separators as in `1_000_000`.
     fn number(&mut self) -> Token<'a> {
-        while !self.is_at_end() && self.peek().is_digit(10) {
+        let radix = match (self.bytes[self.start], self.peek()) {
+            (b'0', b'x' | b'X') => 16,
+            (b'0', b'o' | b'O') => 8,
+            (b'0', b'b' | b'B') => 2,
+            _ => 10,
+        };
+        let result = if radix == 10 {
+            self.decimal()
+        } else {
+            self.advance();
+            self.digits(radix, false)
+        };
+
+        // Letters or digits of another radix glued to the literal make the whole thing invalid.
+        let mut trailing = false;
+        while self.peek().is_ascii_alphanumeric() || self.peek() == b'_' {
             self.advance();
+            trailing = true;
         }
 
-        if !self.is_at_end() && self.peek() == '.' && self.peek_next().is_digit(10) {
+        match result {
+            Err(error) => self.error_token(LexError::InvalidNumber(error)),
+            Ok(()) if trailing => {
+                self.error_token(LexError::InvalidNumber(NumberError::InvalidDigit))
+            }
+            Ok(()) => self.make_token(TokenType::Number),
+        }
+    }
+
+    fn decimal(&mut self) -> Result<(), NumberError> {
+        self.digits(10, true)?;
+
+        if self.peek() == b'.' && self.peek_next().is_ascii_digit() {
+            self.advance();
+            self.digits(10, false)?;
+        }
+
+        if matches!(self.peek(), b'e' | b'E') {
             self.advance();
-            while !self.is_at_end() && self.peek().is_digit(10) {
+            if matches!(self.peek(), b'+' | b'-') {
                 self.advance();
             }
+            if !self.peek().is_ascii_digit() {
+                return Err(NumberError::MissingExponent);
+            }
+            self.digits(10, false)?;
         }
+        Ok(())
+    }
 
-        self.make_token(TokenType::Number)
+    /// Consumes digits of `radix`, `after_digit` tells if a digit was consumed right before.
+    fn digits(&mut self, radix: u32, after_digit: bool) -> Result<(), NumberError> {
+        let mut previous_digit = after_digit;
+        let mut any_digit = after_digit;
+        loop {
+            let c = self.peek();
+            if (c as char).is_digit(radix) {
+                previous_digit = true;
+                any_digit = true;
+            } else if c == b'_' {
+                if !previous_digit || !(self.peek_next() as char).is_digit(radix) {
+                    return Err(NumberError::InvalidSeparator);
+                }
+                previous_digit = false;
+            } else {
+                break;
+            }
+            self.advance();
+        }
+        if any_digit {
+            Ok(())
+        } else {
+            Err(NumberError::MissingDigits)
+        }
     }
 
     fn identifier(&mut self) -> Token<'a> {
-        while !self.is_at_end() && (self.peek().is_ascii_alphanumeric() || self.peek() == '_') {
+        while self.peek().is_ascii_alphanumeric() || self.peek() == b'_' {
             self.advance();
         }
-        let token = self.identifier_type();
+        let token = keyword_type(&self.bytes[self.start..self.current]);
         self.make_token(token)
     }
+}
 
-    fn identifier_type(&mut self) -> TokenType {
-        match self.chars[self.start] {
-            'a' => self.check_keyword(1, 2, "nd", TokenType::And),
-            'c' => self.check_keyword(1, 4, "lass", TokenType::Class),
-            'e' => self.check_keyword(1, 3, "lse", TokenType::Else),
-            'f' => {
-                if self.current - self.start > 1 {
-                    match self.chars[self.start + 1] {
-                        'a' => self.check_keyword(2, 3, "lse", TokenType::False),
-                        'o' => self.check_keyword(2, 1, "r", TokenType::For),
-                        'u' => self.check_keyword(2, 1, "n", TokenType::Fun),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
-            }
-            'i' => self.check_keyword(1, 1, "f", TokenType::If),
-            'n' => self.check_keyword(1, 2, "il", TokenType::Nil),
-            'o' => self.check_keyword(1, 1, "r", TokenType::Or),
-            'p' => self.check_keyword(1, 4, "rint", TokenType::Print),
-            'r' => self.check_keyword(1, 5, "eturn", TokenType::Return),
-            's' => self.check_keyword(1, 4, "uper", TokenType::Super),
-            't' => {
-                if self.current - self.start > 1 {
-                    match self.chars[self.start + 1] {
-                        'h' => self.check_keyword(2, 2, "is", TokenType::This),
-                        'r' => self.check_keyword(2, 2, "ue", TokenType::True),
-                        _ => TokenType::Identifier,
-                    }
-                } else {
-                    TokenType::Identifier
-                }
-            }
-            'v' => self.check_keyword(1, 2, "ar", TokenType::Var),
-            'w' => self.check_keyword(1, 4, "hile", TokenType::While),
-            _ => TokenType::Identifier,
+/// Yields every token up to and including `Eof`.
+impl<'a> Iterator for Scanner<'a> {
+    type Item = Token<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
         }
+        let token = self.scan_token();
+        self.done = token.kind == TokenType::Eof;
+        Some(token)
     }
+}
 
-    fn check_keyword(
-        &self,
-        start: usize,
-        len: usize,
-        rest: &str,
-        token_type: TokenType,
-    ) -> TokenType {
-        if self.current - self.start == start + len
-            && self.source[self.start + start..self.current] == *rest
-        {
-            token_type
-        } else {
-            TokenType::Identifier
+pub struct LosslessScanner<'a> {
+    scanner: Scanner<'a>,
+}
+
+/// Yields every token with its trivia up to and including `Eof`.
+impl<'a> Iterator for LosslessScanner<'a> {
+    type Item = LosslessToken<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.scanner.done {
+            return None;
         }
+        let token = self.scanner.scan_lossless();
+        self.scanner.done = token.token.kind == TokenType::Eof;
+        Some(token)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::diagnostic::Span;
+    use crate::lex_error::{LexError, NumberError};
     use crate::{Scanner, TokenType};
 
     #[test]
@@ -262,7 +568,8 @@ mod tests {
 
         let result = scanner.scan_token();
         assert_eq!(result.kind, TokenType::Error);
-        assert_eq!(result.src, "Unexpected character.");
+        assert_eq!(result.src, "#");
+        assert_eq!(result.error, Some(LexError::UnexpectedCharacter('#')));
 
         let result = scanner.scan_token();
         assert_eq!(result.kind, TokenType::Eof);
@@ -277,6 +584,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn scan_identifier() {
         let ids = ["_", "_a", "x1", "hello"];
         let source = ids.join(" ");
@@ -352,8 +660,26 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn scan_number() {
-        let nums = ["0", "42", "42.5"];
+        let nums = [
+            "0",
+            "42",
+            "42.5",
+            "007",
+            "1_000_000",
+            "0.000_1",
+            "1e9",
+            "1E-9",
+            "2.5e+3",
+            "1_0e1_0",
+            "0x2A",
+            "0XFF_FF",
+            "0b1010",
+            "0B1_0",
+            "0o17",
+            "0O7_7",
+        ];
         let source = nums.join(" ");
         let mut scanner = Scanner::new(source.as_str());
         for i in 0..nums.len() {
@@ -366,6 +692,7 @@ mod tests {
     }
 
     #[test]
+    #[allow(clippy::needless_range_loop)]
     fn scan_string() {
         let nums = [
             "\"\"",
@@ -383,7 +710,16 @@ mod tests {
 
         let result = scanner.scan_token();
         assert_eq!(result.kind, TokenType::Error);
-        assert_eq!(result.src, "Unterminated string");
+        assert_eq!(result.src, "\"Invalid string");
+        assert_eq!(
+            result.error,
+            Some(LexError::UnterminatedString {
+                line: 4,
+                offset: 38,
+                column: 3
+            })
+        );
+        assert_eq!(result.span(), Span::new(38, 39, 4));
 
         let result = scanner.scan_token();
         assert_eq!(result.kind, TokenType::Eof);
@@ -399,10 +735,274 @@ mod tests {
 
         let result = scanner.scan_token();
         assert_eq!(result.kind, TokenType::String);
-        assert_eq!(result.line, 4);
+        assert_eq!(result.line, 1);
 
         let result = scanner.scan_token();
         assert_eq!(result.kind, TokenType::Eof);
         assert_eq!(result.line, 4);
     }
+
+    #[test]
+    fn scan_block_comments() {
+        let source = "/* one */ /* two\nlines */ /* outer /* inner\n */ still outer */ x";
+        let mut scanner = Scanner::new(source);
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Identifier);
+        assert_eq!(result.src, "x");
+        assert_eq!(result.line, 3);
+
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_unterminated_block_comment() {
+        let source = "x\n/* outer\n/* inner */";
+        let mut scanner = Scanner::new(source);
+        assert_eq!(scanner.scan_token().kind, TokenType::Identifier);
+
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Error);
+        assert_eq!(result.src, "/* outer\n/* inner */");
+        assert_eq!(
+            result.error,
+            Some(LexError::UnterminatedComment { line: 2 })
+        );
+
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_doc_comments() {
+        let source = "/// Adds numbers.\n//// not a doc\nfun /// trailing";
+        let mut scanner = Scanner::new(source);
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::DocComment);
+        assert_eq!(result.src, "/// Adds numbers.");
+        assert_eq!(result.line, 1);
+
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Fun);
+        assert_eq!(result.line, 3);
+
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::DocComment);
+        assert_eq!(result.src, "/// trailing");
+
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_slash_is_not_comment() {
+        let mut scanner = Scanner::new("/ /");
+        assert_eq!(scanner.scan_token().kind, TokenType::Slash);
+        assert_eq!(scanner.scan_token().kind, TokenType::Slash);
+        assert_eq!(scanner.scan_token().kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_string_escapes() {
+        let strings = [r#""\n\t\r\"\\\$""#, r#""\u{41}\u{1F600}""#];
+        let source = strings.join(" ");
+        let mut scanner = Scanner::new(source.as_str());
+        for string in strings {
+            let result = scanner.scan_token();
+            assert_eq!(result.kind, TokenType::String);
+            assert_eq!(result.src, string);
+        }
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_invalid_escapes() {
+        let strings = [
+            r#""\q""#,
+            r#""\u{}""#,
+            r#""\u{110000}""#,
+            r#""\u41""#,
+            r#""\u{1234567}""#,
+        ];
+        let escapes = ['q', 'u', 'u', 'u', 'u'];
+        let source = strings.join(" ");
+        let mut scanner = Scanner::new(source.as_str());
+        for (string, escape) in strings.iter().zip(escapes) {
+            let result = scanner.scan_token();
+            assert_eq!(result.kind, TokenType::Error);
+            assert_eq!(result.src, *string);
+            let offset = source.find(string).unwrap() + 1;
+            assert_eq!(
+                result.error,
+                Some(LexError::InvalidEscape {
+                    escape,
+                    line: 1,
+                    offset
+                })
+            );
+        }
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_interpolation() {
+        use TokenType::*;
+        let source = r#""a ${ {x} } b ${"c ${d}"}""#;
+        let expected = [
+            (Interpolation, "\"a ${"),
+            (LeftBrace, "{"),
+            (Identifier, "x"),
+            (RightBrace, "}"),
+            (Interpolation, "} b ${"),
+            (Interpolation, "\"c ${"),
+            (Identifier, "d"),
+            (String, "}\""),
+            (String, "}\""),
+            (Eof, ""),
+        ];
+        let mut scanner = Scanner::new(source);
+        for (kind, src) in expected {
+            let result = scanner.scan_token();
+            assert_eq!(result.kind, kind);
+            assert_eq!(result.src, src);
+        }
+    }
+
+    #[test]
+    fn scan_number_dot_without_fraction() {
+        let mut scanner = Scanner::new("1.");
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Number);
+        assert_eq!(result.src, "1");
+        assert_eq!(scanner.scan_token().kind, TokenType::Dot);
+        assert_eq!(scanner.scan_token().kind, TokenType::Eof);
+    }
+
+    #[test]
+    fn scan_malformed_numbers() {
+        use NumberError::*;
+        let cases = [
+            ("0x", MissingDigits),
+            ("0b2", MissingDigits),
+            ("1e", MissingExponent),
+            ("1e+", MissingExponent),
+            ("1_", InvalidSeparator),
+            ("1__0", InvalidSeparator),
+            ("0x_1", InvalidSeparator),
+            ("0b102", InvalidDigit),
+            ("0o8", MissingDigits),
+            ("12abc", InvalidDigit),
+        ];
+        for (source, error) in cases {
+            let mut scanner = Scanner::new(source);
+            let result = scanner.scan_token();
+            assert_eq!(result.kind, TokenType::Error, "{}", source);
+            assert_eq!(result.src, source);
+            assert_eq!(
+                result.error,
+                Some(LexError::InvalidNumber(error)),
+                "{}",
+                source
+            );
+        }
+    }
+
+    #[test]
+    fn scan_escape_error_line() {
+        let mut scanner = Scanner::new("\"one\ntwo \\x\"");
+        let result = scanner.scan_token();
+        assert_eq!(result.kind, TokenType::Error);
+        assert_eq!(
+            result.error,
+            Some(LexError::InvalidEscape {
+                escape: 'x',
+                line: 2,
+                offset: 9
+            })
+        );
+    }
+
+    #[test]
+    fn scan_columns() {
+        let source = "var x\n  = \"a\nb\" +\t1;";
+        let positions: Vec<_> = Scanner::new(source)
+            .map(|token| (token.kind, token.line, token.column))
+            .collect();
+        use TokenType::*;
+        assert_eq!(
+            positions,
+            [
+                (Var, 1, 1),
+                (Identifier, 1, 5),
+                (Equal, 2, 3),
+                (String, 2, 5),
+                (Plus, 3, 4),
+                (Number, 3, 6),
+                (Semicolon, 3, 7),
+                (Eof, 3, 8),
+            ]
+        );
+    }
+
+    #[test]
+    fn iterate_until_eof() {
+        let mut scanner = Scanner::new("1 2");
+        let kinds: Vec<_> = scanner.by_ref().map(|token| token.kind).collect();
+        assert_eq!(
+            kinds,
+            [TokenType::Number, TokenType::Number, TokenType::Eof]
+        );
+        assert!(scanner.next().is_none());
+    }
+
+    #[test]
+    fn scan_unicode() {
+        let source = "\"héllo ${wörld}\" /* ünïcode */ ☃ x";
+        let tokens: Vec<_> = Scanner::new(source).collect();
+        let summary: Vec<_> = tokens
+            .iter()
+            .map(|token| (token.kind, token.src, token.column))
+            .collect();
+        use TokenType::*;
+        assert_eq!(
+            summary,
+            [
+                (Interpolation, "\"héllo ${", 1),
+                (Identifier, "w", 10),
+                (Error, "ö", 11),
+                (Identifier, "rld", 12),
+                (String, "}\"", 15),
+                (Error, "☃", 32),
+                (Identifier, "x", 34),
+                (Eof, "", 35),
+            ]
+        );
+        assert_eq!(tokens[2].error, Some(LexError::UnexpectedCharacter('ö')));
+    }
+
+    /// Run with `cargo test --release -p rlox bench_scanner -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_scanner_throughput() {
+        let snippet = "/// Sums the values.\nfun sum(a, b) { var total = a + b * 2.5e3 - 0x1F; // add\n\
+            if (total >= 1_000 and !false) { print \"big ${total}\"; } /* done */ return total; }\n";
+        let source = snippet.repeat(4 * 1024 * 1024 / snippet.len());
+
+        let mut best = std::time::Duration::MAX;
+        let mut count = 0;
+        for _ in 0..100 {
+            let start = std::time::Instant::now();
+            count = Scanner::new(source.as_str()).count();
+            best = best.min(start.elapsed());
+        }
+        println!(
+            "{} bytes, {} tokens in {:?}: {:.1} M tokens/s",
+            source.len(),
+            count,
+            best,
+            count as f64 / best.as_secs_f64() / 1e6
+        );
+    }
 }
@@ -1,3 +1,6 @@
+use crate::diagnostic::Span;
+use crate::lex_error::LexError;
+
 #[derive(Debug, PartialEq, Ord, PartialOrd, Eq, Hash, Copy, Clone)]
 pub enum TokenType {
     // Single-character tokens.
@@ -24,6 +27,7 @@ pub enum TokenType {
     // Literals.
     Identifier,
     String,
+    Interpolation,
     Number,
     // Keywords.
     And,
@@ -42,6 +46,8 @@ pub enum TokenType {
     True,
     Var,
     While,
+    // Documentation.
+    DocComment,
 
     Error,
     Eof,
@@ -53,17 +59,43 @@ pub struct Token<'a> {
     pub kind: TokenType,
     pub start: usize,
     pub src: &'a str,
+    /// Line the token starts on, strings and block comments may continue on later lines.
     pub line: usize,
+    /// Column of the first character of the token, counted from 1 on the line it starts.
+    pub column: usize,
+    /// Only set on `TokenType::Error` tokens.
+    pub error: Option<LexError>,
 }
 
 impl<'a> Token<'a> {
-    pub fn new(kind: TokenType, start: usize, src: &'a str, line: usize) -> Self {
+    pub fn new(kind: TokenType, start: usize, src: &'a str, line: usize, column: usize) -> Self {
         Token {
             kind,
             start,
             src,
             line,
+            column,
+            error: None,
+        }
+    }
+
+    pub fn error(error: LexError, start: usize, src: &'a str, line: usize, column: usize) -> Self {
+        Token {
+            kind: TokenType::Error,
+            start,
+            src,
+            line,
+            column,
+            error: Some(error),
+        }
+    }
+
+    /// Where the token is in the source, or the part of it a lexical error is about.
+    pub fn span(&self) -> Span {
+        if let Some(span) = self.error.and_then(|error| error.span()) {
+            return span;
         }
+        Span::new(self.start, self.start + self.src.len(), self.line)
     }
 }
 
@@ -74,6 +106,8 @@ impl Default for Token<'static> {
             start: 0,
             src: "",
             line: 0,
+            column: 0,
+            error: None,
         }
     }
 }
@@ -0,0 +1,167 @@
+use crate::token::Token;
+use crate::trivia::{LosslessToken, Trivia};
+
+/// One line of `rlox tokens` output: kind, lexeme, line and column of `token`.
+pub fn format_token(token: &Token, json: bool) -> String {
+    let error = token.error.map(|error| error.to_string());
+    if json {
+        let mut line = format!(
+            "{{\"kind\":\"{:?}\",\"lexeme\":{},\"line\":{},\"column\":{}",
+            token.kind,
+            json_string(token.src),
+            token.line,
+            token.column
+        );
+        if let Some(error) = error {
+            line.push_str(&format!(",\"error\":{}", json_string(&error)));
+        }
+        line.push('}');
+        line
+    } else {
+        let mut line = format!(
+            "{:>4}:{:<4} {:<14} '{}'",
+            token.line,
+            token.column,
+            format!("{:?}", token.kind),
+            token.src.escape_debug()
+        );
+        if let Some(error) = error {
+            line.push_str(&format!(" {}", error));
+        }
+        line
+    }
+}
+
+/// Like `format_token`, plain output puts every trivia on a line of its own.
+pub fn format_lossless_token(token: &LosslessToken, json: bool) -> String {
+    let formatted = format_token(&token.token, json);
+    if json {
+        format!(
+            "{},\"leading\":{},\"trailing\":{}}}",
+            &formatted[..formatted.len() - 1],
+            json_trivia(&token.leading),
+            json_trivia(&token.trailing)
+        )
+    } else {
+        let leading = token
+            .leading
+            .iter()
+            .map(|trivia| plain_trivia("leading", trivia));
+        let trailing = token
+            .trailing
+            .iter()
+            .map(|trivia| plain_trivia("trailing", trivia));
+        leading
+            .chain(std::iter::once(formatted))
+            .chain(trailing)
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+}
+
+fn plain_trivia(position: &str, trivia: &Trivia) -> String {
+    format!(
+        "{:>9} {:<14} '{}'",
+        position,
+        format!("{:?}", trivia.kind),
+        trivia.src.escape_debug()
+    )
+}
+
+fn json_trivia(trivia: &[Trivia]) -> String {
+    let items: Vec<_> = trivia
+        .iter()
+        .map(|trivia| {
+            format!(
+                "{{\"kind\":\"{:?}\",\"text\":{}}}",
+                trivia.kind,
+                json_string(trivia.src)
+            )
+        })
+        .collect();
+    format!("[{}]", items.join(","))
+}
+
+fn json_string(src: &str) -> String {
+    let mut result = String::with_capacity(src.len() + 2);
+    result.push('"');
+    for c in src.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            '\r' => result.push_str("\\r"),
+            '\t' => result.push_str("\\t"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{format_lossless_token, format_token};
+    use crate::Scanner;
+
+    fn dump(source: &str, json: bool) -> Vec<String> {
+        Scanner::new(source)
+            .map(|token| format_token(&token, json))
+            .collect()
+    }
+
+    #[test]
+    fn dump_plain() {
+        assert_eq!(
+            dump("print \"a\tb\";\n#", false),
+            [
+                "   1:1    Print          'print'",
+                "   1:7    String         '\\\"a\\tb\\\"'",
+                "   1:12   Semicolon      ';'",
+                "   2:1    Error          '#' Unexpected character '#'.",
+                "   2:2    Eof            ''",
+            ]
+        );
+    }
+
+    #[test]
+    fn dump_json() {
+        assert_eq!(
+            dump("\"a\\\"\n\"\x01 #", true),
+            [
+                r#"{"kind":"String","lexeme":"\"a\\\"\n\"","line":1,"column":1}"#,
+                r#"{"kind":"Error","lexeme":"\u0001","line":2,"column":2,"error":"Unexpected character '\u0001'."}"#,
+                r##"{"kind":"Error","lexeme":"#","line":2,"column":4,"error":"Unexpected character '#'."}"##,
+                r#"{"kind":"Eof","lexeme":"","line":2,"column":5}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn dump_trivia() {
+        let tokens: Vec<_> = Scanner::new(" x // c\n")
+            .lossless()
+            .map(|token| format_lossless_token(&token, false))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                "  leading Whitespace     ' '\n   1:2    Identifier     'x'\n trailing Whitespace     ' '\n trailing LineComment    '// c'",
+                "  leading Newline        '\\n'\n   2:1    Eof            ''",
+            ]
+        );
+
+        let tokens: Vec<_> = Scanner::new(" x")
+            .lossless()
+            .map(|token| format_lossless_token(&token, true))
+            .collect();
+        assert_eq!(
+            tokens,
+            [
+                r#"{"kind":"Identifier","lexeme":"x","line":1,"column":2,"leading":[{"kind":"Whitespace","text":" "}],"trailing":[]}"#,
+                r#"{"kind":"Eof","lexeme":"","line":1,"column":3,"leading":[],"trailing":[]}"#,
+            ]
+        );
+    }
+}
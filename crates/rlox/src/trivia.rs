@@ -0,0 +1,118 @@
+use crate::token::Token;
+use std::fmt;
+
+/// Source text between tokens that the compiler ignores.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TriviaKind {
+    Whitespace,
+    Newline,
+    LineComment,
+    BlockComment,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Trivia<'a> {
+    pub kind: TriviaKind,
+    pub src: &'a str,
+}
+
+impl<'a> Trivia<'a> {
+    pub fn new(kind: TriviaKind, src: &'a str) -> Self {
+        Trivia { kind, src }
+    }
+}
+
+/// A token of the trivia preserving scanner mode, see `Scanner::scan_lossless`.
+#[derive(Clone)]
+pub struct LosslessToken<'a> {
+    pub leading: Vec<Trivia<'a>>,
+    pub token: Token<'a>,
+    pub trailing: Vec<Trivia<'a>>,
+}
+
+/// Writes back the exact source text of the token and its trivia.
+impl fmt::Display for LosslessToken<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for trivia in &self.leading {
+            f.write_str(trivia.src)?;
+        }
+        f.write_str(self.token.src)?;
+        for trivia in &self.trailing {
+            f.write_str(trivia.src)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Trivia, TriviaKind};
+    use crate::{Scanner, TokenType};
+
+    fn round_trip(source: &str) -> String {
+        Scanner::new(source)
+            .lossless()
+            .map(|token| token.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn lossless_round_trip() {
+        let sources = [
+            "",
+            "   ",
+            "\n\n",
+            "1 + 2",
+            "  print  1 ;  // trailing\n\n/* leading\n */ x\r\n\t",
+            "/// doc\nfun f() { return \"a ${ b + \"${c}\" } d\"; }",
+            "/* nested /* comment */ */ //// not doc\n",
+            "#@ \"unterminated",
+            "1 /* unterminated /* block */",
+            "0x 1e+ \"\\q\" 12abc",
+            "} { ${ }",
+        ];
+        for source in sources {
+            assert_eq!(round_trip(source), source);
+        }
+    }
+
+    #[test]
+    fn lossless_trivia_placement() {
+        use TriviaKind::*;
+        let source = "  a // one\n\t/* two */ b ";
+        let tokens: Vec<_> = Scanner::new(source).lossless().collect();
+        assert_eq!(tokens.len(), 3);
+
+        assert_eq!(tokens[0].leading, [Trivia::new(Whitespace, "  ")]);
+        assert_eq!(tokens[0].token.src, "a");
+        assert_eq!(
+            tokens[0].trailing,
+            [
+                Trivia::new(Whitespace, " "),
+                Trivia::new(LineComment, "// one")
+            ]
+        );
+
+        assert_eq!(
+            tokens[1].leading,
+            [
+                Trivia::new(Newline, "\n"),
+                Trivia::new(Whitespace, "\t"),
+                Trivia::new(BlockComment, "/* two */"),
+                Trivia::new(Whitespace, " "),
+            ]
+        );
+        assert_eq!(tokens[1].token.src, "b");
+        assert_eq!(tokens[1].trailing, [Trivia::new(Whitespace, " ")]);
+
+        assert_eq!(tokens[2].token.kind, TokenType::Eof);
+        assert!(tokens[2].leading.is_empty());
+    }
+
+    #[test]
+    fn lossless_keeps_lines() {
+        let tokens: Vec<_> = Scanner::new("a\n/* x\n */ b").lossless().collect();
+        assert_eq!(tokens[1].token.line, 3);
+        assert_eq!(tokens[1].token.column, 5);
+    }
+}
@@ -0,0 +1,288 @@
+use crate::object::Obj;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+#[cfg(feature = "nan_boxing")]
+pub(crate) use packed::Packed as Slot;
+/// How the value stack keeps values, packed into 64 bits with the `nan_boxing` feature.
+#[cfg(not(feature = "nan_boxing"))]
+pub(crate) type Slot = Value;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Nil,
+    Bool(bool),
+    Number(f64),
+    Obj(Rc<Obj>),
+}
+
+impl Value {
+    pub fn string(string: impl Into<String>) -> Self {
+        Value::Obj(Rc::new(Obj::String(string.into())))
+    }
+
+    /// `nil` and `false` are false in conditions, every other value is true.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Value::Nil | Value::Bool(false))
+    }
+
+    pub fn as_number(&self) -> Option<f64> {
+        match self {
+            Value::Number(number) => Some(*number),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn as_obj(&self) -> Option<&Obj> {
+        match self {
+            Value::Obj(obj) => Some(obj),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self.as_obj()? {
+            Obj::String(string) => Some(string),
+            _ => None,
+        }
+    }
+
+    /// A list for a script to read, scripts can't make lists themselves yet.
+    pub fn list(values: Vec<Value>) -> Self {
+        Value::Obj(Rc::new(Obj::List(values)))
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self.as_obj()? {
+            Obj::List(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// A map from strings for a script to read, scripts can't make maps themselves yet.
+    pub fn map(entries: HashMap<String, Value>) -> Self {
+        Value::Obj(Rc::new(Obj::Map(entries)))
+    }
+
+    pub fn as_map(&self) -> Option<&HashMap<String, Value>> {
+        match self.as_obj()? {
+            Obj::Map(entries) => Some(entries),
+            _ => None,
+        }
+    }
+}
+
+impl From<f64> for Value {
+    fn from(number: f64) -> Self {
+        Value::Number(number)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Nil => write!(f, "nil"),
+            Value::Bool(value) => write!(f, "{}", value),
+            Value::Number(number) => write!(f, "{}", number),
+            Value::Obj(obj) => write!(f, "{}", obj),
+        }
+    }
+}
+
+/// Numbers are stored as they are. Everything else hides in the quiet NaNs no arithmetic
+/// produces: `nil` and the booleans are tagged in the low bits, an object pointer in the low
+/// 48 bits with the sign bit set, as in clox. Values are packed going onto the stack and
+/// unpacked coming off it, the rest of the interpreter only sees `Value`.
+#[cfg(feature = "nan_boxing")]
+mod packed {
+    use super::Value;
+    use crate::object::Obj;
+    use std::fmt;
+    use std::mem::ManuallyDrop;
+    use std::rc::Rc;
+
+    const SIGN: u64 = 0x8000_0000_0000_0000;
+    const QUIET_NAN: u64 = 0x7ffc_0000_0000_0000;
+    const NIL: u64 = QUIET_NAN | 1;
+    const FALSE: u64 = QUIET_NAN | 2;
+    const TRUE: u64 = QUIET_NAN | 3;
+    const OBJ: u64 = SIGN | QUIET_NAN;
+
+    pub struct Packed(u64);
+
+    impl Packed {
+        /// Reads a number without going through `Value`, for the arithmetic on the stack.
+        pub(crate) fn as_number(&self) -> Option<f64> {
+            if self.0 & QUIET_NAN != QUIET_NAN {
+                Some(f64::from_bits(self.0))
+            } else {
+                None
+            }
+        }
+
+        fn obj_pointer(&self) -> Option<*const Obj> {
+            if self.0 & OBJ == OBJ {
+                Some((self.0 & !OBJ) as *const Obj)
+            } else {
+                None
+            }
+        }
+    }
+
+    impl From<Value> for Packed {
+        fn from(value: Value) -> Self {
+            match value {
+                Value::Nil => Packed(NIL),
+                Value::Bool(value) => Packed(if value { TRUE } else { FALSE }),
+                // Every NaN is stored as the canonical one, its payload could look like a tag.
+                Value::Number(number) if number.is_nan() => Packed(f64::NAN.to_bits()),
+                Value::Number(number) => Packed(number.to_bits()),
+                Value::Obj(obj) => {
+                    let pointer = Rc::into_raw(obj) as u64;
+                    debug_assert_eq!(pointer & OBJ, 0, "Pointer does not fit in 48 bits");
+                    Packed(OBJ | pointer)
+                }
+            }
+        }
+    }
+
+    impl From<Packed> for Value {
+        fn from(packed: Packed) -> Self {
+            // The strong count the packed value holds moves into the `Rc`.
+            let packed = ManuallyDrop::new(packed);
+            match packed.0 {
+                NIL => Value::Nil,
+                TRUE => Value::Bool(true),
+                FALSE => Value::Bool(false),
+                bits if bits & QUIET_NAN != QUIET_NAN => Value::Number(f64::from_bits(bits)),
+                _ => match packed.obj_pointer() {
+                    Some(pointer) => Value::Obj(unsafe { Rc::from_raw(pointer) }),
+                    None => unreachable!("Unknown tag {:#x}", packed.0),
+                },
+            }
+        }
+    }
+
+    impl Clone for Packed {
+        fn clone(&self) -> Self {
+            if let Some(pointer) = self.obj_pointer() {
+                // The pointer came from `Rc::into_raw` and this value holds a strong count on it.
+                unsafe { Rc::increment_strong_count(pointer) };
+            }
+            Packed(self.0)
+        }
+    }
+
+    impl Drop for Packed {
+        fn drop(&mut self) {
+            if let Some(pointer) = self.obj_pointer() {
+                unsafe { Rc::decrement_strong_count(pointer) };
+            }
+        }
+    }
+
+    impl fmt::Debug for Packed {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            Value::from(self.clone()).fmt(f)
+        }
+    }
+}
+
+#[cfg(test)]
+// Without `nan_boxing` a slot is the value itself and the conversions do nothing.
+#[allow(clippy::useless_conversion)]
+mod tests {
+    use super::{Slot, Value};
+    use crate::compiler::{self, OptLevel};
+    use crate::object::Obj;
+    use crate::register_vm::tests::best_of;
+    use crate::vm::VirtualMachine;
+    use crate::InterpretResult;
+    use std::io;
+    use std::rc::Rc;
+
+    fn round_trip(value: &Value) -> Value {
+        Value::from(Slot::from(value.clone()))
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let numbers = [
+            0.0,
+            -0.0,
+            1.5,
+            -2e300,
+            f64::INFINITY,
+            f64::NEG_INFINITY,
+            f64::MIN_POSITIVE,
+        ];
+        for number in numbers {
+            let value = round_trip(&Value::Number(number));
+            assert_eq!(value.as_number().map(f64::to_bits), Some(number.to_bits()));
+        }
+        for value in [
+            Value::Nil,
+            Value::Bool(true),
+            Value::Bool(false),
+            Value::string("a"),
+        ] {
+            assert_eq!(round_trip(&value), value);
+        }
+        // `0 / 0` on x86 is a NaN with the sign bit set, it must not look like an object.
+        for nan in [f64::NAN, -f64::NAN] {
+            assert!(round_trip(&Value::Number(nan))
+                .as_number()
+                .is_some_and(f64::is_nan));
+        }
+    }
+
+    #[test]
+    fn objects_are_shared() {
+        let obj = Rc::new(Obj::String("shared".to_string()));
+        let slot = Slot::from(Value::Obj(obj.clone()));
+        let copies = vec![slot.clone(); 3];
+        assert_eq!(Rc::strong_count(&obj), 5);
+        drop(copies);
+        assert_eq!(Value::from(slot), Value::Obj(obj.clone()));
+        assert_eq!(Rc::strong_count(&obj), 1);
+    }
+
+    /// Run with `cargo test --release -p rlox bench_value -- --ignored --nocapture`, and again
+    /// with `--features nan_boxing` to compare the two representations.
+    #[test]
+    #[ignore]
+    fn bench_value_representation() {
+        let slots: Vec<Slot> = (0..1_000_000)
+            .map(|i| match i % 4 {
+                0 => Value::Number(i as f64),
+                1 => Value::Bool(i % 3 == 0),
+                2 => Value::Nil,
+                _ => Value::Number(0.5),
+            })
+            .map(Slot::from)
+            .collect();
+        let bytes = slots.capacity() * std::mem::size_of::<Slot>();
+        let sum_time = best_of(20, || {
+            let copies = slots.clone();
+            let sum: f64 = copies
+                .into_iter()
+                .filter_map(|slot| Value::from(slot).as_number())
+                .sum();
+            assert!(sum > 0.0);
+        });
+        println!(
+            "{} bytes per value, {} KiB for 1M values, copy and sum in {:?}",
+            std::mem::size_of::<Slot>(),
+            bytes / 1024,
+            sum_time
+        );
+
+        let source = "{ var i = 0; var sum = 0; while (i < 3000000) { \
+            if (i != nil and !false) sum = sum + i * 2 - 1; i = i + 1; } print sum; }";
+        let mut vm = VirtualMachine::with_output(io::sink());
+        assert!(compiler::compile(source, &mut vm.chunks, OptLevel::default()).is_empty());
+        let run_time = best_of(5, || assert!(matches!(vm.run(), InterpretResult::Ok)));
+        println!("loop of 3M iterations in {:?}", run_time);
+    }
+}
@@ -1,90 +1,404 @@
-use crate::chunk::{Chunk, OpCode, Value};
+use crate::chunk::{Chunk, OpCode};
+use crate::compiler::OptLevel;
+use crate::diagnostic::Diagnostic;
+use crate::embed::Vm;
+use crate::error_code::ErrorCode;
+#[cfg(feature = "jit")]
+use crate::jit;
+use crate::lox_error::LoxError;
+use crate::native::Native;
+use crate::object::Obj;
+use crate::value::{Slot, Value};
 use crate::{InterpretResult, VmStack};
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::mem;
+
+/// An instruction of `chunks` with its operands already read, so dispatching it is a single
+/// `match`. Constants, names and slots are indexes, jumps hold the index of their target.
+#[derive(Debug, Copy, Clone)]
+enum Op {
+    Constant(u8),
+    Nil,
+    True,
+    False,
+    Equal,
+    Greater,
+    GreaterEqual,
+    Less,
+    LessEqual,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Negate,
+    Not,
+    Stringify,
+    Print,
+    Pop,
+    DefineGlobal(u8),
+    GetGlobal(u8),
+    SetGlobal(u8),
+    GetLocal(u8),
+    SetLocal(u8),
+    /// Also stands for `OpCode::Loop`, targets can be before or after the jump.
+    Jump(u32),
+    JumpIfFalse(u32),
+    JumpIfTrue(u32),
+    JumpIfFalseOrPop(u32),
+    JumpIfTrueOrPop(u32),
+    Call(u8),
+    SetLocalPop(u8),
+    AddConstant(u8),
+    AddLocals(u8, u8),
+    JumpIfNotLess(u32),
+    Return,
+}
 
 pub struct VirtualMachine {
     pub chunks: Chunk,
-    stack: VmStack<Value>,
-    ip: usize,
+    /// The offset in `chunks` of each instruction `run` decoded, for runtime errors to point at.
+    offsets: Vec<usize>,
+    stack: VmStack<Slot>,
+    pub globals: HashMap<String, Value>,
+    error: Option<Diagnostic>,
+    /// Where `print` writes to.
+    output: Box<dyn Write>,
+    /// The level scripts that native functions evaluate are compiled at.
+    pub(crate) level: OptLevel,
+    /// How often each pair of instructions next to each other in the code ran one after the
+    /// other, the candidates for superinstructions.
+    #[cfg(feature = "opcode_pairs")]
+    pairs: HashMap<(OpCode, OpCode), u64>,
+    /// Whether `run` tries machine code first, on by default.
+    #[cfg(feature = "jit")]
+    pub jit: bool,
 }
 
-impl VirtualMachine {
-    pub fn new() -> Self {
+/// A machine with nothing to run that prints nowhere, it allocates nothing until it runs.
+impl Default for VirtualMachine {
+    fn default() -> Self {
         VirtualMachine {
-            chunks: Chunk::new(),
-            stack: VmStack::new(256),
-            ip: 0,
+            chunks: Chunk::default(),
+            offsets: Vec::new(),
+            stack: VmStack::new(0),
+            globals: HashMap::new(),
+            error: None,
+            output: Box::new(io::sink()),
+            level: OptLevel::default(),
+            #[cfg(feature = "opcode_pairs")]
+            pairs: HashMap::new(),
+            #[cfg(feature = "jit")]
+            jit: true,
         }
     }
+}
 
-    fn interpret(&mut self) -> InterpretResult {
-        self.run()
+impl VirtualMachine {
+    pub fn with_output(output: impl Write + 'static) -> Self {
+        VirtualMachine {
+            stack: VmStack::new(256),
+            output: Box::new(output),
+            ..VirtualMachine::default()
+        }
     }
 
+    /// Runs `chunks` from the start, globals defined by earlier runs are kept.
     pub fn run(&mut self) -> InterpretResult {
+        let code = self.decode();
+        let mut ip = 0;
+        #[cfg(feature = "jit")]
+        if self.jit {
+            ip = self.run_native();
+        }
+        #[cfg(feature = "opcode_pairs")]
+        let mut previous: Option<(OpCode, usize)> = None;
         loop {
-            if cfg!(feature = "debug_trace_execution") {
+            #[cfg(feature = "debug_trace_execution")]
+            {
                 self.stack.trace();
-                self.chunks.disassemble_instruction(self.ip);
+                self.chunks.disassemble_instruction(self.offsets[ip]);
             }
-            match self.get_next_op_code() {
-                OpCode::Constant => {
-                    let idx = self.get_next_byte();
+            #[cfg(feature = "opcode_pairs")]
+            {
+                let offset = self.offsets[ip];
+                let op_code = self.chunks.get_op_code(offset);
+                // Only a pair that follows each other in the code could be fused.
+                if let Some((previous, _)) = previous.filter(|&(_, next)| next == offset) {
+                    *self.pairs.entry((previous, op_code)).or_default() += 1;
+                }
+                previous = Some((op_code, offset + 1 + op_code.operand_bytes()));
+            }
+            let op = code[ip];
+            ip += 1;
+            match op {
+                Op::Constant(idx) => {
                     if let Some(value) = self.chunks.get_constant(idx as usize) {
-                        self.stack.push(*value)
+                        self.stack.push(value.clone())
                     } else {
-                        return InterpretResult::RuntimeError;
+                        return self.broken_code(ip);
                     }
                     continue;
                 }
-                OpCode::Add => {
-                    let value_a = self.stack.pop();
+                Op::Nil => {
+                    self.stack.push(Value::Nil);
+                    continue;
+                }
+                Op::True => {
+                    self.stack.push(Value::Bool(true));
+                    continue;
+                }
+                Op::False => {
+                    self.stack.push(Value::Bool(false));
+                    continue;
+                }
+                Op::Equal => {
                     let value_b = self.stack.pop();
-                    if value_a != None && value_b != None {
-                        self.stack.push(value_a.unwrap() + value_b.unwrap());
+                    let value_a = self.stack.pop();
+                    self.stack.push(Value::Bool(value_a == value_b));
+                    continue;
+                }
+                Op::Greater => {
+                    if self.binary(|a, b| Value::Bool(a > b)) {
                         continue;
                     }
-                    return InterpretResult::RuntimeError;
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
                 }
-                OpCode::Subtract => {
-                    let value_a = self.stack.pop();
-                    let value_b = self.stack.pop();
-                    if value_a != None && value_b != None {
-                        self.stack.push(value_a.unwrap() - value_b.unwrap());
+                Op::GreaterEqual => {
+                    if self.binary(|a, b| Value::Bool(a >= b)) {
                         continue;
                     }
-                    return InterpretResult::RuntimeError;
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
                 }
-                OpCode::Multiply => {
-                    let value_a = self.stack.pop();
-                    let value_b = self.stack.pop();
-                    if value_a != None && value_b != None {
-                        self.stack.push(value_a.unwrap() * value_b.unwrap());
+                Op::Less => {
+                    if self.binary(|a, b| Value::Bool(a < b)) {
                         continue;
                     }
-                    return InterpretResult::RuntimeError;
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
                 }
-                OpCode::Divide => {
-                    let value_a = self.stack.pop();
-                    let value_b = self.stack.pop();
-                    if value_a != None && value_b != None {
-                        self.stack.push(value_a.unwrap() / value_b.unwrap());
+                Op::LessEqual => {
+                    if self.binary(|a, b| Value::Bool(a <= b)) {
                         continue;
                     }
-                    return InterpretResult::RuntimeError;
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
                 }
-                OpCode::Negate => {
-                    if let Some(value) = self.stack.pop() {
-                        self.stack.push(-value);
+                Op::Add => {
+                    let right = self.stack.pop().unwrap_or(Value::Nil);
+                    if self.add_to_top(&right) {
+                        continue;
+                    }
+                    return self.not_addable(ip);
+                }
+                Op::Subtract => {
+                    if self.binary(|a, b| Value::Number(a - b)) {
+                        continue;
+                    }
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
+                }
+                Op::Multiply => {
+                    if self.binary(|a, b| Value::Number(a * b)) {
+                        continue;
+                    }
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
+                }
+                Op::Divide => {
+                    if self.binary(|a, b| Value::Number(a / b)) {
+                        continue;
+                    }
+                    return self.runtime_error(
+                        ErrorCode::OperandsNotNumbers,
+                        "Operands must be numbers.",
+                        ip,
+                    );
+                }
+                Op::Negate => {
+                    if let Some(value) = self.stack.pop().as_ref().and_then(Value::as_number) {
+                        self.stack.push(Value::Number(-value));
                         continue;
                     } else {
-                        return InterpretResult::RuntimeError;
+                        return self.runtime_error(
+                            ErrorCode::OperandNotNumber,
+                            "Operand must be a number.",
+                            ip,
+                        );
                     }
                 }
-                OpCode::Return => {
-                    println!("{:?}", self.stack.pop().unwrap());
-                    break;
+                Op::Not => {
+                    match self.stack.pop() {
+                        Some(value) => self.stack.push(Value::Bool(value.is_falsey())),
+                        None => return self.broken_code(ip),
+                    }
+                    continue;
+                }
+                Op::Stringify => {
+                    match self.stack.pop() {
+                        Some(value) if value.as_str().is_some() => self.stack.push(value),
+                        Some(value) => self.stack.push(Value::string(value.to_string())),
+                        None => return self.broken_code(ip),
+                    }
+                    continue;
+                }
+                Op::Print => {
+                    match self.stack.pop() {
+                        Some(value) => {
+                            writeln!(self.output, "{}", value).expect("Failed to write output")
+                        }
+                        None => return self.broken_code(ip),
+                    }
+                    continue;
+                }
+                Op::Pop => {
+                    self.stack.pop();
+                    continue;
+                }
+                Op::DefineGlobal(name) => {
+                    let name = read_name(&self.chunks, name).to_string();
+                    if let Some(value) = self.stack.pop() {
+                        self.globals.insert(name, value);
+                    }
+                    continue;
+                }
+                Op::GetGlobal(idx) => {
+                    match self.globals.get(read_name(&self.chunks, idx)) {
+                        Some(value) => self.stack.push(value.clone()),
+                        None => return self.undefined_variable(idx, ip),
+                    }
+                    continue;
+                }
+                Op::SetGlobal(idx) => {
+                    let value = self.stack.peek().unwrap_or(Value::Nil);
+                    match self.globals.get_mut(read_name(&self.chunks, idx)) {
+                        Some(global) => *global = value,
+                        None => return self.undefined_variable(idx, ip),
+                    }
+                    continue;
+                }
+                Op::GetLocal(slot) => {
+                    let slot = slot as usize;
+                    match self.stack.get(slot) {
+                        Some(value) => self.stack.push(value),
+                        None => return self.broken_code(ip),
+                    }
+                    continue;
+                }
+                Op::SetLocal(slot) => {
+                    let slot = slot as usize;
+                    let value = self.stack.peek().unwrap_or(Value::Nil);
+                    self.stack.set(slot, value);
+                    continue;
+                }
+                Op::Jump(target) => {
+                    ip = target as usize;
+                    continue;
+                }
+                Op::JumpIfFalse(target) => {
+                    if self.stack.pop().is_some_and(|value| value.is_falsey()) {
+                        ip = target as usize;
+                    }
+                    continue;
+                }
+                Op::JumpIfTrue(target) => {
+                    if self.stack.pop().is_some_and(|value| !value.is_falsey()) {
+                        ip = target as usize;
+                    }
+                    continue;
+                }
+                Op::JumpIfFalseOrPop(target) => {
+                    if self.stack.peek().is_some_and(|value| value.is_falsey()) {
+                        ip = target as usize;
+                    } else {
+                        self.stack.pop();
+                    }
+                    continue;
+                }
+                Op::JumpIfTrueOrPop(target) => {
+                    if self.stack.peek().is_some_and(|value| !value.is_falsey()) {
+                        ip = target as usize;
+                    } else {
+                        self.stack.pop();
+                    }
+                    continue;
+                }
+                Op::Call(argc) => {
+                    let argc = argc as usize;
+                    let callee = self.stack.peek_at(argc).unwrap_or(Value::Nil);
+                    let Some(Obj::Native(native)) = callee.as_obj() else {
+                        let message = "Can only call functions.";
+                        return self.runtime_error(ErrorCode::NotCallable, message, ip);
+                    };
+                    if let Some(message) = native.arity_mismatch(argc) {
+                        return self.runtime_error(ErrorCode::ArityMismatch, &message, ip);
+                    }
+                    match self.call_native(native, argc) {
+                        Ok(result) => {
+                            self.stack.discard(argc + 1);
+                            self.stack.push(result);
+                        }
+                        Err(error) => return self.native_error(native, error, ip),
+                    }
+                    continue;
+                }
+                Op::SetLocalPop(slot) => {
+                    let value = self.stack.pop().unwrap_or(Value::Nil);
+                    self.stack.set(slot as usize, value);
+                    continue;
+                }
+                Op::AddConstant(idx) => {
+                    let constant = self.chunks.get_constant(idx as usize).cloned();
+                    if self.add_to_top(&constant.unwrap_or(Value::Nil)) {
+                        continue;
+                    }
+                    return self.not_addable(ip);
                 }
-                OpCode::EOP => {
+                Op::AddLocals(a, b) => {
+                    let sum = match (self.stack.get(a as usize), self.stack.get(b as usize)) {
+                        (Some(a), Some(b)) => add(&a, &b),
+                        _ => None,
+                    };
+                    match sum {
+                        Some(sum) => self.stack.push(sum),
+                        None => return self.not_addable(ip),
+                    }
+                    continue;
+                }
+                Op::JumpIfNotLess(target) => {
+                    let value_b = self.stack.pop().unwrap_or(Value::Nil);
+                    let value_a = self.stack.pop().unwrap_or(Value::Nil);
+                    match (value_a.as_number(), value_b.as_number()) {
+                        (Some(a), Some(b)) if a < b => {}
+                        (Some(_), Some(_)) => ip = target as usize,
+                        _ => {
+                            let message = "Operands must be numbers.";
+                            return self.runtime_error(ErrorCode::OperandsNotNumbers, message, ip);
+                        }
+                    }
+                    continue;
+                }
+                Op::Return => {
                     break;
                 }
             }
@@ -92,15 +406,335 @@ impl VirtualMachine {
         InterpretResult::Ok
     }
 
-    fn get_next_op_code(&mut self) -> OpCode {
-        let code = self.chunks.get_op_code(self.ip);
-        self.ip += 1;
+    /// Replaces the two numbers on top of the stack with `apply` of them, the left operand's
+    /// slot is reused. False if either is not a number.
+    fn binary(&mut self, apply: impl Fn(f64, f64) -> Value) -> bool {
+        let Some((a, b)) = self.stack.top_two() else {
+            return false;
+        };
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => {
+                self.stack.replace_top_two(apply(a, b));
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Replaces the value on top of the stack with itself plus `right`, numbers in place. False
+    /// unless both are numbers or both are strings.
+    fn add_to_top(&mut self, right: &Value) -> bool {
+        let left = self.stack.top().and_then(Slot::as_number);
+        if let (Some(a), Some(b)) = (left, right.as_number()) {
+            self.stack.replace_top(Value::Number(a + b));
+            return true;
+        }
+        match self.stack.peek().and_then(|left| add(&left, right)) {
+            Some(sum) => {
+                self.stack.replace_top(sum);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The instruction pairs counted so far, the most frequent first.
+    #[cfg(feature = "opcode_pairs")]
+    pub fn pair_report(&self) -> String {
+        let total: u64 = self.pairs.values().sum();
+        let mut pairs: Vec<_> = self.pairs.iter().collect();
+        pairs.sort_by(|a, b| {
+            b.1.cmp(a.1)
+                .then_with(|| format!("{:?}", a.0).cmp(&format!("{:?}", b.0)))
+        });
+        let mut report = String::from("== opcode pairs ==\n");
+        for ((first, second), count) in pairs.into_iter().take(20) {
+            let share = *count as f64 * 100.0 / total as f64;
+            report += &format!("{: >5.1}% {: >12} {:?} {:?}\n", share, count, first, second);
+        }
+        report
+    }
+
+    /// The error that stopped the last `run`, it points at the failing instruction.
+    pub fn take_error(&mut self) -> Option<Diagnostic> {
+        self.error.take()
+    }
+
+    fn not_addable(&mut self, ip: usize) -> InterpretResult {
+        let message = "Operands must be two numbers or two strings.";
+        self.runtime_error(ErrorCode::OperandsNotAddable, message, ip)
+    }
+
+    fn undefined_variable(&mut self, idx: u8, ip: usize) -> InterpretResult {
+        let message = format!("Undefined variable '{}'.", read_name(&self.chunks, idx));
+        self.runtime_error(ErrorCode::UndefinedVariable, &message, ip)
+    }
+
+    /// Stops on code that reads a constant or a stack slot it doesn't have, which
+    /// `Chunk::verify` rules out.
+    fn broken_code(&mut self, ip: usize) -> InterpretResult {
+        let message = "Instruction reads past the constants or the stack.";
+        self.runtime_error(ErrorCode::InvalidBytecode, message, ip)
+    }
+
+    /// `ip` is already past the failing instruction.
+    fn runtime_error(&mut self, code: ErrorCode, message: &str, ip: usize) -> InterpretResult {
+        let span = self.chunks.get_span(self.offsets[ip - 1]);
+        self.error = Some(Diagnostic::error(code, span, message));
+        self.stack.reset();
+        InterpretResult::RuntimeError
+    }
+
+    /// Calls `native` with the `argc` values on top of the stack. Scripts it evaluates run on
+    /// this machine, with its globals, output and settings, while the code and the stack of the
+    /// script calling it wait for it to return.
+    fn call_native(&mut self, native: &Native, argc: usize) -> Result<Value, LoxError> {
+        let args = self.stack.top_values(argc);
+        let chunks = mem::take(&mut self.chunks);
+        let offsets = mem::take(&mut self.offsets);
+        let stack = mem::replace(&mut self.stack, VmStack::new(0));
+        let mut vm = Vm::from_machine(mem::take(self));
+        let result = native.call(&mut vm, &args);
+        *self = vm.into_machine();
+        self.chunks = chunks;
+        self.offsets = offsets;
+        self.stack = stack;
+        result
+    }
+
+    /// Stops on the `error` a call to `native` failed with, pointing at the call. The call is
+    /// noted on the error after the calls it went through inside the native, the notes keep the
+    /// lines of the scripts the native ran.
+    fn native_error(&mut self, native: &Native, error: LoxError, ip: usize) -> InterpretResult {
+        let span = self.chunks.get_span(self.offsets[ip - 1]);
+        let mut error = error.into_diagnostic();
+        error.span = span;
+        error.notes.push(format!(
+            "in {}(), called on line {}",
+            native.name, span.line
+        ));
+        self.error = Some(error);
+        self.stack.reset();
+        InterpretResult::RuntimeError
+    }
+
+    /// Runs `chunks` as machine code if it translates, returns the index in `code` of the
+    /// instruction the interpreter goes on from, with the stack the machine code left.
+    #[cfg(feature = "jit")]
+    fn run_native(&mut self) -> usize {
+        let Some(compiled) = jit::compile::<VirtualMachine>(&self.chunks) else {
+            return 0;
+        };
+        let (offset, stack) = compiled.run(self);
+        for number in stack {
+            self.stack.push(Value::Number(number));
+        }
+        let index = self.offsets.iter().position(|&start| start == offset);
+        index.expect("Machine code stopped inside an instruction")
+    }
+
+    /// Reads the operands of every instruction in `chunks` up front, so running it only has to
+    /// look at one `Op` per instruction. Jump offsets become indexes into the decoded code. The
+    /// chunk is trusted to pass `Chunk::verify`, which debug builds check.
+    fn decode(&mut self) -> Vec<Op> {
+        debug_assert_eq!(self.chunks.verify(), Ok(()));
+        let mut code = Vec::with_capacity(self.chunks.code.len() + 1);
+        self.offsets.clear();
+        let chunk = &self.chunks;
+        // The index in `code` of each instruction's first byte, and of the end of the chunk.
+        let mut indexes = vec![0; chunk.code.len() + 1];
+        let mut offset = 0;
+        while offset < chunk.code.len() {
+            indexes[offset] = self.offsets.len() as u32;
+            self.offsets.push(offset);
+            offset += 1 + chunk.get_op_code(offset).operand_bytes();
+        }
+        indexes[chunk.code.len()] = self.offsets.len() as u32;
+
+        for &offset in &self.offsets {
+            let byte = |n: usize| chunk.code[offset + n];
+            let target = |sign: isize| {
+                let jump = chunk.read_u16(offset + 1) as isize;
+                indexes[(offset as isize + 3 + sign * jump) as usize]
+            };
+            let op = match chunk.get_op_code(offset) {
+                OpCode::Constant => Op::Constant(byte(1)),
+                OpCode::Nil => Op::Nil,
+                OpCode::True => Op::True,
+                OpCode::False => Op::False,
+                OpCode::Equal => Op::Equal,
+                OpCode::Greater => Op::Greater,
+                OpCode::GreaterEqual => Op::GreaterEqual,
+                OpCode::Less => Op::Less,
+                OpCode::LessEqual => Op::LessEqual,
+                OpCode::Add => Op::Add,
+                OpCode::Subtract => Op::Subtract,
+                OpCode::Multiply => Op::Multiply,
+                OpCode::Divide => Op::Divide,
+                OpCode::Negate => Op::Negate,
+                OpCode::Not => Op::Not,
+                OpCode::Stringify => Op::Stringify,
+                OpCode::Print => Op::Print,
+                OpCode::Pop => Op::Pop,
+                OpCode::DefineGlobal => Op::DefineGlobal(byte(1)),
+                OpCode::GetGlobal => Op::GetGlobal(byte(1)),
+                OpCode::SetGlobal => Op::SetGlobal(byte(1)),
+                OpCode::GetLocal => Op::GetLocal(byte(1)),
+                OpCode::SetLocal => Op::SetLocal(byte(1)),
+                OpCode::Jump => Op::Jump(target(1)),
+                OpCode::JumpIfFalse => Op::JumpIfFalse(target(1)),
+                OpCode::JumpIfTrue => Op::JumpIfTrue(target(1)),
+                OpCode::JumpIfFalseOrPop => Op::JumpIfFalseOrPop(target(1)),
+                OpCode::JumpIfTrueOrPop => Op::JumpIfTrueOrPop(target(1)),
+                OpCode::Loop => Op::Jump(target(-1)),
+                OpCode::Call => Op::Call(byte(1)),
+                OpCode::SetLocalPop => Op::SetLocalPop(byte(1)),
+                OpCode::AddConstant => Op::AddConstant(byte(1)),
+                OpCode::AddLocals => Op::AddLocals(byte(1), byte(2)),
+                OpCode::JumpIfNotLess => Op::JumpIfNotLess(target(1)),
+                OpCode::Return | OpCode::Eop => Op::Return,
+            };
+            code.push(op);
+        }
+        // Running off the end of the chunk, or jumping to it, stops like `Return`.
+        code.push(Op::Return);
+        self.offsets.push(chunk.code.len().saturating_sub(1));
         code
     }
+}
+
+#[cfg(feature = "jit")]
+impl jit::Runtime for VirtualMachine {
+    fn print(&mut self, number: f64) {
+        writeln!(self.output, "{}", Value::Number(number)).expect("Failed to write output");
+    }
+
+    fn get_global(&mut self, name: u8) -> Option<f64> {
+        self.globals.get(read_name(&self.chunks, name))?.as_number()
+    }
+
+    fn set_global(&mut self, name: u8, number: f64) -> bool {
+        match self.globals.get_mut(read_name(&self.chunks, name)) {
+            Some(global) => *global = Value::Number(number),
+            None => return false,
+        }
+        true
+    }
+
+    fn define_global(&mut self, name: u8, number: f64) {
+        let name = read_name(&self.chunks, name).to_string();
+        self.globals.insert(name, Value::Number(number));
+    }
+}
+
+/// `a + b` on two numbers or two strings, `None` on anything else.
+fn add(a: &Value, b: &Value) -> Option<Value> {
+    if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+        return Some(Value::Number(a + b));
+    }
+    Some(Value::string([a.as_str()?, b.as_str()?].concat()))
+}
+
+/// The name of a global variable, a string constant. Borrowing it from the chunk rather than
+/// the virtual machine leaves the globals and the stack free to change.
+fn read_name(chunk: &Chunk, idx: u8) -> &str {
+    let name = chunk.get_constant(idx as usize).and_then(Value::as_str);
+    name.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VirtualMachine;
+    use crate::compiler::{compile, OptLevel};
+    use crate::diagnostic::Span;
+    use crate::register_vm::tests::best_of;
+    use crate::InterpretResult;
+    use std::io;
+
+    #[test]
+    fn runtime_error_points_at_the_operator() {
+        let source = "1 + 2;\n\"a\" +\n  3;";
+        let mut vm = VirtualMachine::with_output(io::sink());
+        assert!(compile(source, &mut vm.chunks, OptLevel::None).is_empty());
+
+        assert!(matches!(vm.run(), InterpretResult::RuntimeError));
+        let error = vm.take_error().expect("Runtime error");
+        let plus = source.rfind('+').expect("An addition");
+        assert_eq!(error.span, Span::new(plus, plus + 1, 2));
+    }
+
+    #[test]
+    fn runtime_error_points_past_jumps() {
+        let source =
+            "var i = 0;\nwhile (i < 3) {\n  i = i + 1;\n}\nif (i == 3 and true) print -nil;";
+        let mut vm = VirtualMachine::with_output(io::sink());
+        assert!(compile(source, &mut vm.chunks, OptLevel::default()).is_empty());
+
+        assert!(matches!(vm.run(), InterpretResult::RuntimeError));
+        let error = vm.take_error().expect("Runtime error");
+        let minus = source.rfind('-').expect("A negation");
+        assert_eq!(error.span, Span::new(minus, minus + 1, 5));
+    }
+
+    #[test]
+    fn grow_the_stack_as_deep_as_the_code_needs() {
+        let locals: String = (0..256).map(|i| format!("var a{} = {}; ", i, i)).collect();
+        let locals = format!("{{ {}a0 + a1; }}", locals);
+        let nested = format!(
+            "{{ var a = 1; {}a{}; }}",
+            "(a + ".repeat(300),
+            ")".repeat(300)
+        );
+        for source in [locals, nested] {
+            for level in [OptLevel::None, OptLevel::Fold, OptLevel::Peephole] {
+                let mut vm = VirtualMachine::with_output(io::sink());
+                assert!(compile(&source, &mut vm.chunks, level).is_empty());
+                assert!(matches!(vm.run(), InterpretResult::Ok));
+            }
+        }
+    }
 
-    fn get_next_byte(&mut self) -> u8 {
-        let byte = self.chunks.code[self.ip];
-        self.ip += 1;
-        byte
+    #[test]
+    fn runtime_error_points_into_fused_instructions() {
+        let source = "{ var a = 1;\nvar b = \"b\";\nprint a\n + b; }";
+        let mut vm = VirtualMachine::with_output(io::sink());
+        assert!(compile(source, &mut vm.chunks, OptLevel::Fuse).is_empty());
+
+        assert!(matches!(vm.run(), InterpretResult::RuntimeError));
+        let error = vm.take_error().expect("Runtime error");
+        let plus = source.rfind('+').expect("An addition");
+        assert_eq!(error.span, Span::new(plus, plus + 1, 4));
+    }
+
+    /// Arithmetic-heavy loops, where the time goes into dispatching small instructions.
+    const BENCHMARKS: [(&str, &str); 3] = [
+        (
+            "locals",
+            "{ var i = 0; var sum = 0; while (i < 3000000) { sum = sum + i * 2 - i / 4; i = i + 1; } }",
+        ),
+        (
+            "globals",
+            "var i = 0; var sum = 0; while (i < 1000000) { sum = sum + i * 2 - i / 4; i = i + 1; }",
+        ),
+        (
+            "nested",
+            "{ var a = 0; var n = 0; while (n < 1000) { var m = 0; \
+             while (m < 1000) { a = a + (m < n and 1 or -1); m = m + 1; } n = n + 1; } }",
+        ),
+    ];
+
+    /// Run with `cargo test --release -p rlox bench_dispatch -- --ignored --nocapture`, prints
+    /// each loop without superinstructions then with them.
+    #[test]
+    #[ignore]
+    fn bench_dispatch() {
+        for (name, source) in BENCHMARKS {
+            let [plain, fused] = [OptLevel::Peephole, OptLevel::Fuse].map(|level| {
+                let mut vm = VirtualMachine::with_output(io::sink());
+                assert!(compile(source, &mut vm.chunks, level).is_empty());
+                best_of(5, || assert!(matches!(vm.run(), InterpretResult::Ok)))
+            });
+            println!("{}: {:?} (-O2), {:?} (-O3)", name, plain, fused);
+        }
     }
 }